@@ -12,8 +12,8 @@ use std::time::Duration;
 
 use rotor::{Config as LoopConfig, Loop, Scope};
 use rotor::mio::tcp::TcpStream;
-use rotor_capnp::{Action, CapnpStream, ConnectionState, Endpoint, Error, MessageReader,
-                  MessageBuilder, MessageWriter};
+use rotor_capnp::{Client, ClientAction, ClientStream, CloseReason, MessageReader, MessageBuilder,
+                  RequestSeq, Requests};
 
 use messages_capnp::{request, response};
 
@@ -29,92 +29,87 @@ impl Metrics {
 
 struct EchoClient(Args);
 
-impl EchoClient {
-    fn send_request(mut self,
-                    mut output: MessageWriter,
-                    scope: &mut Scope<Metrics>)
-                    -> Action<Self> {
-        if let Some(content) = self.0.next() {
+impl Client for EchoClient {
+    type Context = Metrics;
+    type Socket = TcpStream;
+    type Seed = Args;
+
+    fn create(seed: Self::Seed,
+              _sock: &mut Self::Socket,
+              _scope: &mut Scope<Self::Context>)
+              -> ClientAction<Self> {
+        ClientAction::Continue(EchoClient(seed))
+    }
+
+    fn connected(mut self,
+                 requests: &mut Requests,
+                 scope: &mut Scope<Self::Context>)
+                 -> ClientAction<Self> {
+        // Send all the requests at once, responses are matched by order.
+        while let Some(content) = self.0.next() {
             scope.requests += 1;
-            println!("[client] sending request: {}", content);
             let mut builder = MessageBuilder::new_default();
             {
                 let mut request = builder.init_root::<request::Builder>();
                 request.set_client(7);
                 request.set_content(&content);
             }
-            output.write(&builder);
-            Action::Recv(self)
+            match requests.send(&builder, Duration::from_secs(10)) {
+                Ok(seq) => println!("[client] sending request {:?}: {}", seq, content),
+                Err(err) => {
+                    println!("[client] {}, closing connection", err);
+                    return ClientAction::Close(self);
+                }
+            }
+        }
+        if requests.pending() > 0 {
+            ClientAction::Continue(self)
         } else {
-            println!("[client] closing connection");
-            Action::Close
+            ClientAction::Close(self)
         }
     }
-}
-
-impl Endpoint for EchoClient {
-    type Context = Metrics;
-    type Socket = TcpStream;
-    type Seed = Args;
 
-    fn create(seed: Self::Seed,
-              _sock: &mut Self::Socket,
-              _scope: &mut Scope<Self::Context>)
-              -> Action<Self> {
-        Action::Flush(EchoClient(seed))
-    }
-
-    fn message_received(self,
-                        message: &MessageReader,
-                        output: MessageWriter,
-                        scope: &mut Scope<Self::Context>)
-                        -> Action<Self> {
+    fn response_received(self,
+                         seq: RequestSeq,
+                         message: &MessageReader,
+                         requests: &mut Requests,
+                         _scope: &mut Scope<Self::Context>)
+                         -> ClientAction<Self> {
         let response = message.get_root::<response::Reader>().unwrap();
         let content = response.get_content().unwrap();
-        println!("[client] received response: {}", content);
-        self.send_request(output, scope)
+        println!("[client] received response to {:?}: {}", seq, content);
+        if requests.pending() > 0 {
+            ClientAction::Continue(self)
+        } else {
+            println!("[client] closing connection");
+            ClientAction::Close(self)
+        }
     }
 
-    fn message_flushed(self,
-                       output: MessageWriter,
-                       scope: &mut Scope<Self::Context>)
-                       -> Action<Self> {
-        self.send_request(output, scope)
+    fn request_timeout(&mut self, seq: RequestSeq, _scope: &mut Scope<Self::Context>) {
+        println!("[client] request {:?} timed out, closing connection", seq);
     }
 
     fn idle_timeout(&self, _scope: &mut Scope<Self::Context>) -> Duration {
         Duration::from_secs(10)
     }
 
-    fn recv_timeout(&self, _scope: &mut Scope<Self::Context>) -> Duration {
-        Duration::from_secs(10)
-    }
-
     fn send_timeout(&self, _scope: &mut Scope<Self::Context>) -> Duration {
         Duration::from_secs(10)
     }
 
-    fn timeout(self,
-               state: ConnectionState,
-               _output: MessageWriter,
-               _scope: &mut Scope<Self::Context>)
-               -> Action<Self> {
-        match state {
-            ConnectionState::Idle => {
-                println!("[client] closing idle connection")
-            }
-            _ => println!("[client] timed out while \"{:?}\"", state),
-        };
-        Action::Close
-    }
-
-    fn wakeup(&self, _scope: &mut Scope<Self::Context>) -> Action<Self> {
-        unreachable!()
+    fn wakeup(self,
+              _requests: &mut Requests,
+              _scope: &mut Scope<Self::Context>)
+              -> ClientAction<Self> {
+        ClientAction::Continue(self)
     }
 
-    // Connection will be closed after this
-    fn exception(self, err: Error, _scope: &mut Scope<Self::Context>) {
-        println!("[client] {}, closing connection", err);
+    fn closed(self, reason: CloseReason, _scope: &mut Scope<Self::Context>) {
+        match reason {
+            CloseReason::Error(err) => println!("[client] {}, connection closed", err),
+            reason => println!("[client] connection closed: {:?}", reason),
+        }
     }
 }
 
@@ -126,7 +121,7 @@ fn main() {
     let socket = TcpStream::connect(&"127.0.0.1:3055".parse().unwrap()).unwrap();
 
     loop_inst.add_machine_with(|scope| {
-                 CapnpStream::<EchoClient>::new(socket, payloads, scope)
+                 ClientStream::<EchoClient>::new(socket, payloads, scope)
              })
              .unwrap();
     loop_inst.run().unwrap();
@@ -11,8 +11,8 @@ use std::time::Duration;
 
 use rotor::{Config as LoopConfig, Loop, Scope};
 use rotor::mio::tcp::{TcpListener, TcpStream};
-use rotor_capnp::{Accept, Action, CapnpStream, ConnectionState, Endpoint, Error, MessageReader,
-                  MessageBuilder, MessageWriter};
+use rotor_capnp::{Acceptor, Action, Admission, CloseReason, ConnectionState, Endpoint,
+                  MessageReader, MessageBuilder, MessageWriter};
 
 use messages_capnp::{request, response};
 
@@ -60,8 +60,13 @@ impl Endpoint for EchoServer {
             let mut response = builder.init_root::<response::Builder>();
             response.set_content(content);
         }
-        output.write(&builder);
-        Action::Flush(EchoServer(request_id))
+        match output.write(&builder) {
+            Ok(()) => Action::Flush(EchoServer(request_id)),
+            Err(err) => {
+                println!("[server] {}, closing connection", err);
+                Action::Close(EchoServer(request_id))
+            }
+        }
     }
 
     fn message_flushed(self,
@@ -95,16 +100,18 @@ impl Endpoint for EchoServer {
             }
             _ => println!("[server] timed out while \"{:?}\"", state),
         };
-        Action::Close
+        Action::Close(self)
     }
 
-    fn wakeup(&self, _scope: &mut Scope<Self::Context>) -> Action<Self> {
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Self::Context>) -> Action<Self> {
         unreachable!()
     }
 
-    // Connection will be closed after this
-    fn exception(self, err: Error, _scope: &mut Scope<Self::Context>) {
-        println!("[server] {}, closing connection", err);
+    fn closed(self, reason: CloseReason, _scope: &mut Scope<Self::Context>) {
+        match reason {
+            CloseReason::Error(err) => println!("[server] {}, connection closed", err),
+            reason => println!("[server] connection closed: {:?}", reason),
+        }
     }
 }
 
@@ -114,7 +121,7 @@ fn main() {
     let socket = TcpListener::bind(&"127.0.0.1:3055".parse().unwrap()).unwrap();
 
     loop_inst.add_machine_with(|scope| {
-                 Accept::<CapnpStream<EchoServer>, TcpListener>::new(socket, (), scope)
+                 Acceptor::<EchoServer, TcpListener>::new(socket, (), Admission::default(), scope)
              })
              .unwrap();
     loop_inst.run().unwrap();
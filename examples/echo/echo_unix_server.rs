@@ -0,0 +1,146 @@
+extern crate capnp;
+extern crate libc;
+extern crate rotor;
+extern crate rotor_capnp;
+
+mod messages_capnp {
+    #![allow(dead_code)]
+    include!(concat!(env!("OUT_DIR"), "/messages_capnp.rs"));
+}
+
+use std::fs;
+use std::time::Duration;
+
+use rotor::{Config as LoopConfig, Loop, Scope};
+use rotor::mio::unix::{UnixListener, UnixStream};
+use rotor_capnp::{Acceptor, Action, Admission, CloseReason, ConnectionState, Endpoint,
+                  MessageReader, MessageBuilder, MessageWriter, PeerCred};
+
+use messages_capnp::{request, response};
+
+const SOCKET_PATH: &'static str = "/tmp/rotor-capnp-echo.sock";
+
+struct Metrics {
+    requests: usize,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        Metrics { requests: 0 }
+    }
+}
+
+struct EchoServer(usize);
+
+impl Endpoint for EchoServer {
+    type Context = Metrics;
+    type Socket = UnixStream;
+    type Seed = ();
+
+    fn create(_seed: Self::Seed,
+              sock: &mut Self::Socket,
+              _scope: &mut Scope<Self::Context>)
+              -> Action<Self> {
+        // Only processes of the same user are served.
+        match sock.peer_cred() {
+            Ok(cred) if cred.uid == unsafe { libc::getuid() } => {
+                println!("[server] new connection from {:?}", cred);
+                Action::Idle(EchoServer(0))
+            }
+            Ok(cred) => {
+                println!("[server] refusing connection from {:?}", cred);
+                Action::Close(EchoServer(0))
+            }
+            Err(err) => {
+                println!("[server] {}, refusing connection", err);
+                Action::Close(EchoServer(0))
+            }
+        }
+    }
+
+    fn message_received(self,
+                        message: &MessageReader,
+                        mut output: MessageWriter,
+                        scope: &mut Scope<Self::Context>)
+                        -> Action<Self> {
+        scope.requests += 1;
+        let request_id = self.0 + 1;
+        let request = message.get_root::<request::Reader>().unwrap();
+        let client = request.get_client();
+        let content = request.get_content().unwrap();
+        println!("[server] request {} from client {}: {}",
+                 request_id,
+                 client,
+                 content);
+        let mut builder = MessageBuilder::new_default();
+        {
+            let mut response = builder.init_root::<response::Builder>();
+            response.set_content(content);
+        }
+        match output.write(&builder) {
+            Ok(()) => Action::Flush(EchoServer(request_id)),
+            Err(err) => {
+                println!("[server] {}, closing connection", err);
+                Action::Close(EchoServer(request_id))
+            }
+        }
+    }
+
+    fn message_flushed(self,
+                       _output: MessageWriter,
+                       _scope: &mut Scope<Self::Context>)
+                       -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    fn idle_timeout(&self, _scope: &mut Scope<Self::Context>) -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn recv_timeout(&self, _scope: &mut Scope<Self::Context>) -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn send_timeout(&self, _scope: &mut Scope<Self::Context>) -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn timeout(self,
+               state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Self::Context>)
+               -> Action<Self> {
+        match state {
+            ConnectionState::Idle => {
+                println!("[server] closing idle connection after {} request(s)",
+                         self.0)
+            }
+            _ => println!("[server] timed out while \"{:?}\"", state),
+        };
+        Action::Close(self)
+    }
+
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Self::Context>) -> Action<Self> {
+        unreachable!()
+    }
+
+    fn closed(self, reason: CloseReason, _scope: &mut Scope<Self::Context>) {
+        match reason {
+            CloseReason::Error(err) => println!("[server] {}, connection closed", err),
+            reason => println!("[server] connection closed: {:?}", reason),
+        }
+    }
+}
+
+fn main() {
+    let loop_creator = Loop::new(&LoopConfig::new()).unwrap();
+    let mut loop_inst = loop_creator.instantiate(Metrics::new());
+    let _ = fs::remove_file(SOCKET_PATH);
+    let socket = UnixListener::bind(SOCKET_PATH).unwrap();
+
+    loop_inst.add_machine_with(|scope| {
+                 Acceptor::<EchoServer, UnixListener>::new(socket, (), Admission::default(), scope)
+             })
+             .unwrap();
+    loop_inst.run().unwrap();
+}
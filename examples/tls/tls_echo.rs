@@ -0,0 +1,297 @@
+extern crate capnp;
+extern crate rcgen;
+extern crate rotor;
+extern crate rotor_capnp;
+extern crate rustls;
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use capnp::message::{Builder, HeapAllocator};
+use capnp::text;
+use rcgen::{BasicConstraints, CertificateParams, IsCa};
+use rotor::{Compose2, Config as LoopConfig, Loop, Scope};
+use rotor::mio::tcp::{TcpListener, TcpStream};
+use rotor_capnp::{Acceptor, Action, Admission, CloseReason, ConnectionState, Connector, Endpoint,
+                  Error, MessageReader, MessageWriter, Reconnect, StartSession, TlsListener,
+                  TlsSession, TlsStream, TlsTarget};
+use rustls::{Certificate, ClientConfig, ClientConnection, Connection, PrivateKey, RootCertStore,
+             ServerConfig, ServerConnection, ServerName};
+use rustls::server::AllowAnyAuthenticatedClient;
+
+/// Configuration of the rustls sessions on either side.
+#[derive(Clone)]
+enum Config {
+    Server(Arc<ServerConfig>),
+    Client(Arc<ClientConfig>, ServerName),
+}
+
+/// A rustls session.
+struct Rustls(Connection);
+
+impl StartSession for Rustls {
+    type Config = Config;
+
+    fn start(config: &Config) -> io::Result<Rustls> {
+        let connection = match *config {
+            Config::Server(ref config) => {
+                ServerConnection::new(config.clone()).map(Connection::from)
+            }
+            Config::Client(ref config, ref name) => {
+                ClientConnection::new(config.clone(), name.clone()).map(Connection::from)
+            }
+        };
+        connection.map(Rustls).map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    }
+}
+
+impl TlsSession for Rustls {
+    fn read_tls(&mut self, rd: &mut Read) -> io::Result<usize> {
+        self.0.read_tls(rd)
+    }
+
+    fn write_tls(&mut self, wr: &mut Write) -> io::Result<usize> {
+        self.0.write_tls(wr)
+    }
+
+    fn process_new_packets(&mut self) -> io::Result<()> {
+        self.0
+            .process_new_packets()
+            .map(|_| ())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.0.is_handshaking()
+    }
+
+    fn wants_write(&self) -> bool {
+        self.0.wants_write()
+    }
+
+    fn read_plaintext(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.reader().read(buf)
+    }
+
+    fn write_plaintext(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.writer().write(buf)
+    }
+
+    fn send_close_notify(&mut self) {
+        self.0.send_close_notify()
+    }
+
+    fn peer_certificate(&self) -> Option<&[u8]> {
+        self.0
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+            .map(|certificate| &certificate.0[..])
+    }
+}
+
+type Socket = TlsStream<TcpStream, Rustls>;
+
+fn text_message(content: &str) -> Builder<HeapAllocator> {
+    let mut builder = Builder::new_default();
+    builder.set_root::<text::Builder, text::Reader>(content).unwrap();
+    builder
+}
+
+struct Metrics {
+    requests: usize,
+}
+
+struct EchoServer;
+
+impl Endpoint for EchoServer {
+    type Context = Metrics;
+    type Socket = Socket;
+    type Seed = ();
+
+    fn create(_seed: Self::Seed,
+              sock: &mut Self::Socket,
+              _scope: &mut Scope<Self::Context>)
+              -> Action<Self> {
+        println!("[server] client certificate of {} bytes",
+                 sock.peer_certificate().map_or(0, |certificate| certificate.len()));
+        Action::Idle(EchoServer)
+    }
+
+    fn message_received(self,
+                        message: &MessageReader,
+                        mut output: MessageWriter,
+                        scope: &mut Scope<Self::Context>)
+                        -> Action<Self> {
+        scope.requests += 1;
+        let content = message.get_root::<text::Reader>().unwrap();
+        println!("[server] request: {}", content);
+        output.write(&text_message(content)).unwrap();
+        Action::Send(self)
+    }
+
+    fn message_flushed(self,
+                       _output: MessageWriter,
+                       _scope: &mut Scope<Self::Context>)
+                       -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    fn recv_timeout(&self, _scope: &mut Scope<Self::Context>) -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn send_timeout(&self, _scope: &mut Scope<Self::Context>) -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn timeout(self,
+               state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Self::Context>)
+               -> Action<Self> {
+        println!("[server] timed out while \"{:?}\"", state);
+        Action::Close(self)
+    }
+
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Self::Context>) -> Action<Self> {
+        unreachable!()
+    }
+
+    fn closed(self, reason: CloseReason, _scope: &mut Scope<Self::Context>) {
+        println!("[server] connection closed: {:?}", reason);
+    }
+
+    fn handshake_failed(_seed: &Self::Seed, err: Error, _scope: &mut Scope<Self::Context>) {
+        println!("[server] {}", err);
+    }
+}
+
+struct EchoClient;
+
+impl Endpoint for EchoClient {
+    type Context = Metrics;
+    type Socket = Socket;
+    type Seed = ();
+
+    fn create(_seed: Self::Seed,
+              sock: &mut Self::Socket,
+              _scope: &mut Scope<Self::Context>)
+              -> Action<Self> {
+        println!("[client] server certificate of {} bytes",
+                 sock.peer_certificate().map_or(0, |certificate| certificate.len()));
+        // `message_flushed` is called right away on the empty buffer.
+        Action::Flush(EchoClient)
+    }
+
+    fn message_received(self,
+                        message: &MessageReader,
+                        _output: MessageWriter,
+                        _scope: &mut Scope<Self::Context>)
+                        -> Action<Self> {
+        println!("[client] response: {}",
+                 message.get_root::<text::Reader>().unwrap());
+        Action::CloseOutput(self)
+    }
+
+    fn message_flushed(self,
+                       mut output: MessageWriter,
+                       _scope: &mut Scope<Self::Context>)
+                       -> Action<Self> {
+        output.write(&text_message("hello over TLS")).unwrap();
+        Action::Recv(self)
+    }
+
+    fn recv_timeout(&self, _scope: &mut Scope<Self::Context>) -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn send_timeout(&self, _scope: &mut Scope<Self::Context>) -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn timeout(self,
+               state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Self::Context>)
+               -> Action<Self> {
+        println!("[client] timed out while \"{:?}\"", state);
+        Action::Close(self)
+    }
+
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Self::Context>) -> Action<Self> {
+        unreachable!()
+    }
+
+    fn closed(self, reason: CloseReason, scope: &mut Scope<Self::Context>) {
+        println!("[client] connection closed: {:?}, {} request(s) served",
+                 reason,
+                 scope.requests);
+        scope.shutdown_loop();
+    }
+
+    fn connect_failed(_seed: &Self::Seed,
+                      err: Error,
+                      delay: Duration,
+                      _scope: &mut Scope<Self::Context>) {
+        println!("[client] {}, retrying in {:?}", err, delay);
+    }
+}
+
+/// Configurations of the server and the client, authenticating each other
+/// with certificates issued by a CA generated on the fly.
+fn configs() -> (ServerConfig, ClientConfig) {
+    let mut ca = CertificateParams::new(Vec::new());
+    ca.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = rcgen::Certificate::from_params(ca).unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(&Certificate(ca.serialize_der().unwrap())).unwrap();
+
+    let issue = |name: &str| {
+        let leaf = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        (vec![Certificate(leaf.serialize_der_with_signer(&ca).unwrap())],
+         PrivateKey(leaf.serialize_private_key_der()))
+    };
+    let (server_chain, server_key) = issue("localhost");
+    let (client_chain, client_key) = issue("client");
+
+    let server = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots.clone()).boxed())
+        .with_single_cert(server_chain, server_key)
+        .unwrap();
+    let client = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_client_auth_cert(client_chain, client_key)
+        .unwrap();
+    (server, client)
+}
+
+fn main() {
+    let (server, client) = configs();
+    let listener = TcpListener::bind(&"127.0.0.1:3056".parse().unwrap()).unwrap();
+    let listener = TlsListener::new(listener, Config::Server(Arc::new(server)));
+    let address: SocketAddr = "127.0.0.1:3056".parse().unwrap();
+    let name = ServerName::try_from("localhost").unwrap();
+    let target = TlsTarget {
+        target: address,
+        config: Config::Client(Arc::new(client), name),
+    };
+
+    let loop_creator = Loop::new(&LoopConfig::new()).unwrap();
+    let mut loop_inst = loop_creator.instantiate(Metrics { requests: 0 });
+    loop_inst.add_machine_with(|scope| {
+                 Acceptor::<EchoServer, _>::new(listener, (), Admission::default(), scope)
+                     .wrap(Compose2::A)
+             })
+             .unwrap();
+    loop_inst.add_machine_with(|scope| {
+                 Connector::<EchoClient, _>::new(scope, target, (), Reconnect::default())
+                     .wrap(Compose2::B)
+             })
+             .unwrap();
+    loop_inst.run().unwrap();
+}
@@ -0,0 +1,560 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rotor::{Evented, EventSet, Machine, PollOpt, Response, Scope, SpawnError, Time};
+use rotor::mio::TryAccept;
+use rotor::void::Void;
+use rotor_stream::{Buf, Exception, Expectation, Intent, Protocol, Stream, Transport};
+
+use error::Error;
+use protocol::Endpoint;
+use serialization::{MessageWriter, OutputLimits, OutputQueue, WireFormat};
+use socket::{HalfClose, Handshake, PeerAddr};
+use stream::Capnp;
+
+/// Limits of the connections accepted by an `Acceptor`.
+#[derive(Clone, Copy, Debug)]
+pub struct Admission {
+    /// Maximum number of open connections, it's 1024 by default.
+    pub max_connections: usize,
+    /// Maximum number of open connections from an IP address, it's 64 by default.
+    pub max_connections_per_ip: usize,
+    /// Maximum number of connections accepted per second, it's 256 by default.
+    /// Connections beyond it are closed right away.
+    pub max_accept_rate: usize,
+    /// Maximum number of rejected connections being sent the final message,
+    /// it's 64 by default. Further rejected connections are closed right away.
+    pub max_rejected: usize,
+    /// Encoding of the final message sent to a rejected connection. By
+    /// default it's unpacked.
+    pub wire_format: WireFormat,
+    /// Timeout for sending the final message to a rejected connection, it's
+    /// 1 second by default.
+    pub reject_timeout: Duration,
+    /// Time the input of a rejected connection is discarded for after the
+    /// final message, until the peer closes its side. Closing a socket with
+    /// unread input resets the connection, which may discard the message
+    /// before the peer reads it. It's 200 milliseconds by default.
+    pub reject_linger: Duration,
+    /// Timeout for completing the handshake of a connection, see
+    /// `Handshake`. It's 10 seconds by default.
+    pub handshake_timeout: Duration,
+}
+
+impl Default for Admission {
+    fn default() -> Admission {
+        Admission {
+            max_connections: 1024,
+            max_connections_per_ip: 64,
+            max_accept_rate: 256,
+            max_rejected: 64,
+            wire_format: WireFormat::Unpacked,
+            reject_timeout: Duration::from_secs(1),
+            reject_linger: Duration::from_millis(200),
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Reason of rejecting a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// `Admission::max_connections` are open.
+    TooManyConnections,
+    /// `Admission::max_connections_per_ip` are open from the IP address.
+    TooManyConnectionsFrom(IpAddr),
+    /// `Endpoint::admit` has refused the connection.
+    Refused,
+}
+
+/// Connections open and the accept rate, shared with the connections.
+struct Counters {
+    connections: usize,
+    connections_per_ip: HashMap<IpAddr, usize>,
+    /// Rejected connections being sent the final message.
+    rejected: usize,
+    /// Connections accepted in the current second, which ends at `window_end`.
+    accepted: usize,
+    window_end: Time,
+}
+
+/// Counted connection, which is uncounted when dropped.
+struct Ticket {
+    counters: Arc<Mutex<Counters>>,
+    counted: Counted,
+}
+
+/// What a `Ticket` counts.
+enum Counted {
+    /// Open connection from the IP address, if it's known.
+    Connection(Option<IpAddr>),
+    /// Rejected connection being sent the final message.
+    Rejected,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut counters = self.counters.lock().unwrap();
+        let ip = match self.counted {
+            Counted::Connection(ip) => ip,
+            Counted::Rejected => {
+                counters.rejected -= 1;
+                return;
+            }
+        };
+        counters.connections -= 1;
+        if let Some(ip) = ip {
+            let remove = match counters.connections_per_ip.get_mut(&ip) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                }
+                None => false,
+            };
+            if remove {
+                counters.connections_per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+/// State machine accepting connections within the `Admission` limits.
+///
+/// `Endpoint::admit` is called before `Endpoint::create` for every
+/// connection within the limits, and `Endpoint::rejected` for every
+/// connection which is rejected. `Endpoint::create` is called once the
+/// handshake of the connection is complete, or `Endpoint::handshake_failed`
+/// if it fails. Connections beyond `Admission::max_accept_rate` or
+/// `Admission::max_rejected`, and those which can't be spawned because the
+/// loop is full, are closed right away.
+pub struct Acceptor<E: Endpoint, A>(State<E, A>);
+
+enum State<E: Endpoint, A> {
+    Listener(A, E::Seed, Admission, Arc<Mutex<Counters>>),
+    Handshake(E::Socket, E::Seed, Ticket, Time),
+    Connection(Stream<Capnp<E>>, Ticket),
+    Rejected(Stream<Farewell<E>>),
+    /// The connection has been closed by `Machine::create`, which can't
+    /// stop the machine, it's stopped on the timeout right away.
+    Closed,
+}
+
+/// Accepted connection, the seed of spawning an `Acceptor`.
+pub struct Accepted<E: Endpoint>(Spawn<E>);
+
+enum Spawn<E: Endpoint> {
+    Admitted(E::Socket, E::Seed, Ticket, Time),
+    Rejected(E::Socket, Farewell<E>),
+}
+
+impl<E, A> Acceptor<E, A>
+    where E: Endpoint,
+          E::Seed: Clone,
+          E::Socket: PeerAddr + Handshake,
+          A: TryAccept<Output = E::Socket> + Evented + Any
+{
+    /// Start accepting connections from the `listener`.
+    pub fn new(listener: A,
+               seed: E::Seed,
+               admission: Admission,
+               scope: &mut Scope<E::Context>)
+               -> Response<Self, Void> {
+        if let Err(err) = scope.register(&listener, EventSet::readable(), PollOpt::edge()) {
+            return Response::error(Box::new(err));
+        }
+        let counters = Counters {
+            connections: 0,
+            connections_per_ip: HashMap::new(),
+            rejected: 0,
+            accepted: 0,
+            window_end: scope.now(),
+        };
+        let state = State::Listener(listener, seed, admission, Arc::new(Mutex::new(counters)));
+        Response::ok(Acceptor(state))
+    }
+
+    /// Accept a connection and spawn a state machine for it.
+    fn accept(listener: A,
+              seed: E::Seed,
+              admission: Admission,
+              counters: Arc<Mutex<Counters>>,
+              scope: &mut Scope<E::Context>)
+              -> Response<Self, Accepted<E>> {
+        loop {
+            let sock = match listener.accept() {
+                Ok(Some(sock)) => sock,
+                // The errors of accepting are transient or repeated on every
+                // attempt, the listener is kept either way.
+                Ok(None) | Err(_) => {
+                    let state = State::Listener(listener, seed, admission, counters);
+                    return Response::ok(Acceptor(state));
+                }
+            };
+            // The connections which aren't spawned are closed by dropping
+            // them, and the next one is accepted.
+            let spawn = Acceptor::<E, A>::admit(sock, &seed, &admission, &counters, scope);
+            if let Some(spawn) = spawn {
+                let state = State::Listener(listener, seed, admission, counters);
+                return Response::spawn(Acceptor(state), Accepted(spawn));
+            }
+        }
+    }
+
+    /// Admit or reject an accepted connection, `None` if it's to be closed
+    /// right away.
+    fn admit(mut sock: E::Socket,
+             seed: &E::Seed,
+             admission: &Admission,
+             counters: &Arc<Mutex<Counters>>,
+             scope: &mut Scope<E::Context>)
+             -> Option<Spawn<E>> {
+        if !Acceptor::<E, A>::within_rate(counters, admission, scope.now()) {
+            return None;
+        }
+        let ip = sock.peer_ip();
+        let admitted = Acceptor::<E, A>::check(counters, ip, admission)
+            .and_then(|()| {
+                if E::admit(seed, &mut sock, scope) {
+                    Ok(())
+                } else {
+                    Err(Rejection::Refused)
+                }
+            });
+        match admitted {
+            Ok(()) => {
+                let ticket = Acceptor::<E, A>::count(counters, Counted::Connection(ip));
+                let deadline = scope.now() + admission.handshake_timeout;
+                Some(Spawn::Admitted(sock, seed.clone(), ticket, deadline))
+            }
+            Err(reason) => {
+                if counters.lock().unwrap().rejected >= admission.max_rejected {
+                    return None;
+                }
+                let ticket = Acceptor::<E, A>::count(counters, Counted::Rejected);
+                let deadline = scope.now() + admission.reject_timeout;
+                let mut farewell = Farewell::new(deadline, admission.reject_linger, ticket);
+                {
+                    let output = MessageWriter::new(&mut farewell.output,
+                                                    &mut farewell.queue,
+                                                    admission.wire_format,
+                                                    OutputLimits::default());
+                    E::rejected(seed, reason, output, scope);
+                }
+                Some(Spawn::Rejected(sock, farewell))
+            }
+        }
+    }
+
+    /// Count a connection against the accept rate, false if it's exceeded.
+    fn within_rate(counters: &Arc<Mutex<Counters>>, admission: &Admission, now: Time) -> bool {
+        let mut counters = counters.lock().unwrap();
+        if now >= counters.window_end {
+            counters.window_end = now + Duration::from_secs(1);
+            counters.accepted = 0;
+        }
+        if counters.accepted >= admission.max_accept_rate {
+            return false;
+        }
+        counters.accepted += 1;
+        true
+    }
+
+    /// Check the limits for a new connection from the `ip`.
+    fn check(counters: &Arc<Mutex<Counters>>,
+             ip: Option<IpAddr>,
+             admission: &Admission)
+             -> Result<(), Rejection> {
+        let counters = counters.lock().unwrap();
+        if counters.connections >= admission.max_connections {
+            return Err(Rejection::TooManyConnections);
+        }
+        if let Some(ip) = ip {
+            let count = counters.connections_per_ip.get(&ip).cloned().unwrap_or(0);
+            if count >= admission.max_connections_per_ip {
+                return Err(Rejection::TooManyConnectionsFrom(ip));
+            }
+        }
+        Ok(())
+    }
+
+    fn count(counters: &Arc<Mutex<Counters>>, counted: Counted) -> Ticket {
+        {
+            let mut counters = counters.lock().unwrap();
+            match counted {
+                Counted::Connection(ip) => {
+                    counters.connections += 1;
+                    if let Some(ip) = ip {
+                        *counters.connections_per_ip.entry(ip).or_insert(0) += 1;
+                    }
+                }
+                Counted::Rejected => counters.rejected += 1,
+            }
+        }
+        Ticket {
+            counters: counters.clone(),
+            counted: counted,
+        }
+    }
+}
+
+impl<E, A> Machine for Acceptor<E, A>
+    where E: Endpoint,
+          E::Seed: Clone,
+          E::Socket: PeerAddr + Handshake,
+          A: TryAccept<Output = E::Socket> + Evented + Any
+{
+    type Context = E::Context;
+    type Seed = Accepted<E>;
+
+    fn create(seed: Self::Seed, scope: &mut Scope<Self::Context>) -> Response<Self, Void> {
+        match seed.0 {
+            Spawn::Admitted(mut sock, seed, ticket, deadline) => {
+                match sock.handshake() {
+                    Ok(true) => {
+                        Stream::new(sock, seed, scope)
+                            .wrap(|stream| Acceptor(State::Connection(stream, ticket)))
+                    }
+                    Ok(false) => {
+                        let events = EventSet::readable() | EventSet::writable();
+                        if let Err(err) = scope.register(&sock, events, PollOpt::edge()) {
+                            return Response::error(Box::new(err));
+                        }
+                        Response::ok(Acceptor(State::Handshake(sock, seed, ticket, deadline)))
+                            .deadline(deadline)
+                    }
+                    Err(err) => {
+                        E::handshake_failed(&seed, Error::Handshake(err), scope);
+                        let now = scope.now();
+                        Response::ok(Acceptor(State::Closed)).deadline(now)
+                    }
+                }
+            }
+            Spawn::Rejected(sock, farewell) => {
+                Stream::new(sock, farewell, scope)
+                    .wrap(|stream| Acceptor(State::Rejected(stream)))
+            }
+        }
+    }
+
+    fn ready(self,
+             events: EventSet,
+             scope: &mut Scope<Self::Context>)
+             -> Response<Self, Self::Seed> {
+        match self.0 {
+            State::Listener(listener, seed, admission, counters) => {
+                Acceptor::accept(listener, seed, admission, counters, scope)
+            }
+            State::Handshake(mut sock, seed, ticket, deadline) => {
+                match sock.handshake() {
+                    Ok(true) => {
+                        Capnp::handshaken(sock, seed, scope)
+                            .map(|stream| Acceptor(State::Connection(stream, ticket)),
+                                 |_| unreachable!())
+                    }
+                    Ok(false) => {
+                        Response::ok(Acceptor(State::Handshake(sock, seed, ticket, deadline)))
+                            .deadline(deadline)
+                    }
+                    Err(err) => {
+                        E::handshake_failed(&seed, Error::Handshake(err), scope);
+                        Response::done()
+                    }
+                }
+            }
+            State::Connection(stream, ticket) => {
+                stream.ready(events, scope)
+                    .map(|stream| Acceptor(State::Connection(stream, ticket)),
+                         |_| unreachable!())
+            }
+            State::Rejected(stream) => {
+                stream.ready(events, scope)
+                    .map(|stream| Acceptor(State::Rejected(stream)), |_| unreachable!())
+            }
+            State::Closed => Response::done(),
+        }
+    }
+
+    fn spawned(self, scope: &mut Scope<Self::Context>) -> Response<Self, Self::Seed> {
+        match self.0 {
+            State::Listener(listener, seed, admission, counters) => {
+                Acceptor::accept(listener, seed, admission, counters, scope)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn spawn_error(self,
+                   scope: &mut Scope<Self::Context>,
+                   _error: SpawnError<Self::Seed>)
+                   -> Response<Self, Self::Seed> {
+        // The connection is closed by dropping the seed, and the next one is
+        // accepted, which is closed as well while the loop is full.
+        self.spawned(scope)
+    }
+
+    fn timeout(self, scope: &mut Scope<Self::Context>) -> Response<Self, Self::Seed> {
+        match self.0 {
+            State::Listener(..) => unreachable!(),
+            State::Handshake(sock, seed, ticket, deadline) => {
+                if scope.now() >= deadline {
+                    E::handshake_failed(&seed, Error::HandshakeTimeout, scope);
+                    Response::done()
+                } else {
+                    // Spurious timeout.
+                    Response::ok(Acceptor(State::Handshake(sock, seed, ticket, deadline)))
+                        .deadline(deadline)
+                }
+            }
+            State::Connection(stream, ticket) => {
+                stream.timeout(scope)
+                    .map(|stream| Acceptor(State::Connection(stream, ticket)),
+                         |_| unreachable!())
+            }
+            State::Rejected(stream) => {
+                stream.timeout(scope)
+                    .map(|stream| Acceptor(State::Rejected(stream)), |_| unreachable!())
+            }
+            State::Closed => Response::done(),
+        }
+    }
+
+    fn wakeup(self, scope: &mut Scope<Self::Context>) -> Response<Self, Self::Seed> {
+        match self.0 {
+            state @ State::Listener(..) => Response::ok(Acceptor(state)),
+            State::Handshake(sock, seed, ticket, deadline) => {
+                Response::ok(Acceptor(State::Handshake(sock, seed, ticket, deadline)))
+                    .deadline(deadline)
+            }
+            State::Connection(stream, ticket) => {
+                stream.wakeup(scope)
+                    .map(|stream| Acceptor(State::Connection(stream, ticket)),
+                         |_| unreachable!())
+            }
+            State::Rejected(stream) => {
+                stream.wakeup(scope)
+                    .map(|stream| Acceptor(State::Rejected(stream)), |_| unreachable!())
+            }
+            State::Closed => Response::done(),
+        }
+    }
+}
+
+/// Protocol sending the final message to a rejected connection, then
+/// shutting down the write side and discarding the input until the peer
+/// closes its side or the linger timeout expires.
+struct Farewell<E> {
+    /// Output written by `Endpoint::rejected`, moved to the connection
+    /// buffer on the first transition.
+    output: Buf,
+    queue: OutputQueue,
+    deadline: Time,
+    linger: Duration,
+    /// The output has been flushed and the write side shut down.
+    lingering: bool,
+    /// Uncounts the connection once the machine is dropped.
+    _ticket: Ticket,
+    endpoint: PhantomData<fn() -> E>,
+}
+
+impl<E: Endpoint> Farewell<E> {
+    fn new(deadline: Time, linger: Duration, ticket: Ticket) -> Farewell<E> {
+        Farewell {
+            output: Buf::new(),
+            queue: OutputQueue::default(),
+            deadline: deadline,
+            linger: linger,
+            lingering: false,
+            _ticket: ticket,
+            endpoint: PhantomData,
+        }
+    }
+
+    fn intent(self) -> Intent<Self> {
+        let deadline = self.deadline;
+        let expectation = if self.lingering {
+            Expectation::Bytes(1)
+        } else {
+            Expectation::Flush(0)
+        };
+        Intent::of(self).expect(expectation).deadline(deadline)
+    }
+}
+
+impl<E: Endpoint> Protocol for Farewell<E> {
+    type Context = E::Context;
+    type Socket = E::Socket;
+    type Seed = Farewell<E>;
+
+    fn create(seed: Self::Seed,
+              _sock: &mut Self::Socket,
+              _scope: &mut Scope<Self::Context>)
+              -> Intent<Self> {
+        // The output isn't available until the first transition,
+        // `bytes_flushed` is called right away on the empty buffer.
+        seed.intent()
+    }
+
+    fn bytes_read(self,
+                  transport: &mut Transport<Self::Socket>,
+                  _end: usize,
+                  _scope: &mut Scope<Self::Context>)
+                  -> Intent<Self> {
+        let len = transport.input().len();
+        transport.input().consume(len);
+        self.intent()
+    }
+
+    fn bytes_flushed(mut self,
+                     transport: &mut Transport<Self::Socket>,
+                     scope: &mut Scope<Self::Context>)
+                     -> Intent<Self> {
+        let len = self.output.len();
+        transport.output().extend(&self.output[..]);
+        self.output.consume(len);
+        self.queue.fill(transport.output());
+        if !transport.output().is_empty() {
+            return self.intent();
+        }
+        if transport.socket().close_write().is_err() {
+            return Intent::done();
+        }
+        let len = transport.input().len();
+        transport.input().consume(len);
+        self.deadline = scope.now() + self.linger;
+        self.lingering = true;
+        self.intent()
+    }
+
+    fn timeout(self,
+               _transport: &mut Transport<Self::Socket>,
+               _scope: &mut Scope<Self::Context>)
+               -> Intent<Self> {
+        Intent::done()
+    }
+
+    fn exception(self,
+                 _transport: &mut Transport<Self::Socket>,
+                 _reason: Exception,
+                 _scope: &mut Scope<Self::Context>)
+                 -> Intent<Self> {
+        Intent::done()
+    }
+
+    fn fatal(self,
+             _reason: Exception,
+             _scope: &mut Scope<Self::Context>)
+             -> Option<Box<::std::error::Error>> {
+        None
+    }
+
+    fn wakeup(self,
+              _transport: &mut Transport<Self::Socket>,
+              _scope: &mut Scope<Self::Context>)
+              -> Intent<Self> {
+        self.intent()
+    }
+}
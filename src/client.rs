@@ -0,0 +1,420 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use rotor::{Scope, Time};
+use rotor_stream::StreamSocket;
+
+use error::Error;
+use pool::SegmentPool;
+use protocol::{Action, CloseReason, ConnectionState, Endpoint};
+use serialization::{FramingLimits, MessageAllocator, MessageBuilder, MessageReader,
+                    MessageWriter, OutputLimits, ReaderOptions, WireFormat};
+use socket::HalfClose;
+
+/// Sequence number of a request within a connection, in the order the
+/// requests are sent.
+///
+/// It isn't sent to the peer, a response is matched to the request by its
+/// position in the sequence only. So the connection is closed once a request
+/// times out, as the later responses can't be told apart from a late one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RequestSeq(u64);
+
+/// Wrapper of the new state of `Client` and whether to keep the connection.
+pub enum ClientAction<C> {
+    /// Keep the connection open.
+    Continue(C),
+    /// Close the connection, `Client::closed` is called with
+    /// `CloseReason::Local`.
+    Close(C),
+}
+
+/// A handler for the client side of a request/response protocol, where
+/// responses come in the order of the requests.
+///
+/// Responses are matched to requests first in, first out, so the peer must
+/// reply to every request exactly once and strictly in order. A response
+/// without a pending request closes the connection with
+/// `Error::UnexpectedMessage`. A protocol with
+/// responses out of order needs to correlate them by an id in the messages,
+/// implementing `Endpoint` instead.
+pub trait Client: Sized {
+    /// Context shared between transitions of the state machine.
+    type Context;
+    /// Type of the underlying socket.
+    type Socket: StreamSocket + HalfClose;
+    /// Seed for initializing the state machine.
+    type Seed;
+
+    /// A new connection has been established.
+    fn create(seed: Self::Seed,
+              sock: &mut Self::Socket,
+              scope: &mut Scope<Self::Context>)
+              -> ClientAction<Self>;
+
+    /// The connection is ready for sending requests.
+    fn connected(self,
+                 requests: &mut Requests,
+                 scope: &mut Scope<Self::Context>)
+                 -> ClientAction<Self>;
+
+    /// The response to the request `seq` has been received.
+    fn response_received(self,
+                         seq: RequestSeq,
+                         response: &MessageReader,
+                         requests: &mut Requests,
+                         scope: &mut Scope<Self::Context>)
+                         -> ClientAction<Self>;
+
+    /// No response to the request `seq` has been received before its
+    /// timeout expired. It's called for every request expired at the same
+    /// time, then the connection is closed, abandoning the other pending
+    /// requests, and `closed` is called with a timeout while receiving.
+    fn request_timeout(&mut self, seq: RequestSeq, scope: &mut Scope<Self::Context>);
+
+    /// The requests pending to be sent have reached `OutputLimits::high_watermark`.
+    fn output_congested(&mut self, _scope: &mut Scope<Self::Context>) {}
+
+    /// The requests pending to be sent have dropped to
+    /// `OutputLimits::low_watermark` after being congested.
+    fn output_drained(&mut self, _requests: &mut Requests, _scope: &mut Scope<Self::Context>) {}
+
+    /// Options for the Cap'n Proto message reader.
+    fn reader_options(&self, _scope: &mut Scope<Self::Context>) -> ReaderOptions {
+        ReaderOptions::new()
+    }
+
+    /// Limits of the framing of the messages received.
+    fn framing_limits(&self, _scope: &mut Scope<Self::Context>) -> FramingLimits {
+        FramingLimits::default()
+    }
+
+    /// Pool of buffers for the received messages, which may be shared with
+    /// other connections. By default the buffers are allocated per message.
+    fn segment_pool(&self, _scope: &mut Scope<Self::Context>) -> Option<SegmentPool> {
+        None
+    }
+
+    /// Encoding of the messages sent and received. By default it's unpacked.
+    fn wire_format(&self, _scope: &mut Scope<Self::Context>) -> WireFormat {
+        WireFormat::Unpacked
+    }
+
+    /// Limits of the messages written to the connection.
+    fn output_limits(&self, _scope: &mut Scope<Self::Context>) -> OutputLimits {
+        OutputLimits::default()
+    }
+
+    /// Timeout for a connection without pending requests, the connection is
+    /// closed after it expires. By default it's 120 seconds.
+    fn idle_timeout(&self, _scope: &mut Scope<Self::Context>) -> Duration {
+        Duration::from_secs(120)
+    }
+
+    /// Maximum number of messages received in a row from the buffered input
+    /// before other connections are served. By default it's 64.
+    fn recv_budget(&self, _scope: &mut Scope<Self::Context>) -> usize {
+        64
+    }
+
+    /// Timeout for sending requests before the connection is ready, the
+    /// connection is closed after it expires.
+    fn send_timeout(&self, scope: &mut Scope<Self::Context>) -> Duration;
+
+    /// Interval of checking the requests pending to be sent, see
+    /// `Endpoint::output_check_interval`.
+    fn output_check_interval(&self, _scope: &mut Scope<Self::Context>) -> Duration {
+        Duration::from_millis(10)
+    }
+
+    /// The state machine has been woken up.
+    fn wakeup(self,
+              requests: &mut Requests,
+              scope: &mut Scope<Self::Context>)
+              -> ClientAction<Self>;
+
+    /// The connection has been closed. If a request has expired, `reason`
+    /// is a timeout while receiving.
+    fn closed(self, reason: CloseReason, scope: &mut Scope<Self::Context>);
+
+    /// Connecting by a `Connector` has failed, it's retried after the `delay`.
+    fn connect_failed(_seed: &Self::Seed,
+                      _err: Error,
+                      _delay: Duration,
+                      _scope: &mut Scope<Self::Context>) {
+    }
+}
+
+struct PendingRequest {
+    seq: RequestSeq,
+    deadline: Time,
+}
+
+#[derive(Default)]
+struct Pending {
+    requests: VecDeque<PendingRequest>,
+    next_seq: u64,
+}
+
+/// Sender of requests on a `Client` connection.
+pub struct Requests<'a> {
+    output: MessageWriter<'a>,
+    pending: &'a mut Pending,
+    now: Time,
+}
+
+impl<'a> Requests<'a> {
+    /// Send a request, its response must arrive before the `timeout` expires.
+    ///
+    /// The returned sequence number is passed to `Client::response_received`
+    /// along with the response. Nothing is sent if the request exceeds the `OutputLimits`.
+    pub fn send<A: MessageAllocator>(&mut self,
+                                     request: &MessageBuilder<A>,
+                                     timeout: Duration)
+                                     -> Result<RequestSeq, Error> {
+        try!(self.output.write(request));
+        Ok(self.push(timeout))
+    }
+
+    /// Send a request taking the ownership of it, see `MessageWriter::write_owned`.
+    pub fn send_owned<A>(&mut self,
+                         request: MessageBuilder<A>,
+                         timeout: Duration)
+                         -> Result<RequestSeq, Error>
+        where A: MessageAllocator + 'static
+    {
+        try!(self.output.write_owned(request));
+        Ok(self.push(timeout))
+    }
+
+    fn push(&mut self, timeout: Duration) -> RequestSeq {
+        let seq = RequestSeq(self.pending.next_seq);
+        self.pending.next_seq += 1;
+        self.pending.requests.push_back(PendingRequest {
+            seq: seq,
+            deadline: self.now + timeout,
+        });
+        seq
+    }
+
+    /// Number of requests waiting for a response.
+    pub fn pending(&self) -> usize {
+        self.pending.requests.len()
+    }
+}
+
+/// Adaptor implementing `Endpoint` for a `Client`.
+pub struct Requester<C: Client> {
+    client: C,
+    pending: Pending,
+    /// Error closing the connection, reported instead of `CloseReason::Local`.
+    failure: Option<Error>,
+}
+
+impl<C: Client> Requester<C> {
+    fn action(action: ClientAction<C>, pending: Pending) -> Action<Self> {
+        match action {
+            ClientAction::Continue(client) => {
+                let idle = pending.requests.is_empty();
+                let requester = Requester::new(client, pending);
+                if idle {
+                    Action::Idle(requester)
+                } else {
+                    // Outgoing requests are written while receiving.
+                    Action::Recv(requester)
+                }
+            }
+            ClientAction::Close(client) => Action::Close(Requester::new(client, pending)),
+        }
+    }
+
+    fn new(client: C, pending: Pending) -> Requester<C> {
+        Requester {
+            client: client,
+            pending: pending,
+            failure: None,
+        }
+    }
+
+    /// Call the client with the requests sender.
+    fn with_requests<F>(self,
+                        output: MessageWriter,
+                        scope: &mut Scope<C::Context>,
+                        f: F)
+                        -> Action<Self>
+        where F: FnOnce(C, &mut Requests, &mut Scope<C::Context>) -> ClientAction<C>
+    {
+        let Requester { client, mut pending, .. } = self;
+        let action = {
+            let mut requests = Requests {
+                output: output,
+                pending: &mut pending,
+                now: scope.now(),
+            };
+            f(client, &mut requests, scope)
+        };
+        Requester::action(action, pending)
+    }
+
+    /// Notify the client of the requests past their deadline and close the
+    /// connection, as the responses can't be matched after a missing one.
+    fn expire_requests(mut self, scope: &mut Scope<C::Context>) -> Action<Self> {
+        let now = scope.now();
+        let mut expired = false;
+        for request in &self.pending.requests {
+            if request.deadline <= now {
+                self.client.request_timeout(request.seq, scope);
+                expired = true;
+            }
+        }
+        if expired {
+            Action::Close(self)
+        } else {
+            Action::Recv(self)
+        }
+    }
+}
+
+impl<C: Client> Endpoint for Requester<C> {
+    type Context = C::Context;
+    type Socket = C::Socket;
+    type Seed = C::Seed;
+
+    fn create(seed: Self::Seed,
+              sock: &mut Self::Socket,
+              scope: &mut Scope<Self::Context>)
+              -> Action<Self> {
+        match C::create(seed, sock, scope) {
+            // The output isn't available until the first transition,
+            // `message_flushed` is called right away on the empty buffer.
+            ClientAction::Continue(client) => {
+                Action::Flush(Requester::new(client, Pending::default()))
+            }
+            ClientAction::Close(client) => {
+                Action::Close(Requester::new(client, Pending::default()))
+            }
+        }
+    }
+
+    fn message_received(mut self,
+                        message: &MessageReader,
+                        output: MessageWriter,
+                        scope: &mut Scope<Self::Context>)
+                        -> Action<Self> {
+        let request = match self.pending.requests.pop_front() {
+            Some(request) => request,
+            None => {
+                self.failure = Some(Error::UnexpectedMessage);
+                return Action::Close(self);
+            }
+        };
+        self.with_requests(output, scope, |client, requests, scope| {
+            client.response_received(request.seq, message, requests, scope)
+        })
+    }
+
+    fn message_flushed(self,
+                       output: MessageWriter,
+                       scope: &mut Scope<Self::Context>)
+                       -> Action<Self> {
+        self.with_requests(output,
+                           scope,
+                           |client, requests, scope| client.connected(requests, scope))
+    }
+
+    fn output_congested(&mut self, scope: &mut Scope<Self::Context>) {
+        self.client.output_congested(scope)
+    }
+
+    fn output_drained(&mut self, output: MessageWriter, scope: &mut Scope<Self::Context>) {
+        let mut requests = Requests {
+            output: output,
+            pending: &mut self.pending,
+            now: scope.now(),
+        };
+        self.client.output_drained(&mut requests, scope)
+    }
+
+    fn reader_options(&self, scope: &mut Scope<Self::Context>) -> ReaderOptions {
+        self.client.reader_options(scope)
+    }
+
+    fn framing_limits(&self, scope: &mut Scope<Self::Context>) -> FramingLimits {
+        self.client.framing_limits(scope)
+    }
+
+    fn segment_pool(&self, scope: &mut Scope<Self::Context>) -> Option<SegmentPool> {
+        self.client.segment_pool(scope)
+    }
+
+    fn wire_format(&self, scope: &mut Scope<Self::Context>) -> WireFormat {
+        self.client.wire_format(scope)
+    }
+
+    fn output_limits(&self, scope: &mut Scope<Self::Context>) -> OutputLimits {
+        self.client.output_limits(scope)
+    }
+
+    fn idle_timeout(&self, scope: &mut Scope<Self::Context>) -> Duration {
+        self.client.idle_timeout(scope)
+    }
+
+    fn recv_budget(&self, scope: &mut Scope<Self::Context>) -> usize {
+        self.client.recv_budget(scope)
+    }
+
+    /// Unused, as `recv_deadline` is overridden.
+    fn recv_timeout(&self, scope: &mut Scope<Self::Context>) -> Duration {
+        self.client.idle_timeout(scope)
+    }
+
+    /// The earliest deadline of the pending requests.
+    fn recv_deadline(&self, scope: &mut Scope<Self::Context>) -> Time {
+        self.pending
+            .requests
+            .iter()
+            .map(|request| request.deadline)
+            .min()
+            .unwrap_or_else(|| scope.now() + self.client.idle_timeout(scope))
+    }
+
+    fn send_timeout(&self, scope: &mut Scope<Self::Context>) -> Duration {
+        self.client.send_timeout(scope)
+    }
+
+    fn output_check_interval(&self, scope: &mut Scope<Self::Context>) -> Duration {
+        self.client.output_check_interval(scope)
+    }
+
+    fn timeout(self,
+               state: ConnectionState,
+               _output: MessageWriter,
+               scope: &mut Scope<Self::Context>)
+               -> Action<Self> {
+        match state {
+            ConnectionState::Receiving => self.expire_requests(scope),
+            _ => Action::Close(self),
+        }
+    }
+
+    fn wakeup(self, output: MessageWriter, scope: &mut Scope<Self::Context>) -> Action<Self> {
+        self.with_requests(output,
+                           scope,
+                           |client, requests, scope| client.wakeup(requests, scope))
+    }
+
+    fn closed(self, reason: CloseReason, scope: &mut Scope<Self::Context>) {
+        let reason = match self.failure {
+            Some(err) => CloseReason::Error(err),
+            None => reason,
+        };
+        self.client.closed(reason, scope)
+    }
+
+    fn connect_failed(seed: &Self::Seed,
+                      err: Error,
+                      delay: Duration,
+                      scope: &mut Scope<Self::Context>) {
+        C::connect_failed(seed, err, delay, scope)
+    }
+}
@@ -0,0 +1,536 @@
+use std::cell::Cell;
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
+use std::vec;
+
+use rotor::{EventSet, Machine, PollOpt, Response, Scope, Time};
+use rotor::void::{unreachable, Void};
+use rotor_stream::{ActiveStream, SocketError, Stream};
+
+use error::Error;
+use pool::SegmentPool;
+use protocol::{Action, CloseReason, ConnectionState, Endpoint};
+use serialization::{FramingLimits, MessageReader, MessageWriter, OutputLimits, ReaderOptions,
+                    WireFormat};
+use socket::Handshake;
+use stream::Capnp;
+
+/// Policy of connecting and reconnecting a `Connector`.
+#[derive(Clone, Copy, Debug)]
+pub struct Reconnect {
+    /// Timeout for establishing a connection, including the handshake of
+    /// the socket. It's 1 second by default.
+    pub connect_timeout: Duration,
+    /// Delay before reconnecting, it's doubled after every failed attempt.
+    /// It's 200 milliseconds by default.
+    pub min_delay: Duration,
+    /// Maximum delay before reconnecting, it's 30 seconds by default.
+    pub max_delay: Duration,
+}
+
+impl Default for Reconnect {
+    fn default() -> Reconnect {
+        Reconnect {
+            connect_timeout: Duration::from_secs(1),
+            min_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Reconnect {
+    /// Delay before the `attempt`th reconnection in a row, a random one
+    /// between half of the exponential backoff and the whole of it.
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.min_delay
+            .checked_mul(1 << cmp::min(attempt.saturating_sub(1), 31))
+            .map_or(self.max_delay, |delay| cmp::min(delay, self.max_delay));
+        let nanos = backoff.as_secs().saturating_mul(1_000_000_000) +
+                    backoff.subsec_nanos() as u64;
+        let half = nanos / 2;
+        // The hasher is seeded with random keys.
+        let random = RandomState::new().build_hasher().finish();
+        let nanos = half + random % (half + 1);
+        Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+    }
+}
+
+/// Address of a peer, resolved before every round of connection attempts.
+///
+/// It's resolved on the thread of the loop, so it must not block. Names are
+/// to be resolved elsewhere, into a `Vec<SocketAddr>` for example.
+pub trait Resolve {
+    /// Type of the resolved addresses.
+    type Address;
+
+    /// Resolve the addresses of the peer, they're connected to in order
+    /// until an attempt succeeds.
+    fn resolve(&self) -> io::Result<Vec<Self::Address>>;
+}
+
+impl Resolve for SocketAddr {
+    type Address = SocketAddr;
+
+    fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        Ok(vec![*self])
+    }
+}
+
+/// Addresses resolved in advance.
+impl Resolve for Vec<SocketAddr> {
+    type Address = SocketAddr;
+
+    fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        Ok(self.clone())
+    }
+}
+
+#[cfg(unix)]
+impl Resolve for PathBuf {
+    type Address = PathBuf;
+
+    fn resolve(&self) -> io::Result<Vec<PathBuf>> {
+        Ok(vec![self.clone()])
+    }
+}
+
+/// State machine for an outgoing connection, which is reconnected with
+/// exponential backoff after it's closed or connecting has failed.
+///
+/// When connecting to an address fails, the next address resolved is
+/// connected to right away, and the backoff applies once none is left.
+/// `Endpoint::create` is called on every new connection once its handshake
+/// is complete, and `Endpoint::connect_failed` on every failed attempt.
+/// The connector terminates when the endpoint closes the connection with
+/// `Action::Close` or `Action::FlushAndClose`, or when the peer closes it
+/// after `Action::CloseOutput`.
+pub struct Connector<E: Endpoint, R: Resolve> {
+    peer: Peer<E, R>,
+    state: State<E>,
+}
+
+struct Peer<E: Endpoint, R: Resolve> {
+    target: R,
+    seed: E::Seed,
+    reconnect: Reconnect,
+    /// Number of reconnections in a row without establishing a connection.
+    attempts: u32,
+    /// Addresses left to connect to before resolving the target again.
+    addresses: vec::IntoIter<R::Address>,
+}
+
+impl<E: Endpoint, R: Resolve> Peer<E, R> {
+    /// The connection has been established, the next one starts afresh.
+    fn reset(self) -> Peer<E, R> {
+        Peer {
+            attempts: 0,
+            addresses: Vec::new().into_iter(),
+            ..self
+        }
+    }
+}
+
+enum State<E: Endpoint> {
+    Connecting(E::Socket, Time),
+    Handshake(E::Socket, Time),
+    /// The connection and whether it has been closed by the endpoint.
+    Established(Box<Stream<Capnp<Dialed<E>>>>, Rc<Cell<bool>>),
+    Sleeping(Time),
+}
+
+impl<E, R> Connector<E, R>
+    where E: Endpoint,
+          E::Seed: Clone,
+          E::Socket: ActiveStream + Handshake,
+          R: Resolve<Address = <E::Socket as ActiveStream>::Address>
+{
+    /// Start connecting to the `target`.
+    pub fn new(scope: &mut Scope<E::Context>,
+               target: R,
+               seed: E::Seed,
+               reconnect: Reconnect)
+               -> Response<Self, Void> {
+        let peer = Peer {
+            target: target,
+            seed: seed,
+            reconnect: reconnect,
+            attempts: 0,
+            addresses: Vec::new().into_iter(),
+        };
+        Connector::connect(peer, scope)
+    }
+
+    fn response(peer: Peer<E, R>, state: State<E>) -> Response<Self, Void> {
+        let deadline = match state {
+            State::Connecting(_, deadline) |
+            State::Handshake(_, deadline) |
+            State::Sleeping(deadline) => deadline,
+            // The deadline of the connection is set by the stream.
+            State::Established(..) => unreachable!(),
+        };
+        Response::ok(Connector {
+                peer: peer,
+                state: state,
+            })
+            .deadline(deadline)
+    }
+
+    fn connect(mut peer: Peer<E, R>, scope: &mut Scope<E::Context>) -> Response<Self, Void> {
+        if peer.addresses.len() == 0 {
+            match peer.target.resolve() {
+                Ok(addresses) => peer.addresses = addresses.into_iter(),
+                Err(err) => return Connector::retry(peer, Some(Error::Connect(err)), scope),
+            }
+        }
+        let sock = peer.addresses
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address resolved"))
+            .and_then(|address| E::Socket::connect(&address))
+            .and_then(|sock| {
+                try!(scope.register(&sock, EventSet::writable(), PollOpt::level()));
+                Ok(sock)
+            });
+        match sock {
+            Ok(sock) => {
+                let deadline = scope.now() + peer.reconnect.connect_timeout;
+                Connector::response(peer, State::Connecting(sock, deadline))
+            }
+            Err(err) => Connector::retry(peer, Some(Error::Connect(err)), scope),
+        }
+    }
+
+    /// Connect to the next address, or reconnect after a delay, reporting
+    /// the `err` of the failed attempt.
+    fn retry(mut peer: Peer<E, R>,
+             err: Option<Error>,
+             scope: &mut Scope<E::Context>)
+             -> Response<Self, Void> {
+        if peer.addresses.len() > 0 {
+            if let Some(err) = err {
+                E::connect_failed(&peer.seed, err, Duration::from_secs(0), scope);
+            }
+            return Connector::connect(peer, scope);
+        }
+        peer.attempts = peer.attempts.saturating_add(1);
+        let delay = peer.reconnect.delay(peer.attempts);
+        if let Some(err) = err {
+            E::connect_failed(&peer.seed, err, delay, scope);
+        }
+        let deadline = scope.now() + delay;
+        Connector::response(peer, State::Sleeping(deadline))
+    }
+
+    fn connected(peer: Peer<E, R>,
+                 mut sock: E::Socket,
+                 deadline: Time,
+                 scope: &mut Scope<E::Context>)
+                 -> Response<Self, Void> {
+        match sock.handshake() {
+            Ok(true) => {
+                let finished = Rc::new(Cell::new(false));
+                let seed = (peer.seed.clone(), finished.clone());
+                let response = Stream::connected(sock, seed, scope);
+                Connector::established(peer.reset(), response, finished, scope)
+            }
+            Ok(false) => {
+                // The socket is registered for writing only while connecting.
+                let events = EventSet::readable() | EventSet::writable();
+                match scope.reregister(&sock, events, PollOpt::edge()) {
+                    Ok(()) => Connector::response(peer, State::Handshake(sock, deadline)),
+                    Err(err) => Connector::retry(peer, Some(Error::Connect(err)), scope),
+                }
+            }
+            Err(err) => Connector::retry(peer, Some(Error::Handshake(err)), scope),
+        }
+    }
+
+    fn handshake(peer: Peer<E, R>,
+                 mut sock: E::Socket,
+                 deadline: Time,
+                 scope: &mut Scope<E::Context>)
+                 -> Response<Self, Void> {
+        match sock.handshake() {
+            Ok(true) => {
+                let finished = Rc::new(Cell::new(false));
+                let seed = (peer.seed.clone(), finished.clone());
+                let response = Capnp::handshaken(sock, seed, scope);
+                Connector::established(peer.reset(), response, finished, scope)
+            }
+            Ok(false) => Connector::response(peer, State::Handshake(sock, deadline)),
+            Err(err) => Connector::retry(peer, Some(Error::Handshake(err)), scope),
+        }
+    }
+
+    /// Continue with the response of the established connection.
+    fn established(peer: Peer<E, R>,
+                   response: Response<Stream<Capnp<Dialed<E>>>, Void>,
+                   finished: Rc<Cell<bool>>,
+                   scope: &mut Scope<E::Context>)
+                   -> Response<Self, Void> {
+        if finished.get() {
+            Response::done()
+        } else if response.is_stopped() {
+            // The endpoint has been notified of closing the connection.
+            Connector::retry(peer, None, scope)
+        } else {
+            response.wrap(|stream| {
+                Connector {
+                    peer: peer,
+                    state: State::Established(Box::new(stream), finished),
+                }
+            })
+        }
+    }
+}
+
+impl<E, R> Machine for Connector<E, R>
+    where E: Endpoint,
+          E::Seed: Clone,
+          E::Socket: ActiveStream + Handshake,
+          R: Resolve<Address = <E::Socket as ActiveStream>::Address>
+{
+    type Context = E::Context;
+    type Seed = Void;
+
+    fn create(seed: Self::Seed, _scope: &mut Scope<Self::Context>) -> Response<Self, Void> {
+        unreachable(seed)
+    }
+
+    fn ready(self, events: EventSet, scope: &mut Scope<Self::Context>) -> Response<Self, Void> {
+        let Connector { peer, state } = self;
+        match state {
+            State::Connecting(sock, deadline) => {
+                if !events.is_writable() && !events.is_hup() && !events.is_error() {
+                    // Spurious event.
+                    return Connector::response(peer, State::Connecting(sock, deadline));
+                }
+                match sock.take_socket_error() {
+                    Ok(()) if events.is_writable() => {
+                        Connector::connected(peer, sock, deadline, scope)
+                    }
+                    Ok(()) => {
+                        let err = io::Error::new(io::ErrorKind::ConnectionAborted,
+                                                 "connection closed immediately");
+                        Connector::retry(peer, Some(Error::Connect(err)), scope)
+                    }
+                    Err(err) => Connector::retry(peer, Some(Error::Connect(err)), scope),
+                }
+            }
+            State::Handshake(sock, deadline) => Connector::handshake(peer, sock, deadline, scope),
+            State::Established(stream, finished) => {
+                let response = stream.ready(events, scope);
+                Connector::established(peer, response, finished, scope)
+            }
+            // Spurious event.
+            state => Connector::response(peer, state),
+        }
+    }
+
+    fn spawned(self, _scope: &mut Scope<Self::Context>) -> Response<Self, Void> {
+        unreachable!()
+    }
+
+    fn timeout(self, scope: &mut Scope<Self::Context>) -> Response<Self, Void> {
+        let Connector { peer, state } = self;
+        match state {
+            State::Connecting(_, deadline) if scope.now() >= deadline => {
+                Connector::retry(peer, Some(Error::ConnectTimeout), scope)
+            }
+            State::Handshake(_, deadline) if scope.now() >= deadline => {
+                Connector::retry(peer, Some(Error::HandshakeTimeout), scope)
+            }
+            State::Sleeping(deadline) if scope.now() >= deadline => {
+                Connector::connect(peer, scope)
+            }
+            State::Established(stream, finished) => {
+                let response = stream.timeout(scope);
+                Connector::established(peer, response, finished, scope)
+            }
+            // Spurious timeout.
+            state => Connector::response(peer, state),
+        }
+    }
+
+    fn wakeup(self, scope: &mut Scope<Self::Context>) -> Response<Self, Void> {
+        let Connector { peer, state } = self;
+        match state {
+            State::Established(stream, finished) => {
+                let response = stream.wakeup(scope);
+                Connector::established(peer, response, finished, scope)
+            }
+            // Spurious wakeup.
+            state => Connector::response(peer, state),
+        }
+    }
+}
+
+/// Adaptor of the endpoint of a `Connector`, which tells it whether the
+/// connection has been closed by the endpoint.
+struct Dialed<E: Endpoint> {
+    endpoint: E,
+    /// Set once the connection is closed by the endpoint.
+    finished: Rc<Cell<bool>>,
+    /// The endpoint has returned `Action::CloseOutput`.
+    output_closed: bool,
+}
+
+impl<E: Endpoint> Dialed<E> {
+    fn action(action: Action<E>, finished: Rc<Cell<bool>>, output_closed: bool) -> Action<Self> {
+        let wrap = |endpoint| {
+            Dialed {
+                endpoint: endpoint,
+                finished: finished,
+                output_closed: output_closed,
+            }
+        };
+        match action {
+            Action::Idle(endpoint) => Action::Idle(wrap(endpoint)),
+            Action::Recv(endpoint) => Action::Recv(wrap(endpoint)),
+            Action::Send(endpoint) => Action::Send(wrap(endpoint)),
+            Action::Flush(endpoint) => Action::Flush(wrap(endpoint)),
+            Action::CloseOutput(endpoint) => {
+                let mut dialed = wrap(endpoint);
+                dialed.output_closed = true;
+                Action::CloseOutput(dialed)
+            }
+            Action::Sleep(endpoint, timeout) => Action::Sleep(wrap(endpoint), timeout),
+            Action::Close(endpoint) => Action::Close(wrap(endpoint)),
+            Action::FlushAndClose(endpoint, linger) => {
+                Action::FlushAndClose(wrap(endpoint), linger)
+            }
+        }
+    }
+
+    fn map<F>(self, f: F) -> Action<Self>
+        where F: FnOnce(E) -> Action<E>
+    {
+        let Dialed { endpoint, finished, output_closed } = self;
+        Dialed::action(f(endpoint), finished, output_closed)
+    }
+}
+
+impl<E: Endpoint> Endpoint for Dialed<E> {
+    type Context = E::Context;
+    type Socket = E::Socket;
+    type Seed = (E::Seed, Rc<Cell<bool>>);
+
+    fn create(seed: Self::Seed,
+              sock: &mut Self::Socket,
+              scope: &mut Scope<Self::Context>)
+              -> Action<Self> {
+        let (seed, finished) = seed;
+        Dialed::action(E::create(seed, sock, scope), finished, false)
+    }
+
+    fn message_received(self,
+                        message: &MessageReader,
+                        output: MessageWriter,
+                        scope: &mut Scope<Self::Context>)
+                        -> Action<Self> {
+        self.map(|endpoint| endpoint.message_received(message, output, scope))
+    }
+
+    fn take_message(self,
+                    message: MessageReader,
+                    output: MessageWriter,
+                    scope: &mut Scope<Self::Context>)
+                    -> Action<Self> {
+        self.map(|endpoint| endpoint.take_message(message, output, scope))
+    }
+
+    fn input_closed(self,
+                    output: MessageWriter,
+                    scope: &mut Scope<Self::Context>)
+                    -> Action<Self> {
+        self.map(|endpoint| endpoint.input_closed(output, scope))
+    }
+
+    fn message_flushed(self,
+                       output: MessageWriter,
+                       scope: &mut Scope<Self::Context>)
+                       -> Action<Self> {
+        self.map(|endpoint| endpoint.message_flushed(output, scope))
+    }
+
+    fn output_congested(&mut self, scope: &mut Scope<Self::Context>) {
+        self.endpoint.output_congested(scope)
+    }
+
+    fn output_drained(&mut self, output: MessageWriter, scope: &mut Scope<Self::Context>) {
+        self.endpoint.output_drained(output, scope)
+    }
+
+    fn reader_options(&self, scope: &mut Scope<Self::Context>) -> ReaderOptions {
+        self.endpoint.reader_options(scope)
+    }
+
+    fn framing_limits(&self, scope: &mut Scope<Self::Context>) -> FramingLimits {
+        self.endpoint.framing_limits(scope)
+    }
+
+    fn segment_pool(&self, scope: &mut Scope<Self::Context>) -> Option<SegmentPool> {
+        self.endpoint.segment_pool(scope)
+    }
+
+    fn wire_format(&self, scope: &mut Scope<Self::Context>) -> WireFormat {
+        self.endpoint.wire_format(scope)
+    }
+
+    fn output_limits(&self, scope: &mut Scope<Self::Context>) -> OutputLimits {
+        self.endpoint.output_limits(scope)
+    }
+
+    fn idle_timeout(&self, scope: &mut Scope<Self::Context>) -> Duration {
+        self.endpoint.idle_timeout(scope)
+    }
+
+    fn recv_timeout(&self, scope: &mut Scope<Self::Context>) -> Duration {
+        self.endpoint.recv_timeout(scope)
+    }
+
+    fn recv_deadline(&self, scope: &mut Scope<Self::Context>) -> Time {
+        self.endpoint.recv_deadline(scope)
+    }
+
+    fn recv_budget(&self, scope: &mut Scope<Self::Context>) -> usize {
+        self.endpoint.recv_budget(scope)
+    }
+
+    fn send_timeout(&self, scope: &mut Scope<Self::Context>) -> Duration {
+        self.endpoint.send_timeout(scope)
+    }
+
+    fn output_check_interval(&self, scope: &mut Scope<Self::Context>) -> Duration {
+        self.endpoint.output_check_interval(scope)
+    }
+
+    fn timeout(self,
+               state: ConnectionState,
+               output: MessageWriter,
+               scope: &mut Scope<Self::Context>)
+               -> Action<Self> {
+        self.map(|endpoint| endpoint.timeout(state, output, scope))
+    }
+
+    fn wakeup(self, output: MessageWriter, scope: &mut Scope<Self::Context>) -> Action<Self> {
+        self.map(|endpoint| endpoint.wakeup(output, scope))
+    }
+
+    fn closed(self, reason: CloseReason, scope: &mut Scope<Self::Context>) {
+        let finished = match reason {
+            CloseReason::Local |
+            CloseReason::Shutdown { .. } => true,
+            CloseReason::PeerClosed => self.output_closed,
+            _ => false,
+        };
+        self.finished.set(finished);
+        self.endpoint.closed(reason, scope)
+    }
+}
@@ -0,0 +1,421 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use rotor::{EventSet, Machine, PollOpt, Response, Scope, Time};
+use rotor::mio::udp::UdpSocket;
+use rotor::void::{unreachable, Void};
+
+use error::Error;
+use pool::SegmentPool;
+use serialization::{self, FramingLimits, MessageAllocator, MessageBuilder, MessageReader,
+                    OutputLimits, ReaderOptions, WireFormat};
+
+/// Maximum size of the payload of a UDP datagram over IPv4.
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// Size of the buffer datagrams are received in, any datagram without an
+/// IPv6 jumbogram fits in it.
+const RECV_BUFFER_SIZE: usize = 65536;
+
+/// Wrapper of the new state of `DatagramEndpoint` and the next action.
+pub enum DatagramAction<E: DatagramEndpoint> {
+    /// Wait for datagrams with no timeout.
+    Idle(E),
+    /// Wait for datagrams until the timeout expires, `timeout` is called then.
+    Wait(E, Duration),
+    /// Close the socket immediately, datagrams pending to be sent are discarded.
+    Close(E),
+}
+
+/// A handler for receiving and sending Cap'n Proto messages over UDP.
+///
+/// Every datagram carries exactly one message, framed like on a stream and
+/// packed if the `wire_format` says so. A datagram is received as a whole or
+/// not at all, so a lost one doesn't hold up the others.
+pub trait DatagramEndpoint: Sized {
+    /// Context shared between transitions of the state machine.
+    type Context;
+    /// Seed for initializing the state machine.
+    type Seed;
+
+    /// The socket has been registered in the loop.
+    fn create(seed: Self::Seed,
+              sock: &mut UdpSocket,
+              scope: &mut Scope<Self::Context>)
+              -> DatagramAction<Self>;
+
+    /// A new message has been received from the `source`.
+    fn message_received(self,
+                        message: &MessageReader,
+                        source: SocketAddr,
+                        output: DatagramWriter,
+                        scope: &mut Scope<Self::Context>)
+                        -> DatagramAction<Self>;
+
+    /// A datagram from the `source` has been dropped, as it doesn't hold a
+    /// valid message within the `FramingLimits`.
+    fn invalid_datagram(&mut self,
+                        _source: SocketAddr,
+                        _err: Error,
+                        _scope: &mut Scope<Self::Context>) {
+    }
+
+    /// Receiving has failed, e.g. with `ConnectionRefused` after a datagram
+    /// sent hasn't been delivered. Receiving goes on.
+    fn recv_failed(&mut self, _err: Error, _scope: &mut Scope<Self::Context>) {}
+
+    /// Sending a datagram to the `destination` has failed, it's dropped.
+    fn send_failed(&mut self,
+                   _destination: SocketAddr,
+                   _err: Error,
+                   _scope: &mut Scope<Self::Context>) {
+    }
+
+    /// Options for the Cap'n Proto message reader.
+    fn reader_options(&self, _scope: &mut Scope<Self::Context>) -> ReaderOptions {
+        ReaderOptions::new()
+    }
+
+    /// Limits of the framing of the messages received. By default a message
+    /// has a single segment and fits in a datagram over IPv4.
+    fn framing_limits(&self, _scope: &mut Scope<Self::Context>) -> FramingLimits {
+        FramingLimits {
+            max_segments: 1,
+            max_segment_size: MAX_DATAGRAM_SIZE,
+            max_message_size: MAX_DATAGRAM_SIZE,
+            ..FramingLimits::default()
+        }
+    }
+
+    /// Pool of buffers for the received messages, which may be shared with
+    /// connections. By default the buffers are allocated per message.
+    fn segment_pool(&self, _scope: &mut Scope<Self::Context>) -> Option<SegmentPool> {
+        None
+    }
+
+    /// Encoding of the messages sent and received. By default it's unpacked.
+    fn wire_format(&self, _scope: &mut Scope<Self::Context>) -> WireFormat {
+        WireFormat::Unpacked
+    }
+
+    /// Limits of the messages sent, only `max_message_size` and
+    /// `max_pending_size` apply. By default a message fits in a datagram
+    /// over IPv4.
+    fn output_limits(&self, _scope: &mut Scope<Self::Context>) -> OutputLimits {
+        OutputLimits { max_message_size: MAX_DATAGRAM_SIZE, ..OutputLimits::default() }
+    }
+
+    /// Maximum number of datagrams received in a row before other state
+    /// machines are served. By default it's 64.
+    fn recv_budget(&self, _scope: &mut Scope<Self::Context>) -> usize {
+        64
+    }
+
+    /// Timeout of `DatagramAction::Wait` expired.
+    fn timeout(self,
+               output: DatagramWriter,
+               scope: &mut Scope<Self::Context>)
+               -> DatagramAction<Self>;
+
+    /// The state machine has been woken up.
+    fn wakeup(self,
+              output: DatagramWriter,
+              scope: &mut Scope<Self::Context>)
+              -> DatagramAction<Self>;
+
+    /// The socket has been closed. It's called exactly once, when the state
+    /// machine terminates.
+    fn closed(self, scope: &mut Scope<Self::Context>);
+}
+
+/// Cap'n Proto message serializer, sending every message in a datagram of
+/// its own.
+pub struct DatagramWriter<'a> {
+    queue: &'a mut DatagramQueue,
+    format: WireFormat,
+    limits: OutputLimits,
+}
+
+/// Datagrams waiting for the socket to become writable.
+#[derive(Default)]
+struct DatagramQueue {
+    datagrams: VecDeque<(SocketAddr, Vec<u8>)>,
+    /// Number of bytes in the queue.
+    len: usize,
+}
+
+impl<'a> DatagramWriter<'a> {
+    fn new(queue: &'a mut DatagramQueue,
+           format: WireFormat,
+           limits: OutputLimits)
+           -> DatagramWriter<'a> {
+        DatagramWriter {
+            queue: queue,
+            format: format,
+            limits: limits,
+        }
+    }
+
+    /// Number of bytes pending to be sent.
+    pub fn pending(&self) -> usize {
+        self.queue.len
+    }
+
+    /// Serialize the message and queue it to be sent to the `destination`.
+    ///
+    /// Nothing is queued if the message has more than one segment or exceeds
+    /// the `OutputLimits`, packed messages are measured after packing.
+    pub fn write<A: MessageAllocator>(&mut self,
+                                      message: &MessageBuilder<A>,
+                                      destination: SocketAddr)
+                                      -> Result<(), Error> {
+        let count = message.get_segments_for_output().len();
+        if count > 1 {
+            return Err(Error::OutgoingMessageSegmented { count: count });
+        }
+        let bytes = try!(serialization::write_message(message, self.format));
+        if bytes.len() > self.limits.max_message_size {
+            return Err(Error::OutgoingMessageTooLarge {
+                size: bytes.len(),
+                limit: self.limits.max_message_size,
+            });
+        }
+        if self.queue.len + bytes.len() > self.limits.max_pending_size {
+            return Err(Error::OutputBufferFull {
+                pending: self.queue.len,
+                limit: self.limits.max_pending_size,
+            });
+        }
+        self.queue.len += bytes.len();
+        self.queue.datagrams.push_back((destination, bytes));
+        Ok(())
+    }
+}
+
+/// State machine for a UDP socket carrying Cap'n Proto messages.
+pub struct Datagram<E: DatagramEndpoint>(State<E>);
+
+enum State<E: DatagramEndpoint> {
+    Open(E, Box<Transport>),
+    /// The endpoint has been closed, the state machine terminates once it's
+    /// woken up by the deadline.
+    Closed,
+}
+
+struct Transport {
+    sock: UdpSocket,
+    input: Vec<u8>,
+    queue: DatagramQueue,
+    /// Deadline of `DatagramAction::Wait`.
+    deadline: Option<Time>,
+    /// Events the socket is registered for.
+    interest: EventSet,
+}
+
+impl<E: DatagramEndpoint> Datagram<E> {
+    /// Receive and send messages on the bound `sock`.
+    pub fn new(mut sock: UdpSocket,
+               seed: E::Seed,
+               scope: &mut Scope<E::Context>)
+               -> Response<Self, Void> {
+        if let Err(err) = scope.register(&sock, EventSet::readable(), PollOpt::level()) {
+            return Response::error(Box::new(err));
+        }
+        let action = E::create(seed, &mut sock, scope);
+        let transport = Box::new(Transport {
+            sock: sock,
+            input: vec![0; RECV_BUFFER_SIZE],
+            queue: DatagramQueue::default(),
+            deadline: None,
+            interest: EventSet::readable(),
+        });
+        Datagram::action(action, transport, scope)
+    }
+
+    /// Apply the action, then send the datagrams queued.
+    fn action(action: DatagramAction<E>,
+              mut transport: Box<Transport>,
+              scope: &mut Scope<E::Context>)
+              -> Response<Self, Void> {
+        match Datagram::apply(action, &mut transport, scope) {
+            Some(fsm) => Datagram::flush(fsm, transport, scope),
+            None => Datagram::closed(scope),
+        }
+    }
+
+    /// Update the deadline for the action, returns `None` if the endpoint
+    /// has been closed.
+    fn apply(action: DatagramAction<E>,
+             transport: &mut Transport,
+             scope: &mut Scope<E::Context>)
+             -> Option<E> {
+        match action {
+            DatagramAction::Idle(fsm) => {
+                transport.deadline = None;
+                Some(fsm)
+            }
+            DatagramAction::Wait(fsm, timeout) => {
+                transport.deadline = Some(scope.now() + timeout);
+                Some(fsm)
+            }
+            DatagramAction::Close(fsm) => {
+                let _ = scope.deregister(&transport.sock);
+                fsm.closed(scope);
+                None
+            }
+        }
+    }
+
+    /// The state machine can't terminate right away when it's created, so
+    /// it does once the deadline expires.
+    fn closed(scope: &mut Scope<E::Context>) -> Response<Self, Void> {
+        let now = scope.now();
+        Response::ok(Datagram(State::Closed)).deadline(now)
+    }
+
+    /// Send the datagrams queued until the socket would block, and wait for
+    /// it to become writable if any are left.
+    fn flush(mut fsm: E,
+             mut transport: Box<Transport>,
+             scope: &mut Scope<E::Context>)
+             -> Response<Self, Void> {
+        while let Some(&(destination, ref bytes)) = transport.queue.datagrams.front() {
+            match transport.sock.send_to(bytes, &destination) {
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(err) => fsm.send_failed(destination, Error::SendTo(err), scope),
+            }
+            transport.queue.len -= bytes.len();
+            transport.queue.datagrams.pop_front();
+        }
+        let interest = if transport.queue.datagrams.is_empty() {
+            EventSet::readable()
+        } else {
+            EventSet::readable() | EventSet::writable()
+        };
+        if interest != transport.interest {
+            if let Err(err) = scope.reregister(&transport.sock, interest, PollOpt::level()) {
+                fsm.closed(scope);
+                return Response::error(Box::new(err));
+            }
+            transport.interest = interest;
+        }
+        let deadline = transport.deadline;
+        let response = Response::ok(Datagram(State::Open(fsm, transport)));
+        match deadline {
+            Some(deadline) => response.deadline(deadline),
+            None => response,
+        }
+    }
+
+    /// Receive datagrams until the socket would block or the budget is
+    /// spent, the rest are received once the loop polls again.
+    fn receive(mut fsm: E,
+               mut transport: Box<Transport>,
+               scope: &mut Scope<E::Context>)
+               -> Response<Self, Void> {
+        for _ in 0..fsm.recv_budget(scope) {
+            let (len, source) = match transport.sock.recv_from(&mut transport.input) {
+                Ok(Some(datagram)) => datagram,
+                Ok(None) => break,
+                Err(err) => {
+                    fsm.recv_failed(Error::RecvFrom(err), scope);
+                    continue;
+                }
+            };
+            let format = fsm.wire_format(scope);
+            let options = fsm.reader_options(scope);
+            let limits = fsm.framing_limits(scope);
+            let output_limits = fsm.output_limits(scope);
+            let pool = fsm.segment_pool(scope);
+            let action = {
+                let transport = &mut *transport;
+                match serialization::read_message(&transport.input[..len],
+                                                  format,
+                                                  options,
+                                                  limits,
+                                                  pool) {
+                    Ok(message) => {
+                        let output =
+                            DatagramWriter::new(&mut transport.queue, format, output_limits);
+                        fsm.message_received(&message, source, output, scope)
+                    }
+                    Err(err) => {
+                        fsm.invalid_datagram(source, err, scope);
+                        continue;
+                    }
+                }
+            };
+            fsm = match Datagram::apply(action, &mut transport, scope) {
+                Some(fsm) => fsm,
+                None => return Datagram::closed(scope),
+            };
+        }
+        Datagram::flush(fsm, transport, scope)
+    }
+
+    /// Call a hook taking the writer.
+    fn call<F>(fsm: E,
+               mut transport: Box<Transport>,
+               scope: &mut Scope<E::Context>,
+               hook: F)
+               -> Response<Self, Void>
+        where F: FnOnce(E, DatagramWriter, &mut Scope<E::Context>) -> DatagramAction<E>
+    {
+        let format = fsm.wire_format(scope);
+        let limits = fsm.output_limits(scope);
+        let action = hook(fsm,
+                          DatagramWriter::new(&mut transport.queue, format, limits),
+                          scope);
+        Datagram::action(action, transport, scope)
+    }
+}
+
+impl<E: DatagramEndpoint> Machine for Datagram<E> {
+    type Context = E::Context;
+    type Seed = Void;
+
+    fn create(seed: Self::Seed, _scope: &mut Scope<Self::Context>) -> Response<Self, Void> {
+        unreachable(seed)
+    }
+
+    fn ready(self, events: EventSet, scope: &mut Scope<Self::Context>) -> Response<Self, Void> {
+        match self.0 {
+            State::Open(fsm, transport) => {
+                if events.is_readable() {
+                    Datagram::receive(fsm, transport, scope)
+                } else {
+                    Datagram::flush(fsm, transport, scope)
+                }
+            }
+            State::Closed => Response::done(),
+        }
+    }
+
+    fn spawned(self, _scope: &mut Scope<Self::Context>) -> Response<Self, Void> {
+        unreachable!()
+    }
+
+    fn timeout(self, scope: &mut Scope<Self::Context>) -> Response<Self, Void> {
+        match self.0 {
+            State::Open(fsm, mut transport) => {
+                match transport.deadline {
+                    Some(deadline) if deadline <= scope.now() => {
+                        transport.deadline = None;
+                        Datagram::call(fsm, transport, scope, E::timeout)
+                    }
+                    _ => Datagram::flush(fsm, transport, scope),
+                }
+            }
+            State::Closed => Response::done(),
+        }
+    }
+
+    fn wakeup(self, scope: &mut Scope<Self::Context>) -> Response<Self, Void> {
+        match self.0 {
+            State::Open(fsm, transport) => Datagram::call(fsm, transport, scope, E::wakeup),
+            State::Closed => Response::done(),
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::io;
+
 use rotor_stream;
 
 use serialization;
@@ -9,6 +11,8 @@ quick_error! {
         /// Cap'n Proto (de)serialization error.
         /// See `capnp::Error` for details.
         Serialization(err: serialization::Error) {
+            from()
+            from(err: io::Error) -> (serialization::Error::from(err))
             cause(err)
             description(err.description())
         }
@@ -19,5 +23,96 @@ quick_error! {
             description(err.description())
             display("{}", err)
         }
+        /// A received message has more segments than `FramingLimits::max_segments`.
+        TooManySegments { count: usize, limit: usize } {
+            description("received message has too many segments")
+            display("received message has {} segments, the limit is {}", count, limit)
+        }
+        /// A received message has no segments.
+        ZeroSegments {
+            description("received message has no segments")
+        }
+        /// A segment of a received message is larger than
+        /// `FramingLimits::max_segment_size`.
+        SegmentTooLarge { size: usize, limit: usize } {
+            description("received segment is too large")
+            display("received segment of {} bytes exceeds the limit of {} bytes", size, limit)
+        }
+        /// A received message is larger than `FramingLimits::max_message_size`
+        /// or `ReaderOptions::traversal_limit_in_words`.
+        MessageTooLarge { size: usize, limit: usize } {
+            description("received message is too large")
+            display("received message of {} bytes exceeds the limit of {} bytes", size, limit)
+        }
+        /// A received packed message has a run of words crossing its end.
+        InvalidPacking {
+            description("received packed message did not end on a word boundary")
+        }
+        /// The peer closed the connection while a message was being received.
+        PeerClosedMidMessage {
+            description("connection closed in the middle of a message")
+        }
+        /// An outgoing message is larger than `OutputLimits::max_message_size`.
+        OutgoingMessageTooLarge { size: usize, limit: usize } {
+            description("outgoing message is too large")
+            display("outgoing message of {} bytes exceeds the limit of {} bytes", size, limit)
+        }
+        /// Writing a message would exceed `OutputLimits::max_pending_size`.
+        OutputBufferFull { pending: usize, limit: usize } {
+            description("output buffer is full")
+            display("{} bytes pending to be sent, the limit is {} bytes", pending, limit)
+        }
+        /// A message is written after `Action::CloseOutput`.
+        OutputClosed {
+            description("write side of the connection is shut down")
+        }
+        /// Error connecting to the peer.
+        Connect(err: io::Error) {
+            cause(err)
+            description(err.description())
+            display("error connecting: {}", err)
+        }
+        /// The connection hasn't been established before the timeout for
+        /// connecting expired.
+        ConnectTimeout {
+            description("timeout for connecting expired")
+        }
+        /// Error completing the handshake with the peer, see `Handshake`.
+        Handshake(err: io::Error) {
+            cause(err)
+            description(err.description())
+            display("error in handshake: {}", err)
+        }
+        /// The handshake hasn't been completed before its timeout expired.
+        HandshakeTimeout {
+            description("timeout for handshake expired")
+        }
+        /// A received datagram doesn't hold exactly one message.
+        InvalidDatagram { size: usize } {
+            description("received datagram doesn't hold exactly one message")
+            display("received datagram of {} bytes doesn't hold exactly one message", size)
+        }
+        /// An outgoing message written to a `DatagramWriter` has more than
+        /// one segment.
+        OutgoingMessageSegmented { count: usize } {
+            description("outgoing message has more than one segment")
+            display("outgoing message has {} segments, a datagram carries one", count)
+        }
+        /// Error receiving a datagram.
+        RecvFrom(err: io::Error) {
+            cause(err)
+            description(err.description())
+            display("error receiving a datagram: {}", err)
+        }
+        /// Error sending a datagram.
+        SendTo(err: io::Error) {
+            cause(err)
+            description(err.description())
+            display("error sending a datagram: {}", err)
+        }
+        /// A message has been received while no request is pending.
+        UnexpectedMessage {
+            description("received a message without a pending request")
+        }
     }
 }
@@ -2,22 +2,49 @@
 //!
 extern crate byteorder;
 extern crate capnp;
+#[cfg(unix)]
+extern crate libc;
 extern crate rotor;
 extern crate rotor_stream;
 #[macro_use]
 extern crate quick_error;
 
+mod acceptor;
+mod client;
+mod connector;
+mod datagram;
 mod error;
+#[cfg(feature = "testing")]
+mod loopback;
+mod pool;
 mod protocol;
 mod serialization;
+mod socket;
 mod stream;
+mod tls;
 
 pub use rotor_stream::{Accept, Persistent, Stream};
 
+pub use acceptor::{Accepted, Acceptor, Admission, Rejection};
+pub use client::{Client, ClientAction, RequestSeq, Requester, Requests};
+pub use connector::{Connector, Reconnect, Resolve};
+pub use datagram::{Datagram, DatagramAction, DatagramEndpoint, DatagramWriter};
 pub use error::Error;
-pub use protocol::{Action, ConnectionState, Endpoint};
-pub use serialization::{MessageReader, MessageBuilder, MessageWriter};
+#[cfg(feature = "testing")]
+pub use loopback::{Harness, LoopbackSocket};
+pub use pool::SegmentPool;
+pub use protocol::{Action, CloseReason, ConnectionState, Endpoint};
+pub use serialization::{into_owned, FramingLimits, MessageReader, MessageBuilder, MessageWriter,
+                        OutputLimits, OwnedMessage, WireFormat};
+pub use socket::{HalfClose, Handshake, PeerAddr, PeerCred, PeerCredentials};
 pub use stream::Capnp;
+pub use tls::{StartSession, TlsListener, TlsSession, TlsStream, TlsTarget};
 
 /// State machine for the Cap'n Proto message stream.
 pub type CapnpStream<E> = Stream<Capnp<E>>;
+
+/// State machine for the client side of a request/response protocol.
+pub type ClientStream<C> = CapnpStream<Requester<C>>;
+
+/// State machine reconnecting the client side of a request/response protocol.
+pub type ClientConnector<C, R> = Connector<Requester<C>, R>;
@@ -0,0 +1,479 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use rotor::{self, EventSet, Machine, Response, Scope, Time};
+use rotor::{_LoopApi as LoopApi, _Notify as Notify};
+use rotor::mio::{self, EventLoop, Evented, PollOpt, Selector, Sender, Timeout, TimerError, Token};
+use rotor::void::Void;
+use rotor_stream::{SocketError, Stream};
+
+use protocol::Endpoint;
+use socket::{HalfClose, Handshake, PeerAddr};
+use stream::Capnp;
+
+/// Data in flight from one end of a loopback connection to the other.
+struct Pipe {
+    /// Chunks of the data written and the time they become readable at.
+    chunks: VecDeque<(Duration, Vec<u8>)>,
+    /// Number of bytes in the chunks.
+    len: usize,
+    delay: Duration,
+    chunk_size: usize,
+    capacity: usize,
+    /// The writing end has shut down its side or has been dropped.
+    write_closed: bool,
+    /// The reading end has been dropped.
+    read_closed: bool,
+    /// Error of the next read of the reading end.
+    read_error: Option<io::Error>,
+    /// Error of the next write of the writing end.
+    write_error: Option<io::Error>,
+}
+
+impl Pipe {
+    fn new() -> Pipe {
+        Pipe {
+            chunks: VecDeque::new(),
+            len: 0,
+            delay: Duration::from_secs(0),
+            chunk_size: usize::max_value(),
+            capacity: usize::max_value(),
+            write_closed: false,
+            read_closed: false,
+            read_error: None,
+            write_error: None,
+        }
+    }
+}
+
+struct Link {
+    /// Virtual time elapsed since the sockets were created.
+    clock: Duration,
+    /// Pipes written by either end.
+    pipes: [Pipe; 2],
+    /// Number of events of either end, anything the other end does counts.
+    events: [u64; 2],
+}
+
+impl Link {
+    /// Move the clock to `now`, the chunks which become readable count as
+    /// events of their reading end.
+    fn advance(&mut self, now: Duration) {
+        for end in 0..2 {
+            let clock = self.clock;
+            if self.pipes[end].chunks.iter().any(|&(at, _)| clock < at && at <= now) {
+                self.events[1 - end] += 1;
+            }
+        }
+        self.clock = now;
+    }
+}
+
+/// One end of an in-memory connection, for testing an `Endpoint` with a
+/// `Harness` instead of the network.
+///
+/// Either end is non-blocking, like a socket registered in the loop.
+/// The settings of an end apply to the data it writes, so the other end
+/// may be used by the test to feed the endpoint with chunked, delayed or
+/// failed input.
+///
+/// It's only available with the `testing` feature.
+pub struct LoopbackSocket {
+    link: Arc<Mutex<Link>>,
+    /// Index of the pipe written by this end.
+    end: usize,
+}
+
+impl LoopbackSocket {
+    /// Create both ends of a connection.
+    pub fn pair() -> (LoopbackSocket, LoopbackSocket) {
+        let link = Arc::new(Mutex::new(Link {
+            clock: Duration::from_secs(0),
+            pipes: [Pipe::new(), Pipe::new()],
+            // Either end is writable once connected, like a socket when it's
+            // registered.
+            events: [1, 1],
+        }));
+        (LoopbackSocket {
+            link: link.clone(),
+            end: 0,
+        },
+         LoopbackSocket {
+            link: link,
+            end: 1,
+        })
+    }
+
+    fn link(&self) -> MutexGuard<Link> {
+        self.link.lock().unwrap()
+    }
+
+    /// Data written by this end becomes readable by the other one after the
+    /// `delay`, chunk after chunk.
+    pub fn set_delay(&self, delay: Duration) {
+        self.link().pipes[self.end].delay = delay;
+    }
+
+    /// Data written by this end is read by the other one in chunks of at
+    /// most `size` bytes.
+    pub fn set_chunk_size(&self, size: usize) {
+        self.link().pipes[self.end].chunk_size = cmp::max(size, 1);
+    }
+
+    /// At most `capacity` bytes written by this end are left unread by the
+    /// other one, writing more fails with `WouldBlock`.
+    pub fn set_capacity(&self, capacity: usize) {
+        self.link().pipes[self.end].capacity = capacity;
+    }
+
+    /// The next read of the other end fails with the `err`.
+    pub fn fail_peer_read(&self, err: io::Error) {
+        let mut link = self.link();
+        link.pipes[self.end].read_error = Some(err);
+        link.events[1 - self.end] += 1;
+    }
+
+    /// The next write of the other end fails with the `err`.
+    pub fn fail_peer_write(&self, err: io::Error) {
+        let mut link = self.link();
+        link.pipes[1 - self.end].write_error = Some(err);
+        link.events[1 - self.end] += 1;
+    }
+}
+
+impl Read for LoopbackSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut link = self.link();
+        let link = &mut *link;
+        let pipe = &mut link.pipes[1 - self.end];
+        if let Some(err) = pipe.read_error.take() {
+            return Err(err);
+        }
+        if pipe.chunks.is_empty() && pipe.write_closed {
+            return Ok(0);
+        }
+        let (len, consumed) = match pipe.chunks.front_mut() {
+            Some(&mut (at, ref mut chunk)) if at <= link.clock => {
+                let len = cmp::min(buf.len(), chunk.len());
+                buf[..len].copy_from_slice(&chunk[..len]);
+                chunk.drain(..len);
+                (len, chunk.is_empty())
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::WouldBlock, "no data to read")),
+        };
+        if consumed {
+            pipe.chunks.pop_front();
+        }
+        pipe.len -= len;
+        // The room freed is an event of the writing end.
+        link.events[1 - self.end] += 1;
+        Ok(len)
+    }
+}
+
+impl Write for LoopbackSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut link = self.link();
+        let link = &mut *link;
+        let pipe = &mut link.pipes[self.end];
+        if let Some(err) = pipe.write_error.take() {
+            return Err(err);
+        }
+        if pipe.write_closed || pipe.read_closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"));
+        }
+        let len = cmp::min(buf.len(), pipe.capacity.saturating_sub(pipe.len));
+        if len == 0 && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "no room to write"));
+        }
+        for chunk in buf[..len].chunks(pipe.chunk_size) {
+            let previous = pipe.chunks.back().map_or(link.clock, |&(at, _)| at);
+            let at = cmp::max(previous, link.clock) + pipe.delay;
+            pipe.chunks.push_back((at, chunk.to_vec()));
+        }
+        pipe.len += len;
+        link.events[1 - self.end] += 1;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The other end reads EOF after the data already written, and fails to
+/// write with `BrokenPipe`.
+impl Drop for LoopbackSocket {
+    fn drop(&mut self) {
+        if let Ok(mut link) = self.link.lock() {
+            link.pipes[self.end].write_closed = true;
+            link.pipes[1 - self.end].read_closed = true;
+            link.events[1 - self.end] += 1;
+        }
+    }
+}
+
+/// A loopback socket is driven by a `Harness`, it can't be registered in
+/// a loop.
+impl Evented for LoopbackSocket {
+    fn register(&self,
+                _selector: &mut Selector,
+                _token: Token,
+                _interest: EventSet,
+                _opts: PollOpt)
+                -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::InvalidInput,
+                           "loopback socket is driven by a harness"))
+    }
+
+    fn reregister(&self,
+                  _selector: &mut Selector,
+                  _token: Token,
+                  _interest: EventSet,
+                  _opts: PollOpt)
+                  -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::InvalidInput,
+                           "loopback socket is driven by a harness"))
+    }
+
+    fn deregister(&self, _selector: &mut Selector) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::InvalidInput,
+                           "loopback socket is driven by a harness"))
+    }
+}
+
+impl SocketError for LoopbackSocket {
+    fn take_socket_error(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl HalfClose for LoopbackSocket {
+    fn close_write(&mut self) -> io::Result<()> {
+        let mut link = self.link();
+        link.pipes[self.end].write_closed = true;
+        link.events[1 - self.end] += 1;
+        Ok(())
+    }
+}
+
+impl Handshake for LoopbackSocket {
+    fn handshake(&mut self) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+impl PeerAddr for LoopbackSocket {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+/// The loop as seen by the state machine of a harness, the socket isn't
+/// registered anywhere.
+struct Api {
+    shutdown: bool,
+}
+
+impl LoopApi for Api {
+    fn register(&mut self,
+                _io: &Evented,
+                _token: Token,
+                _interest: EventSet,
+                _opt: PollOpt)
+                -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reregister(&mut self,
+                  _io: &Evented,
+                  _token: Token,
+                  _interest: EventSet,
+                  _opt: PollOpt)
+                  -> io::Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&mut self, _io: &Evented) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn timeout_ms(&mut self, _token: Token, _delay: u64) -> Result<Timeout, TimerError> {
+        panic!("timeouts of a harness are set by the deadlines of the state machine")
+    }
+
+    fn clear_timeout(&mut self, _timeout: Timeout) -> bool {
+        false
+    }
+
+    fn shutdown(&mut self) {
+        self.shutdown = true;
+    }
+}
+
+/// Counter of the wakeups sent to the state machine by `Notifier`s.
+struct Wakeups(usize);
+
+impl mio::Handler for Wakeups {
+    type Timeout = ();
+    type Message = Notify;
+
+    fn notify(&mut self, _event_loop: &mut EventLoop<Wakeups>, _msg: Notify) {
+        self.0 += 1;
+    }
+}
+
+/// Driver of a `CapnpStream` on a `LoopbackSocket` in virtual time, for
+/// unit testing an `Endpoint` deterministically.
+///
+/// Time only passes in `advance`, a millisecond at a time, and the events
+/// of the socket and the wakeups are delivered in between. The test plays
+/// the peer with the other end of the socket.
+///
+/// It's only available with the `testing` feature, as it relies on the
+/// internals of rotor which are hidden from its documentation.
+pub struct Harness<E: Endpoint<Socket = LoopbackSocket>> {
+    stream: Option<Stream<Capnp<E>>>,
+    context: E::Context,
+    link: Arc<Mutex<Link>>,
+    end: usize,
+    /// Number of events of the socket delivered already.
+    delivered: u64,
+    api: Api,
+    channel: Sender<Notify>,
+    notifications: EventLoop<Wakeups>,
+    wakeups: Wakeups,
+}
+
+impl<E: Endpoint<Socket = LoopbackSocket>> Harness<E> {
+    /// Create the state machine on the `sock` like on an accepted
+    /// connection, and deliver the events pending.
+    pub fn new(sock: LoopbackSocket,
+               seed: E::Seed,
+               context: E::Context)
+               -> io::Result<Harness<E>> {
+        let notifications = try!(EventLoop::new());
+        let mut harness = Harness {
+            stream: None,
+            context: context,
+            link: sock.link.clone(),
+            end: sock.end,
+            delivered: 0,
+            api: Api { shutdown: false },
+            channel: notifications.channel(),
+            notifications: notifications,
+            wakeups: Wakeups(0),
+        };
+        let response = harness.with_scope(|scope| Stream::new(sock, seed, scope));
+        harness.resume(response);
+        harness.poll();
+        Ok(harness)
+    }
+
+    /// Whether the state machine is still running.
+    pub fn is_running(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Whether the endpoint has asked to shut down the loop.
+    pub fn is_shutdown(&self) -> bool {
+        self.api.shutdown
+    }
+
+    /// Context of the state machine.
+    pub fn context(&mut self) -> &mut E::Context {
+        &mut self.context
+    }
+
+    /// Virtual time elapsed since the harness has been created.
+    pub fn elapsed(&self) -> Duration {
+        self.link.lock().unwrap().clock
+    }
+
+    /// Deliver the events of the socket and the wakeups pending, with no
+    /// time passing. Returns whether the state machine is still running.
+    pub fn poll(&mut self) -> bool {
+        while self.is_running() {
+            if self.wakeups.0 == 0 {
+                try_or_panic(self.notifications.run_once(&mut self.wakeups, Some(0)));
+            }
+            if self.wakeups.0 > 0 {
+                self.wakeups.0 -= 1;
+                self.step(|stream, scope| stream.wakeup(scope));
+                continue;
+            }
+            let events = self.link.lock().unwrap().events[self.end];
+            if events == self.delivered {
+                break;
+            }
+            self.delivered = events;
+            let events = EventSet::readable() | EventSet::writable();
+            self.step(|stream, scope| stream.ready(events, scope));
+        }
+        self.is_running()
+    }
+
+    /// Wake up the state machine like a `Notifier`, then poll.
+    pub fn wakeup(&mut self) -> bool {
+        self.step(|stream, scope| stream.wakeup(scope));
+        self.poll()
+    }
+
+    /// Let the `duration` pass, polling and expiring the timeouts every
+    /// millisecond. Returns whether the state machine is still running.
+    pub fn advance(&mut self, duration: Duration) -> bool {
+        let end = self.elapsed() + duration;
+        while self.poll() && self.elapsed() < end {
+            let now = cmp::min(self.elapsed() + Duration::from_millis(1), end);
+            self.link.lock().unwrap().advance(now);
+            if self.poll() {
+                // rotor-stream ignores a timeout before its deadline.
+                self.step(|stream, scope| stream.timeout(scope));
+            }
+        }
+        self.is_running()
+    }
+
+    /// Let time pass until the state machine terminates, but no longer than
+    /// the `limit`. Returns whether it has terminated.
+    pub fn run(&mut self, limit: Duration) -> bool {
+        !self.advance(limit)
+    }
+
+    fn with_scope<F, R>(&mut self, f: F) -> R
+        where F: FnOnce(&mut Scope<E::Context>) -> R
+    {
+        let time = Time::zero() + self.elapsed();
+        let mut scope = rotor::_scope(time,
+                                      Token(0),
+                                      &mut self.context,
+                                      &mut self.channel,
+                                      &mut self.api);
+        f(&mut scope)
+    }
+
+    fn step<F>(&mut self, f: F)
+        where F: FnOnce(Stream<Capnp<E>>, &mut Scope<E::Context>)
+                        -> Response<Stream<Capnp<E>>, Void>
+    {
+        if let Some(stream) = self.stream.take() {
+            let response = self.with_scope(|scope| f(stream, scope));
+            self.resume(response);
+        }
+    }
+
+    fn resume(&mut self, response: Response<Stream<Capnp<E>>, Void>) {
+        let stream = &mut self.stream;
+        response.wrap(|machine| *stream = Some(machine));
+    }
+}
+
+fn try_or_panic(result: io::Result<()>) {
+    if let Err(err) = result {
+        panic!("polling for wakeups failed: {}", err);
+    }
+}
@@ -0,0 +1,135 @@
+use std::sync::{Arc, Mutex};
+
+use capnp::Word;
+
+/// Size of the buffers of the smallest size class in words.
+const MIN_CLASS_WORDS: usize = 64;
+/// Number of size classes, each twice as large as the previous one.
+const SIZE_CLASSES: usize = 16;
+
+/// Pool of buffers reused for the segments of received messages.
+///
+/// Buffers are returned to the pool when the message is dropped. Cloning
+/// the pool gives another handle to it, so that a pool can be shared by
+/// all the connections of a loop.
+#[derive(Clone)]
+pub struct SegmentPool {
+    buffers: Arc<Mutex<Buffers>>,
+}
+
+struct Buffers {
+    classes: Vec<Vec<Vec<Word>>>,
+    /// Total size of the unused buffers.
+    words: usize,
+    max_words: usize,
+}
+
+impl SegmentPool {
+    /// Create a pool keeping at most `max_bytes` of unused buffers.
+    pub fn new(max_bytes: usize) -> SegmentPool {
+        SegmentPool {
+            buffers: Arc::new(Mutex::new(Buffers {
+                classes: (0..SIZE_CLASSES).map(|_| Vec::new()).collect(),
+                words: 0,
+                max_words: max_bytes / 8,
+            })),
+        }
+    }
+
+    /// Take a buffer of at least `len` words.
+    ///
+    /// A reused buffer isn't zeroed, it still holds the words of the message
+    /// it was taken for. They're overwritten by the segments of the next one,
+    /// whose readers don't reach past the segments.
+    pub fn take(&self, len: usize) -> Vec<Word> {
+        let class = match size_class(len) {
+            Some(class) => class,
+            None => return Word::allocate_zeroed_vec(len),
+        };
+        let mut buffers = self.buffers.lock().unwrap();
+        match buffers.classes[class].pop() {
+            Some(buf) => {
+                buffers.words -= buf.len();
+                buf
+            }
+            None => Word::allocate_zeroed_vec(MIN_CLASS_WORDS << class),
+        }
+    }
+
+    /// Return a buffer taken from the pool. It's dropped if the pool is full.
+    pub fn give(&self, buf: Vec<Word>) {
+        match size_class(buf.len()) {
+            Some(class) if buf.len() == MIN_CLASS_WORDS << class => {
+                let mut buffers = self.buffers.lock().unwrap();
+                if buffers.words + buf.len() <= buffers.max_words {
+                    buffers.words += buf.len();
+                    buffers.classes[class].push(buf);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Index of the smallest size class fitting `len` words.
+fn size_class(len: usize) -> Option<usize> {
+    (0..SIZE_CLASSES).find(|&class| MIN_CLASS_WORDS << class >= len)
+}
+
+#[cfg(test)]
+mod tests {
+    use capnp::Word;
+
+    use super::{SegmentPool, MIN_CLASS_WORDS, SIZE_CLASSES};
+
+    /// Total size of the unused buffers in the pool.
+    fn pooled(pool: &SegmentPool) -> usize {
+        pool.buffers.lock().unwrap().words
+    }
+
+    #[test]
+    fn rounded_to_size_class() {
+        let pool = SegmentPool::new(1 << 20);
+        assert_eq!(pool.take(1).len(), MIN_CLASS_WORDS);
+        assert_eq!(pool.take(MIN_CLASS_WORDS).len(), MIN_CLASS_WORDS);
+        assert_eq!(pool.take(MIN_CLASS_WORDS + 1).len(), MIN_CLASS_WORDS * 2);
+
+        // Buffers larger than the largest class are neither rounded nor kept.
+        let len = (MIN_CLASS_WORDS << (SIZE_CLASSES - 1)) + 1;
+        let buf = pool.take(len);
+        assert_eq!(buf.len(), len);
+        pool.give(buf);
+        assert_eq!(pooled(&pool), 0);
+    }
+
+    #[test]
+    fn reused_with_stale_contents() {
+        let pool = SegmentPool::new(1 << 20);
+        let mut buf = pool.take(100);
+        Word::words_to_bytes_mut(&mut buf)[0] = 42;
+        let ptr = buf.as_ptr();
+        pool.give(buf);
+        assert_eq!(pooled(&pool), MIN_CLASS_WORDS * 2);
+
+        // Any length of the same class gets the buffer back.
+        let buf = pool.take(MIN_CLASS_WORDS + 1);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(Word::words_to_bytes(&buf)[0], 42);
+        assert_eq!(pooled(&pool), 0);
+    }
+
+    #[test]
+    fn capped_at_max_bytes() {
+        let pool = SegmentPool::new(MIN_CLASS_WORDS * 8 * 3);
+        let buffers: Vec<_> = (0..4).map(|_| pool.take(1)).collect();
+        for buf in buffers {
+            pool.give(buf);
+        }
+        assert_eq!(pooled(&pool), MIN_CLASS_WORDS * 3);
+
+        // Only a buffer of a size class is kept.
+        let pool = SegmentPool::new(1 << 20);
+        pool.give(Word::allocate_zeroed_vec(MIN_CLASS_WORDS + 1));
+        assert_eq!(pooled(&pool), 0);
+    }
+}
@@ -1,27 +1,55 @@
 use std::time::Duration;
 
-use rotor::Scope;
+use rotor::{Scope, Time};
 use rotor_stream::StreamSocket;
 
+use acceptor::Rejection;
 use error::Error;
-use serialization::{MessageReader, MessageWriter, ReaderOptions};
+use pool::SegmentPool;
+use serialization::{FramingLimits, MessageReader, MessageWriter, OutputLimits, ReaderOptions,
+                    WireFormat};
+use socket::HalfClose;
 
 /// Wrapper of the new state of `Endpoint` and the next action.
 pub enum Action<E: Endpoint> {
     /// Wait for arrival of a new message until the timeout expires.
     Idle(E),
     /// Receive new message until the timeout expires.
+    ///
+    /// If a message was being received when the timeout expired or the state
+    /// machine was woken up, receiving of that message is continued.
     Recv(E),
+    /// Wait for arrival of a new message like `Idle`, while the write buffer
+    /// is flushed in background until the timeout for sending expires.
+    ///
+    /// `message_flushed` is called once the write buffer is empty. The
+    /// input is read whenever the socket drains meanwhile, but only every
+    /// `Endpoint::output_check_interval` while it doesn't.
+    Send(E),
     /// Flush the write buffer until the timeout expires.
+    /// No messages are received meanwhile.
     Flush(E),
+    /// Flush the write buffer in background like `Send`, then shut down the
+    /// write side of the connection while messages are still received.
+    ///
+    /// `message_flushed` isn't called, and writing afterwards fails with
+    /// `Error::OutputClosed`.
+    CloseOutput(E),
     /// Sleep until the specified the timeout expires.
     Sleep(E, Duration),
     /// Close the connection immediately, pending data in the buffers will be discarded.
-    Close,
+    Close(E),
+    /// Flush the write buffer until the timeout for sending expires, then
+    /// shut down the write side of the connection and close it.
+    ///
+    /// With a linger timeout, the input is discarded until the peer closes
+    /// its side or the timeout expires. It's closed right away if the peer
+    /// has closed its side already.
+    FlushAndClose(E, Option<Duration>),
 }
 
 /// State of the underlying connection
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ConnectionState {
     Idle,
     Receiving,
@@ -29,15 +57,33 @@ pub enum ConnectionState {
     Sleeping,
 }
 
+/// Reason of closing the connection.
+#[derive(Debug)]
+pub enum CloseReason {
+    /// The peer has closed the connection between messages.
+    PeerClosed,
+    /// The endpoint has returned `Action::Close`.
+    Local,
+    /// The endpoint has returned `Action::Close` after the timeout expired
+    /// during the state.
+    Timeout(ConnectionState),
+    /// The connection has been closed after `Action::FlushAndClose`,
+    /// `flushed` is false if the output couldn't be sent in time.
+    Shutdown { flushed: bool },
+    /// The connection has failed.
+    Error(Error),
+}
+
 /// A handler for receiving and sending Cap'n Proto messages.
 ///
-/// Currently this is used by both client side and server side of the connection.
-/// Client specific abstractions might be added in the future.
+/// It's used by both the client side and the server side of a connection.
+/// A client matching responses to its requests can implement `Client`
+/// instead, which `Requester` adapts to this trait.
 pub trait Endpoint: Sized {
     /// Context shared between transitions of the state machine.
     type Context;
     /// Type of the underlying socket.
-    type Socket: StreamSocket;
+    type Socket: StreamSocket + HalfClose;
     /// Seed for initializing the state machine.
     type Seed;
 
@@ -54,17 +100,70 @@ pub trait Endpoint: Sized {
                         scope: &mut Scope<Self::Context>)
                         -> Action<Self>;
 
+    /// A new message has been received, which the handler takes the
+    /// ownership of. By default it's passed on to `message_received`.
+    ///
+    /// The message may borrow the input buffer, see `into_owned` for keeping
+    /// it after the call.
+    fn take_message(self,
+                    message: MessageReader,
+                    output: MessageWriter,
+                    scope: &mut Scope<Self::Context>)
+                    -> Action<Self> {
+        self.message_received(&message, output, scope)
+    }
+
+    /// The peer has shut down its side of the connection between messages,
+    /// nothing more will be received. By default the connection is closed,
+    /// which is reported as `CloseReason::PeerClosed`.
+    ///
+    /// It isn't called if the write side has been shut down already.
+    fn input_closed(self,
+                    _output: MessageWriter,
+                    _scope: &mut Scope<Self::Context>)
+                    -> Action<Self> {
+        Action::Close(self)
+    }
+
     /// All outgoing messages have been flushed.
     fn message_flushed(self,
                        output: MessageWriter,
                        scope: &mut Scope<Self::Context>)
                        -> Action<Self>;
 
+    /// The output pending to be sent has reached `OutputLimits::high_watermark`.
+    fn output_congested(&mut self, _scope: &mut Scope<Self::Context>) {}
+
+    /// The output pending to be sent has dropped to `OutputLimits::low_watermark`
+    /// after being congested.
+    fn output_drained(&mut self, _output: MessageWriter, _scope: &mut Scope<Self::Context>) {}
+
     /// Options for the Cap'n Proto message reader.
     fn reader_options(&self, _scope: &mut Scope<Self::Context>) -> ReaderOptions {
         ReaderOptions::new()
     }
 
+    /// Limits of the framing of the messages received.
+    fn framing_limits(&self, _scope: &mut Scope<Self::Context>) -> FramingLimits {
+        FramingLimits::default()
+    }
+
+    /// Pool of buffers for the received messages, which may be shared with
+    /// other connections. By default the buffers are allocated per message.
+    fn segment_pool(&self, _scope: &mut Scope<Self::Context>) -> Option<SegmentPool> {
+        None
+    }
+
+    /// Encoding of the messages sent and received. By default it's unpacked.
+    fn wire_format(&self, _scope: &mut Scope<Self::Context>) -> WireFormat {
+        WireFormat::Unpacked
+    }
+
+    /// Limits of the messages written to the connection.
+    fn output_limits(&self, _scope: &mut Scope<Self::Context>) -> OutputLimits {
+        OutputLimits::default()
+    }
+
     /// Timeout for an idle connection. By default it's 120 seconds.
     fn idle_timeout(&self, _scope: &mut Scope<Self::Context>) -> Duration {
         Duration::from_secs(120)
@@ -73,9 +172,27 @@ pub trait Endpoint: Sized {
     /// Timeout for reading a message.
     fn recv_timeout(&self, scope: &mut Scope<Self::Context>) -> Duration;
 
+    /// Deadline for reading a message, by default `recv_timeout` from now.
+    fn recv_deadline(&self, scope: &mut Scope<Self::Context>) -> Time {
+        scope.now() + self.recv_timeout(scope)
+    }
+
+    /// Maximum number of messages received in a row from the buffered input
+    /// before other connections are served. By default it's 64.
+    fn recv_budget(&self, _scope: &mut Scope<Self::Context>) -> usize {
+        64
+    }
+
     /// Timeout for sending a message.
     fn send_timeout(&self, scope: &mut Scope<Self::Context>) -> Duration;
 
+    /// Interval of checking the output flushed in background, congested or
+    /// queued, and of reading the input while the output doesn't drain.
+    /// By default it's 10 milliseconds.
+    fn output_check_interval(&self, _scope: &mut Scope<Self::Context>) -> Duration {
+        Duration::from_millis(10)
+    }
+
     /// Timeout expired during the `state`.
     fn timeout(self,
                state: ConnectionState,
@@ -84,8 +201,38 @@ pub trait Endpoint: Sized {
                -> Action<Self>;
 
     /// The state machine has been woken up.
-    fn wakeup(&self, scope: &mut Scope<Self::Context>) -> Action<Self>;
+    fn wakeup(self, output: MessageWriter, scope: &mut Scope<Self::Context>) -> Action<Self>;
+
+    /// The connection has been closed. It's called exactly once, when the
+    /// state machine terminates.
+    fn closed(self, reason: CloseReason, scope: &mut Scope<Self::Context>);
+
+    /// A connection accepted by an `Acceptor` is within its limits, it's
+    /// rejected with `Rejection::Refused` unless this returns true.
+    fn admit(_seed: &Self::Seed,
+             _sock: &mut Self::Socket,
+             _scope: &mut Scope<Self::Context>)
+             -> bool {
+        true
+    }
+
+    /// A connection accepted by an `Acceptor` has been rejected for the
+    /// `reason`, a message written to the `output` is sent before closing it.
+    /// It isn't called for the connections closed right away, see `Admission`.
+    fn rejected(_seed: &Self::Seed,
+                _reason: Rejection,
+                _output: MessageWriter,
+                _scope: &mut Scope<Self::Context>) {
+    }
+
+    /// Connecting by a `Connector` has failed, it's retried after the `delay`.
+    fn connect_failed(_seed: &Self::Seed,
+                      _err: Error,
+                      _delay: Duration,
+                      _scope: &mut Scope<Self::Context>) {
+    }
 
-    /// Connection will be closed after this.
-    fn exception(self, err: Error, scope: &mut Scope<Self::Context>);
+    /// The handshake of a connection accepted by an `Acceptor` has failed,
+    /// the connection is closed without calling `create`.
+    fn handshake_failed(_seed: &Self::Seed, _err: Error, _scope: &mut Scope<Self::Context>) {}
 }
@@ -1,77 +1,576 @@
 // See https://capnproto.org/encoding.html#serialization-over-a-stream for
 // the specification.
-use std::io::{Cursor, Write};
+use std::{cmp, mem};
+use std::collections::VecDeque;
+use std::io::{IoSlice, Write};
 
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
-use capnp::Result;
+use capnp::{serialize_packed, OutputSegments};
 use capnp::message::{Builder, Reader, ReaderSegments};
 use rotor_stream::Buf;
 
+use error;
+use pool::SegmentPool;
+
 pub use capnp::{Error, Word};
 pub use capnp::message::{Allocator as MessageAllocator, ReaderOptions};
 
+type Result<T> = ::std::result::Result<T, error::Error>;
+
 /// Cap'n Proto message reader.
-pub type MessageReader = Reader<OwnedSegments>;
+pub type MessageReader<'a> = Reader<Segments<'a>>;
+
+/// Cap'n Proto message reader owning its segments, it can be sent to other
+/// threads.
+pub type OwnedMessage = Reader<OwnedSegments>;
 
 /// Cap'n Proto message builder.
 pub type MessageBuilder<A> = Builder<A>;
 
 /// Cap'n Proto message serializer.
-pub struct MessageWriter<'a>(pub &'a mut Buf);
+pub struct MessageWriter<'a> {
+    buf: &'a mut Buf,
+    queue: &'a mut OutputQueue,
+    format: WireFormat,
+    limits: OutputLimits,
+}
+
+/// Limits of the messages written to a connection.
+#[derive(Clone, Copy, Debug)]
+pub struct OutputLimits {
+    /// Maximum size of a message in bytes, it's 64 MiB by default.
+    pub max_message_size: usize,
+    /// Maximum size of the output pending to be sent in bytes, it's 256 MiB by default.
+    pub max_pending_size: usize,
+    /// Size of the pending output at which `Endpoint::output_congested` is
+    /// called, it's 1 MiB by default.
+    pub high_watermark: usize,
+    /// Size of the pending output at which `Endpoint::output_drained` is
+    /// called after being congested, it's 256 KiB by default.
+    pub low_watermark: usize,
+}
+
+impl Default for OutputLimits {
+    fn default() -> OutputLimits {
+        OutputLimits {
+            max_message_size: 64 << 20,
+            max_pending_size: 256 << 20,
+            high_watermark: 1 << 20,
+            low_watermark: 256 << 10,
+        }
+    }
+}
+
+/// Limits of the framing of received messages, they're checked before
+/// anything is allocated for a message.
+#[derive(Clone, Copy, Debug)]
+pub struct FramingLimits {
+    /// Maximum number of segments of a message, it's 511 by default like in
+    /// the reference implementation.
+    pub max_segments: usize,
+    /// Maximum size of a segment in bytes, it's 64 MiB by default.
+    pub max_segment_size: usize,
+    /// Maximum size of a message in bytes including the segment table, it's
+    /// 64 MiB by default. `ReaderOptions::traversal_limit_in_words` applies too.
+    pub max_message_size: usize,
+    /// Maximum number of bytes of the messages received in a row from the
+    /// buffered input before other connections are served, it's 1 MiB by
+    /// default. A message is never split.
+    pub max_bytes_per_wakeup: usize,
+}
+
+impl Default for FramingLimits {
+    fn default() -> FramingLimits {
+        FramingLimits {
+            max_segments: 511,
+            max_segment_size: 64 << 20,
+            max_message_size: 64 << 20,
+            max_bytes_per_wakeup: 1 << 20,
+        }
+    }
+}
 
-pub fn read_segment_count(buf: &mut Buf) -> Result<usize> {
+/// Encoding of the messages on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WireFormat {
+    /// Standard stream framing, segments are sent as is.
+    ///
+    /// The segment table is padded to a word boundary for an even number of
+    /// segments as the specification requires. Releases up to 0.1.1 neither
+    /// wrote nor expected the padding, so they can't exchange such messages
+    /// with this one, `UnpaddedUnpacked` can be used to talk to them.
+    Unpacked,
+    /// Standard stream framing compressed with the packed encoding.
+    /// See https://capnproto.org/encoding.html#packing for details.
+    Packed,
+    /// The unpacked framing of the releases up to 0.1.1, the segment table
+    /// isn't padded. Only messages with an odd number of segments can be
+    /// exchanged with other implementations.
+    UnpaddedUnpacked,
+}
+
+impl WireFormat {
+    /// Whether the segment table is padded to a word boundary.
+    fn is_padded(self) -> bool {
+        self != WireFormat::UnpaddedUnpacked
+    }
+}
+
+pub fn read_segment_count(buf: &mut Buf, limits: FramingLimits) -> Result<usize> {
     let segment_count = <LittleEndian as ByteOrder>::read_u32(&buf[0..4]).wrapping_add(1) as usize;
     buf.consume(4);
-    if segment_count >= 512 {
-        Err(Error::failed(format!("Too many segments: {}", segment_count)))
+    check_segment_count(segment_count, limits)
+}
+
+fn check_segment_count(segment_count: usize, limits: FramingLimits) -> Result<usize> {
+    if segment_count > limits.max_segments {
+        Err(error::Error::TooManySegments {
+            count: segment_count,
+            limit: limits.max_segments,
+        })
     } else if segment_count == 0 {
-        Err(Error::failed(format!("Too few segments: {}", segment_count)))
+        Err(error::Error::ZeroSegments)
     } else {
         Ok(segment_count)
     }
 }
 
+/// Size of the segment table in bytes, including the segment count and the
+/// padding to a word boundary if the `format` has it.
+fn segment_table_size(segment_count: usize, format: WireFormat) -> usize {
+    if format.is_padded() {
+        (segment_count / 2 + 1) * 8
+    } else {
+        (segment_count + 1) * 4
+    }
+}
+
+/// Size of a message in bytes, including the segment table.
+pub fn message_size(segment_count: usize, total_words: usize, format: WireFormat) -> usize {
+    segment_table_size(segment_count, format) + total_words * 8
+}
+
+/// Size of the segment table following the segment count in bytes.
+pub fn segment_table_len(segment_count: usize, format: WireFormat) -> usize {
+    segment_table_size(segment_count, format) - 4
+}
+
 pub fn read_segment_table(buf: &mut Buf,
                           segment_count: usize,
-                          options: ReaderOptions)
+                          format: WireFormat,
+                          options: ReaderOptions,
+                          limits: FramingLimits)
                           -> Result<(usize, Vec<(usize, usize)>)> {
-    let mut segment_slices = Vec::with_capacity(segment_count);
+    let table = try!(parse_segment_table(&buf[..], segment_count, format, options, limits));
+    buf.consume(segment_table_len(segment_count, format));
+    Ok(table)
+}
+
+/// Parse the segment table following the segment count, returning the
+/// total number of words and the slices of the segments.
+fn parse_segment_table(table: &[u8],
+                       segment_count: usize,
+                       format: WireFormat,
+                       options: ReaderOptions,
+                       limits: FramingLimits)
+                       -> Result<(usize, Vec<(usize, usize)>)> {
+    let segment_len = |i: usize| {
+        <LittleEndian as ByteOrder>::read_u32(&table[i * 4..i * 4 + 4]) as usize
+    };
     let mut total_words: usize = 0;
-    let mut i = 0;
-    for _ in 0..segment_count {
-        let segment_len = <LittleEndian as ByteOrder>::read_u32(&buf[i..i + 4]) as usize;
-        segment_slices.push((total_words, total_words + segment_len));
-        total_words += segment_len;
-        i += 4;
-    }
-    buf.consume(segment_count * 4);
-    if total_words as u64 > options.traversal_limit_in_words {
-        Err(Error::failed(format!("Message has {} words, which is too \
-            large. To increase the limit on the receiving end, see \
-            capnp::message::ReaderOptions.",
-                                  total_words)))
+    for i in 0..segment_count {
+        total_words += try!(check_segment_len(segment_len(i), limits));
+    }
+    try!(check_total_words(segment_count, total_words, format, options, limits));
+    let mut segment_slices = Vec::with_capacity(segment_count);
+    let mut start = 0;
+    for i in 0..segment_count {
+        segment_slices.push((start, start + segment_len(i)));
+        start += segment_len(i);
+    }
+    Ok((total_words, segment_slices))
+}
+
+fn check_segment_len(segment_len: usize, limits: FramingLimits) -> Result<usize> {
+    if segment_len * 8 > limits.max_segment_size {
+        Err(error::Error::SegmentTooLarge {
+            size: segment_len * 8,
+            limit: limits.max_segment_size,
+        })
     } else {
-        Ok((total_words, segment_slices))
+        Ok(segment_len)
     }
 }
 
-pub fn read_segments(buf: &mut Buf,
+fn check_total_words(segment_count: usize,
                      total_words: usize,
-                     segment_slices: Vec<(usize, usize)>,
-                     options: ReaderOptions)
-                     -> Result<MessageReader> {
-    let mut owned_space: Vec<Word> = Word::allocate_zeroed_vec(total_words);
-    try!(buf.write_to(&mut Cursor::new(Word::words_to_bytes_mut(&mut owned_space[..]))));
-    let segments = OwnedSegments {
-        segment_slices: segment_slices,
-        owned_space: owned_space,
+                     format: WireFormat,
+                     options: ReaderOptions,
+                     limits: FramingLimits)
+                     -> Result<usize> {
+    let size = message_size(segment_count, total_words, format);
+    if size > limits.max_message_size {
+        Err(error::Error::MessageTooLarge {
+            size: size,
+            limit: limits.max_message_size,
+        })
+    } else if total_words as u64 > options.traversal_limit_in_words {
+        Err(error::Error::MessageTooLarge {
+            size: total_words * 8,
+            limit: (options.traversal_limit_in_words as usize).saturating_mul(8),
+        })
+    } else {
+        Ok(total_words)
+    }
+}
+
+/// Read the segments borrowing them from the buffer if it's aligned to a
+/// word boundary, or copy them otherwise.
+///
+/// The buffer isn't consumed, the `total_words` are to be consumed once the
+/// message is dropped.
+pub fn read_segments<'a>(buf: &'a [u8],
+                         total_words: usize,
+                         segment_slices: Vec<(usize, usize)>,
+                         options: ReaderOptions,
+                         pool: Option<SegmentPool>)
+                         -> MessageReader<'a> {
+    let bytes = &buf[..total_words * 8];
+    let segments = if bytes.as_ptr() as usize % mem::align_of::<Word>() == 0 {
+        Segments::Borrowed(BorrowedSegments {
+            segment_slices: segment_slices,
+            words: Word::bytes_to_words(bytes),
+        })
+    } else {
+        let mut owned_space = allocate(total_words, &pool);
+        Word::words_to_bytes_mut(&mut owned_space[..total_words]).copy_from_slice(bytes);
+        Segments::Owned(OwnedSegments {
+            segment_slices: segment_slices,
+            owned_space: owned_space,
+            pool: pool,
+        })
     };
-    Ok(Reader::new(segments, options))
+    Reader::new(segments, options)
+}
+
+/// Read a message taking up the whole of the `bytes`, like a datagram.
+///
+/// Unpacked segments are borrowed from the `bytes` if they're aligned to a
+/// word boundary.
+pub fn read_message<'a>(bytes: &'a [u8],
+                        format: WireFormat,
+                        options: ReaderOptions,
+                        limits: FramingLimits,
+                        pool: Option<SegmentPool>)
+                        -> Result<MessageReader<'a>> {
+    let invalid = || error::Error::InvalidDatagram { size: bytes.len() };
+    match format {
+        WireFormat::Unpacked | WireFormat::UnpaddedUnpacked => {
+            if bytes.len() < 4 {
+                return Err(invalid());
+            }
+            let segment_count =
+                <LittleEndian as ByteOrder>::read_u32(&bytes[0..4]).wrapping_add(1) as usize;
+            let segment_count = try!(check_segment_count(segment_count, limits));
+            let table_size = segment_table_size(segment_count, format);
+            if bytes.len() < table_size {
+                return Err(invalid());
+            }
+            let (total_words, segment_slices) =
+                try!(parse_segment_table(&bytes[4..], segment_count, format, options, limits));
+            if bytes.len() != message_size(segment_count, total_words, format) {
+                return Err(invalid());
+            }
+            Ok(read_segments(&bytes[table_size..], total_words, segment_slices, options, pool))
+        }
+        WireFormat::Packed => {
+            let mut buf = Buf::new();
+            buf.extend(bytes);
+            match try!(PackedReader::new().read(&mut buf, options, limits, pool)) {
+                Some((message, _)) if buf.is_empty() => Ok(message),
+                _ => Err(invalid()),
+            }
+        }
+    }
+}
+
+/// Serialize a message on its own, like a datagram.
+pub fn write_message<A: MessageAllocator>(message: &MessageBuilder<A>,
+                                          format: WireFormat)
+                                          -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    match format {
+        WireFormat::Unpacked | WireFormat::UnpaddedUnpacked => {
+            let segments = message.get_segments_for_output();
+            try!(write_segment_table(&mut bytes, &segments, format));
+            for &segment in &*segments {
+                bytes.extend_from_slice(Word::words_to_bytes(segment));
+            }
+        }
+        WireFormat::Packed => try!(serialize_packed::write_message(&mut bytes, message)),
+    }
+    Ok(bytes)
+}
+
+/// Resumable decoder of a packed message.
+///
+/// Input is consumed as soon as it's unpacked, so a partially received
+/// message is only kept in its unpacked form.
+#[derive(Debug)]
+pub struct PackedReader {
+    stage: Stage,
+    /// Number of bytes of the current stage already unpacked.
+    pos: usize,
+    unpacker: Unpacker,
+}
+
+#[derive(Debug)]
+enum Stage {
+    /// The first word with the segment count and the first segment size.
+    SegmentCount([u8; 8]),
+    /// The rest of the segment table, padded to a word boundary.
+    SegmentTable(usize, usize, Vec<u8>),
+    Segments(usize, Vec<(usize, usize)>, Vec<Word>),
+}
+
+impl PackedReader {
+    pub fn new() -> PackedReader {
+        PackedReader {
+            stage: Stage::SegmentCount([0; 8]),
+            pos: 0,
+            unpacker: Unpacker::default(),
+        }
+    }
+
+    /// Whether nothing of a message has been unpacked yet.
+    pub fn is_empty(&self) -> bool {
+        match self.stage {
+            Stage::SegmentCount(_) => self.pos == 0,
+            _ => false,
+        }
+    }
+
+    /// Unpack as much of the message as is available in the buffer.
+    ///
+    /// Returns the message along with its unpacked size in bytes once all of
+    /// its segments are unpacked.
+    pub fn read(&mut self,
+                buf: &mut Buf,
+                options: ReaderOptions,
+                limits: FramingLimits,
+                pool: Option<SegmentPool>)
+                -> Result<Option<(MessageReader<'static>, usize)>> {
+        loop {
+            let complete = {
+                let out = match self.stage {
+                    Stage::SegmentCount(ref mut word) => &mut word[..],
+                    Stage::SegmentTable(_, _, ref mut table) => &mut table[..],
+                    Stage::Segments(total_words, _, ref mut words) => {
+                        Word::words_to_bytes_mut(&mut words[..total_words])
+                    }
+                };
+                let consumed = self.unpacker.unpack(&buf[..], out, &mut self.pos);
+                buf.consume(consumed);
+                self.pos == out.len()
+            };
+            if !complete {
+                return Ok(None);
+            }
+            self.pos = 0;
+            self.stage = match mem::replace(&mut self.stage, Stage::SegmentCount([0; 8])) {
+                Stage::SegmentCount(word) => {
+                    let segment_count =
+                        <LittleEndian as ByteOrder>::read_u32(&word[0..4]).wrapping_add(1) as usize;
+                    let segment_count = try!(check_segment_count(segment_count, limits));
+                    let first_len = <LittleEndian as ByteOrder>::read_u32(&word[4..8]) as usize;
+                    let first_len = try!(check_segment_len(first_len, limits));
+                    let table_len = segment_table_size(segment_count, WireFormat::Packed) - 8;
+                    Stage::SegmentTable(segment_count, first_len, vec![0; table_len])
+                }
+                Stage::SegmentTable(segment_count, first_len, table) => {
+                    let segment_len = |i: usize| {
+                        <LittleEndian as ByteOrder>::read_u32(&table[i * 4..i * 4 + 4]) as usize
+                    };
+                    let mut total_words = first_len;
+                    for i in 0..segment_count - 1 {
+                        total_words += try!(check_segment_len(segment_len(i), limits));
+                    }
+                    try!(check_total_words(segment_count,
+                                           total_words,
+                                           WireFormat::Packed,
+                                           options,
+                                           limits));
+                    let mut segment_slices = Vec::with_capacity(segment_count);
+                    let mut start = first_len;
+                    segment_slices.push((0, start));
+                    for i in 0..segment_count - 1 {
+                        segment_slices.push((start, start + segment_len(i)));
+                        start += segment_len(i);
+                    }
+                    Stage::Segments(total_words, segment_slices, allocate(total_words, &pool))
+                }
+                Stage::Segments(total_words, segment_slices, owned_space) => {
+                    if !self.unpacker.is_clean() {
+                        return Err(error::Error::InvalidPacking);
+                    }
+                    let size = message_size(segment_slices.len(), total_words, WireFormat::Packed);
+                    let segments = OwnedSegments {
+                        segment_slices: segment_slices,
+                        owned_space: owned_space,
+                        pool: pool,
+                    };
+                    return Ok(Some((Reader::new(Segments::Owned(segments), options), size)));
+                }
+            }
+        }
+    }
+}
+
+/// State of the packed encoding carried between chunks of input.
+#[derive(Debug, Default)]
+struct Unpacker {
+    zero_words: usize,
+    raw_bytes: usize,
+}
+
+impl Unpacker {
+    /// Unpack the `input` into `out` starting at `pos`, until either of them
+    /// is exhausted. Returns the number of input bytes consumed.
+    ///
+    /// A tag is only consumed along with all the bytes it describes, and a
+    /// run may continue into the next `out`.
+    fn unpack(&mut self, input: &[u8], out: &mut [u8], pos: &mut usize) -> usize {
+        let mut consumed = 0;
+        while *pos < out.len() {
+            let input = &input[consumed..];
+            if self.zero_words > 0 {
+                let len = cmp::min(self.zero_words * 8, out.len() - *pos);
+                for byte in &mut out[*pos..*pos + len] {
+                    *byte = 0;
+                }
+                *pos += len;
+                self.zero_words -= len / 8;
+                continue;
+            }
+            if self.raw_bytes > 0 {
+                let len = cmp::min(cmp::min(self.raw_bytes, out.len() - *pos), input.len());
+                if len == 0 {
+                    break;
+                }
+                out[*pos..*pos + len].copy_from_slice(&input[..len]);
+                *pos += len;
+                consumed += len;
+                self.raw_bytes -= len;
+                continue;
+            }
+            let tag = match input.first() {
+                Some(&tag) => tag,
+                None => break,
+            };
+            // Tag 0x00 and 0xff are followed by the length of a run.
+            let run = if tag == 0 || tag == 0xff { 1 } else { 0 };
+            let len = 1 + tag.count_ones() as usize + run;
+            if input.len() < len {
+                break;
+            }
+            let mut i = 1;
+            for (bit, byte) in out[*pos..*pos + 8].iter_mut().enumerate() {
+                if tag & (1 << bit) != 0 {
+                    *byte = input[i];
+                    i += 1;
+                } else {
+                    *byte = 0;
+                }
+            }
+            if tag == 0 {
+                self.zero_words = input[i] as usize;
+            } else if tag == 0xff {
+                self.raw_bytes = input[i] as usize * 8;
+            }
+            *pos += 8;
+            consumed += len;
+        }
+        consumed
+    }
+
+    /// Whether there's no unfinished run.
+    fn is_clean(&self) -> bool {
+        self.zero_words == 0 && self.raw_bytes == 0
+    }
+}
+
+/// Segments of a received message.
+pub enum Segments<'a> {
+    Borrowed(BorrowedSegments<'a>),
+    Owned(OwnedSegments),
+}
+
+impl<'a> ReaderSegments for Segments<'a> {
+    fn get_segment<'b>(&'b self, id: u32) -> Option<&'b [Word]> {
+        match *self {
+            Segments::Borrowed(ref segments) => segments.get_segment(id),
+            Segments::Owned(ref segments) => segments.get_segment(id),
+        }
+    }
+}
+
+impl<'a> Segments<'a> {
+    /// Take the ownership of the segments, copying them if they're borrowed.
+    pub fn into_owned(self) -> OwnedSegments {
+        match self {
+            Segments::Borrowed(segments) => {
+                let mut owned_space = Word::allocate_zeroed_vec(segments.words.len());
+                Word::words_to_bytes_mut(&mut owned_space[..])
+                    .copy_from_slice(Word::words_to_bytes(segments.words));
+                OwnedSegments {
+                    segment_slices: segments.segment_slices,
+                    owned_space: owned_space,
+                    pool: None,
+                }
+            }
+            Segments::Owned(segments) => segments,
+        }
+    }
+}
+
+/// Take the ownership of a received message, so that it can be kept after
+/// `Endpoint::take_message` returns.
+///
+/// The segments are only copied if they're borrowed from the input buffer.
+pub fn into_owned(message: MessageReader, options: ReaderOptions) -> OwnedMessage {
+    Reader::new(message.into_segments().into_owned(), options)
+}
+
+/// Segments borrowed from the input buffer of the connection.
+pub struct BorrowedSegments<'a> {
+    segment_slices: Vec<(usize, usize)>,
+    words: &'a [Word],
+}
+
+impl<'a> ReaderSegments for BorrowedSegments<'a> {
+    fn get_segment<'b>(&'b self, id: u32) -> Option<&'b [Word]> {
+        if id < self.segment_slices.len() as u32 {
+            let (a, b) = self.segment_slices[id as usize];
+            Some(&self.words[a..b])
+        } else {
+            None
+        }
+    }
+}
+
+/// Allocate the space for the segments, which may be larger than `total_words`.
+fn allocate(total_words: usize, pool: &Option<SegmentPool>) -> Vec<Word> {
+    match *pool {
+        Some(ref pool) => pool.take(total_words),
+        None => Word::allocate_zeroed_vec(total_words),
+    }
 }
 
 pub struct OwnedSegments {
     segment_slices: Vec<(usize, usize)>,
     owned_space: Vec<Word>,
+    /// Pool the space is returned to.
+    pool: Option<SegmentPool>,
 }
 
 impl ReaderSegments for OwnedSegments {
@@ -85,17 +584,751 @@ impl ReaderSegments for OwnedSegments {
     }
 }
 
+impl Drop for OwnedSegments {
+    fn drop(&mut self) {
+        if let Some(ref pool) = self.pool {
+            pool.give(mem::replace(&mut self.owned_space, Vec::new()));
+        }
+    }
+}
+
 impl<'a> MessageWriter<'a> {
+    /// Create a serializer writing messages in `format` to the buffer, after
+    /// the messages in the `queue`.
+    pub fn new(buf: &'a mut Buf,
+               queue: &'a mut OutputQueue,
+               format: WireFormat,
+               limits: OutputLimits)
+               -> MessageWriter<'a> {
+        MessageWriter {
+            buf: buf,
+            queue: queue,
+            format: format,
+            limits: limits,
+        }
+    }
+
+    /// Number of bytes pending to be sent.
+    pub fn pending(&self) -> usize {
+        self.buf.len() + self.queue.len
+    }
+
     /// Serialize and write the message to the connection buffer.
-    pub fn write<A: MessageAllocator>(&mut self, message: &MessageBuilder<A>) {
+    ///
+    /// Nothing is written if the message exceeds the `OutputLimits`, packed
+    /// messages are measured before packing, or after `Action::CloseOutput`.
+    pub fn write<A: MessageAllocator>(&mut self,
+                                      message: &MessageBuilder<A>)
+                                      -> Result<()> {
+        try!(self.check_open());
         let segments = message.get_segments_for_output();
-        self.0.write_u32::<LittleEndian>(segments.len() as u32 - 1).unwrap();
-        let segments: &[&[Word]] = &*segments;
-        for segment in segments {
-            self.0.write_u32::<LittleEndian>(segment.len() as u32).unwrap();
+        try!(self.check_limits(&segments));
+        match self.format {
+            format @ WireFormat::Unpacked | format @ WireFormat::UnpaddedUnpacked => {
+                try!(write_segment_table(&mut self.sink(), &segments, format));
+                for &segment in &*segments {
+                    try!(self.sink().write_all(Word::words_to_bytes(segment)));
+                }
+            }
+            WireFormat::Packed => try!(serialize_packed::write_message(&mut self.sink(), message)),
+        }
+        Ok(())
+    }
+
+    /// Write the message taking the ownership of it.
+    ///
+    /// The segments are written to the socket directly whenever nothing is
+    /// buffered before them, all at once with a vectored write, and copied
+    /// to the connection buffer in chunks as it drains otherwise. The
+    /// message is dropped once it's written, and `message_flushed` is called
+    /// once it's sent like for `write`. Packed messages are packed at once.
+    pub fn write_owned<A>(&mut self,
+                          message: MessageBuilder<A>)
+                          -> Result<()>
+        where A: MessageAllocator + 'static
+    {
+        if self.format == WireFormat::Packed {
+            return self.write(&message);
+        }
+        try!(self.check_open());
+        let payload = {
+            let segments = message.get_segments_for_output();
+            try!(self.check_limits(&segments));
+            let format = self.format;
+            try!(write_segment_table(&mut self.sink(), &segments, format));
+            segments.iter().fold(0, |size, segment| size + segment.len() * 8)
+        };
+        self.queue.len += payload;
+        self.queue.messages.push_back(Queued::Segments(Box::new(message), 0, 0));
+        Ok(())
+    }
+
+    fn check_open(&self) -> Result<()> {
+        if self.queue.closed {
+            return Err(error::Error::OutputClosed);
+        }
+        Ok(())
+    }
+
+    fn check_limits(&self, segments: &[&[Word]]) -> Result<()> {
+        let table_size = segment_table_size(segments.len(), self.format);
+        let size = segments.iter().fold(table_size, |size, segment| size + segment.len() * 8);
+        if size > self.limits.max_message_size || segments.len() > u32::max_value() as usize {
+            return Err(error::Error::OutgoingMessageTooLarge {
+                size: size,
+                limit: self.limits.max_message_size,
+            });
+        }
+        if self.pending() + size > self.limits.max_pending_size {
+            return Err(error::Error::OutputBufferFull {
+                pending: self.pending(),
+                limit: self.limits.max_pending_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Where the output goes, it's queued after the messages already queued.
+    fn sink(&mut self) -> Sink {
+        if self.queue.messages.is_empty() {
+            return Sink::Buf(self.buf);
+        }
+        match self.queue.messages.back() {
+            Some(&Queued::Bytes(..)) => {}
+            _ => self.queue.messages.push_back(Queued::Bytes(Vec::new(), 0)),
+        }
+        match self.queue.messages.back_mut() {
+            Some(&mut Queued::Bytes(ref mut bytes, _)) => Sink::Queue(bytes, &mut self.queue.len),
+            _ => unreachable!(),
         }
-        for &segment in segments {
-            self.0.write(Word::words_to_bytes(segment)).unwrap();
+    }
+}
+
+enum Sink<'a> {
+    Buf(&'a mut Buf),
+    /// Bytes queued and the length of the queue.
+    Queue(&'a mut Vec<u8>, &'a mut usize),
+}
+
+impl<'a> Write for Sink<'a> {
+    fn write(&mut self, data: &[u8]) -> ::std::io::Result<usize> {
+        match *self {
+            Sink::Buf(ref mut buf) => buf.write(data),
+            Sink::Queue(ref mut bytes, ref mut len) => {
+                bytes.extend_from_slice(data);
+                **len += data.len();
+                Ok(data.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> ::std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn write_segment_table<W: Write>(sink: &mut W,
+                                 segments: &[&[Word]],
+                                 format: WireFormat)
+                                 -> ::std::io::Result<()> {
+    try!(sink.write_u32::<LittleEndian>(segments.len() as u32 - 1));
+    for segment in segments {
+        try!(sink.write_u32::<LittleEndian>(segment.len() as u32));
+    }
+    if format.is_padded() && segments.len() % 2 == 0 {
+        // Padding to a word boundary, which `capnp::serialize` writes and
+        // expects too.
+        try!(sink.write_u32::<LittleEndian>(0));
+    }
+    Ok(())
+}
+
+/// Size of the chunks the queued messages are copied to the connection
+/// buffer in.
+pub const OUTPUT_CHUNK_SIZE: usize = 64 << 10;
+
+/// Messages waiting to be copied to the connection buffer.
+#[derive(Default)]
+pub struct OutputQueue {
+    messages: VecDeque<Queued>,
+    /// Number of bytes in the queue.
+    len: usize,
+    /// Nothing may be written anymore, see `Action::CloseOutput`.
+    closed: bool,
+}
+
+enum Queued {
+    /// Serialized output and the number of bytes of it already copied.
+    Bytes(Vec<u8>, usize),
+    /// Message written by `write_owned`, the index of the segment being
+    /// copied and the number of bytes of it already copied.
+    Segments(Box<OutputMessage>, usize, usize),
+}
+
+/// Message builder with any allocator.
+trait OutputMessage {
+    fn segments(&self) -> OutputSegments;
+}
+
+impl<A: MessageAllocator> OutputMessage for MessageBuilder<A> {
+    fn segments(&self) -> OutputSegments {
+        self.get_segments_for_output()
+    }
+}
+
+impl OutputQueue {
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Number of bytes in the queue.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Reject writing of messages from now on, as the write side of the
+    /// connection is shut down.
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    /// Copy the queued messages to the buffer while it's shorter than a chunk.
+    pub fn fill(&mut self, buf: &mut Buf) {
+        while buf.len() < OUTPUT_CHUNK_SIZE {
+            let room = OUTPUT_CHUNK_SIZE - buf.len();
+            let copied = self.with_front(|slices| {
+                let mut copied = 0;
+                for slice in slices {
+                    let len = cmp::min(slice.len(), room - copied);
+                    buf.extend(&slice[..len]);
+                    copied += len;
+                    if copied == room {
+                        break;
+                    }
+                }
+                copied
+            });
+            match copied {
+                Some(len) => self.consume(len),
+                None => return,
+            }
+        }
+    }
+
+    /// Write the queued messages to the `sock` directly, the segments of a
+    /// message at once, until it would block.
+    ///
+    /// It's only called while the connection buffer is empty. Errors are
+    /// left for writing of the connection buffer to report.
+    pub fn write_to<W: Write>(&mut self, sock: &mut W) {
+        loop {
+            let written = self.with_front(|slices| {
+                let slices: Vec<_> = slices.iter().map(|slice| IoSlice::new(slice)).collect();
+                sock.write_vectored(&slices)
+            });
+            match written {
+                Some(Ok(0)) | Some(Err(_)) | None => return,
+                Some(Ok(len)) => self.consume(len),
+            }
+        }
+    }
+
+    /// Call `f` with the bytes of the first message not written yet.
+    fn with_front<F, R>(&self, f: F) -> Option<R>
+        where F: FnOnce(&[&[u8]]) -> R
+    {
+        match self.messages.front() {
+            Some(&Queued::Bytes(ref bytes, pos)) => Some(f(&[&bytes[pos..]])),
+            Some(&Queued::Segments(ref message, index, pos)) => {
+                let segments = message.segments();
+                let mut slices: Vec<&[u8]> =
+                    segments[index..].iter().map(|segment| Word::words_to_bytes(segment)).collect();
+                if let Some(first) = slices.first_mut() {
+                    *first = &first[pos..];
+                }
+                Some(f(&slices))
+            }
+            None => None,
+        }
+    }
+
+    /// Drop the `len` bytes written from the front of the queue, and the
+    /// messages written entirely.
+    fn consume(&mut self, mut len: usize) {
+        self.len -= len;
+        loop {
+            let done = match self.messages.front_mut() {
+                Some(&mut Queued::Bytes(ref bytes, ref mut pos)) => {
+                    let advance = cmp::min(len, bytes.len() - *pos);
+                    *pos += advance;
+                    len -= advance;
+                    *pos == bytes.len()
+                }
+                Some(&mut Queued::Segments(ref message, ref mut index, ref mut pos)) => {
+                    let segments = message.segments();
+                    while *index < segments.len() {
+                        let size = segments[*index].len() * 8;
+                        let advance = cmp::min(len, size - *pos);
+                        *pos += advance;
+                        len -= advance;
+                        if *pos < size {
+                            break;
+                        }
+                        *index += 1;
+                        *pos = 0;
+                    }
+                    *index == segments.len()
+                }
+                None => return,
+            };
+            if !done {
+                return;
+            }
+            self.messages.pop_front();
+            if len == 0 {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::io::{self, Write};
+
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use capnp::{serialize, serialize_packed, text};
+    use capnp::message::{AllocationStrategy, Builder, HeapAllocator, ReaderOptions,
+                         ReaderSegments};
+    use rotor_stream::Buf;
+
+    use error::Error;
+    use super::{read_message, read_segment_count, read_segment_table, read_segments,
+                write_message, FramingLimits, MessageReader, MessageWriter, OutputLimits,
+                OutputQueue, PackedReader, Segments, WireFormat, Word};
+
+    fn text_message(allocator: HeapAllocator, content: &str) -> Builder<HeapAllocator> {
+        let mut builder = Builder::new(allocator);
+        builder.set_root::<text::Builder, text::Reader>(content).unwrap();
+        builder
+    }
+
+    fn packed(message: &Builder<HeapAllocator>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        serialize_packed::write_message(&mut bytes, message).unwrap();
+        bytes
+    }
+
+    fn unpacked_size(message: &Builder<HeapAllocator>) -> usize {
+        let mut bytes = Vec::new();
+        serialize::write_message(&mut bytes, message).unwrap();
+        bytes.len()
+    }
+
+    /// Feed the packed bytes one at a time, the message must only be
+    /// complete after the last of them.
+    fn read_bytewise(packed: &[u8]) -> Result<(MessageReader<'static>, usize), Error> {
+        let mut reader = PackedReader::new();
+        let mut buf = Buf::new();
+        for (i, &byte) in packed.iter().enumerate() {
+            buf.extend(&[byte]);
+            if let Some(message) = try!(reader.read(&mut buf,
+                                                    ReaderOptions::new(),
+                                                    FramingLimits::default(),
+                                                    None)) {
+                assert_eq!(i, packed.len() - 1);
+                assert!(buf.is_empty());
+                assert!(reader.is_empty());
+                return Ok(message);
+            }
+        }
+        panic!("incomplete message")
+    }
+
+    fn segments(message: MessageReader) -> Vec<Vec<u8>> {
+        let segments = message.into_segments();
+        (0..)
+            .map(|id| segments.get_segment(id))
+            .take_while(Option::is_some)
+            .map(|segment| Word::words_to_bytes(segment.unwrap()).to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn single_segment_byte_by_byte() {
+        let message = text_message(HeapAllocator::new(), "hello, packed world");
+        let (reader, size) = read_bytewise(&packed(&message)).unwrap();
+        assert_eq!(size, unpacked_size(&message));
+        assert_eq!(reader.get_root::<text::Reader>().unwrap(), "hello, packed world");
+    }
+
+    #[test]
+    fn multiple_segments_byte_by_byte() {
+        let content: String = (0..200).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+        let allocator = HeapAllocator::new()
+            .first_segment_words(1)
+            .allocation_strategy(AllocationStrategy::FixedSize);
+        let message = text_message(allocator, &content);
+        let expected: Vec<Vec<u8>> = message.get_segments_for_output()
+            .iter()
+            .map(|segment| Word::words_to_bytes(segment).to_vec())
+            .collect();
+        assert!(expected.len() > 1);
+
+        let (reader, size) = read_bytewise(&packed(&message)).unwrap();
+        assert_eq!(size, unpacked_size(&message));
+        assert_eq!(reader.get_root::<text::Reader>().unwrap(), &content[..]);
+        assert_eq!(segments(reader), expected);
+    }
+
+    #[test]
+    fn zero_run_across_stages() {
+        // Two segments of 4 and 0 words. The run of zeros starts at the
+        // rest of the segment table and ends in the first segment.
+        let packed = [0x11, 1, 4, 0x00, 3, 0x01, 42];
+        let (reader, size) = read_bytewise(&packed).unwrap();
+        assert_eq!(size, 16 + 4 * 8);
+        let mut first = vec![0; 32];
+        first[24] = 42;
+        assert_eq!(segments(reader), vec![first, vec![]]);
+    }
+
+    #[test]
+    fn raw_run_across_stages() {
+        // Two segments of 2 and 0 words. The raw run starts at the segment
+        // count and goes through the rest of the segment table into the
+        // first segment.
+        let mut packed = vec![0xff, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        packed.extend(&[0; 8]);
+        packed.extend(1..17);
+        let (reader, size) = read_bytewise(&packed).unwrap();
+        assert_eq!(size, 16 + 2 * 8);
+        assert_eq!(segments(reader), vec![(1..17).collect(), vec![]]);
+    }
+
+    #[test]
+    fn consecutive_messages() {
+        let first = packed(&text_message(HeapAllocator::new(), "first"));
+        let second = packed(&text_message(HeapAllocator::new(), "second"));
+        let mut buf = Buf::new();
+        buf.extend(&first);
+        buf.extend(&second[..second.len() - 1]);
+
+        let mut reader = PackedReader::new();
+        let read = |reader: &mut PackedReader, buf: &mut Buf| {
+            reader.read(buf, ReaderOptions::new(), FramingLimits::default(), None).unwrap()
+        };
+        let (message, _) = read(&mut reader, &mut buf).unwrap();
+        assert_eq!(message.get_root::<text::Reader>().unwrap(), "first");
+        assert!(read(&mut reader, &mut buf).is_none());
+        buf.extend(&second[second.len() - 1..]);
+        let (message, _) = read(&mut reader, &mut buf).unwrap();
+        assert_eq!(message.get_root::<text::Reader>().unwrap(), "second");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn zero_run_past_message() {
+        // A segment of 1 word followed by a run of a zero word more.
+        match read_bytewise(&[0x10, 1, 0x00, 1]) {
+            Err(Error::InvalidPacking) => {}
+            result => panic!("unexpected result: {:?}", result.map(|(_, size)| size)),
+        }
+    }
+
+    #[test]
+    fn raw_run_past_message() {
+        // A segment of 1 word followed by a run of a raw word more.
+        let mut packed = vec![0x10, 1, 0xff];
+        packed.extend(1..9);
+        packed.push(1);
+        match read_bytewise(&packed) {
+            Err(Error::InvalidPacking) => {}
+            result => panic!("unexpected result: {:?}", result.map(|(_, size)| size)),
+        }
+    }
+
+    fn limits(max_segments: usize,
+              max_segment_size: usize,
+              max_message_size: usize)
+              -> FramingLimits {
+        FramingLimits {
+            max_segments: max_segments,
+            max_segment_size: max_segment_size,
+            max_message_size: max_message_size,
+            ..FramingLimits::default()
+        }
+    }
+
+    /// Check the segment table of a message with segments of `lens` words.
+    fn check_table(lens: &[u32],
+                   options: ReaderOptions,
+                   limits: FramingLimits)
+                   -> Result<(usize, Vec<(usize, usize)>), Error> {
+        let mut table = Vec::new();
+        table.write_u32::<LittleEndian>((lens.len() as u32).wrapping_sub(1)).unwrap();
+        for &len in lens {
+            table.write_u32::<LittleEndian>(len).unwrap();
         }
+        if lens.len() % 2 == 0 {
+            table.write_u32::<LittleEndian>(0).unwrap();
+        }
+        let mut buf = Buf::new();
+        buf.extend(&table);
+        let count = try!(read_segment_count(&mut buf, limits));
+        let format = WireFormat::Unpacked;
+        let table = try!(read_segment_table(&mut buf, count, format, options, limits));
+        assert!(buf.is_empty());
+        Ok(table)
+    }
+
+    #[test]
+    fn segment_count_limit() {
+        let lens = vec![1; 511];
+        assert!(check_table(&lens, ReaderOptions::new(), FramingLimits::default()).is_ok());
+        let lens = vec![1; 512];
+        match check_table(&lens, ReaderOptions::new(), FramingLimits::default()) {
+            Err(Error::TooManySegments { count: 512, limit: 511 }) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert!(check_table(&[1, 1], ReaderOptions::new(), limits(2, 8, 32)).is_ok());
+        match check_table(&[1, 1, 1], ReaderOptions::new(), limits(2, 8, 40)) {
+            Err(Error::TooManySegments { count: 3, limit: 2 }) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn zero_segments() {
+        match check_table(&[], ReaderOptions::new(), FramingLimits::default()) {
+            Err(Error::ZeroSegments) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn segment_size_limit() {
+        assert_eq!(check_table(&[1, 2], ReaderOptions::new(), limits(2, 16, 64)).unwrap(),
+                   (3, vec![(0, 1), (1, 3)]));
+        match check_table(&[1, 3], ReaderOptions::new(), limits(2, 16, 64)) {
+            Err(Error::SegmentTooLarge { size: 24, limit: 16 }) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn message_size_limit() {
+        // The segment table of 2 segments takes 16 bytes with the padding.
+        assert!(check_table(&[1, 2], ReaderOptions::new(), limits(2, 16, 40)).is_ok());
+        match check_table(&[1, 2], ReaderOptions::new(), limits(2, 16, 39)) {
+            Err(Error::MessageTooLarge { size: 40, limit: 39 }) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+        // The segment table of 3 segments takes 16 bytes too.
+        assert!(check_table(&[1, 1, 1], ReaderOptions::new(), limits(3, 8, 40)).is_ok());
+        match check_table(&[1, 1, 2], ReaderOptions::new(), limits(3, 16, 40)) {
+            Err(Error::MessageTooLarge { size: 48, limit: 40 }) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn traversal_limit() {
+        let mut options = ReaderOptions::new();
+        options.traversal_limit_in_words(3);
+        assert!(check_table(&[1, 2], options, FramingLimits::default()).is_ok());
+        match check_table(&[2, 2], options, FramingLimits::default()) {
+            Err(Error::MessageTooLarge { size: 32, limit: 24 }) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn packed_limits() {
+        // The segment count word of 3 segments, the first of 2 words.
+        let packed = [0x11, 2, 2];
+        let mut buf = Buf::new();
+        buf.extend(&packed);
+        match PackedReader::new().read(&mut buf, ReaderOptions::new(), limits(2, 16, 64), None) {
+            Err(Error::TooManySegments { count: 3, limit: 2 }) => {}
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+        let mut buf = Buf::new();
+        buf.extend(&packed);
+        match PackedReader::new().read(&mut buf, ReaderOptions::new(), limits(3, 8, 64), None) {
+            Err(Error::SegmentTooLarge { size: 16, limit: 8 }) => {}
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn padded_segment_table() {
+        let allocator = HeapAllocator::new()
+            .first_segment_words(1)
+            .allocation_strategy(AllocationStrategy::FixedSize);
+        let message = text_message(allocator, "two segments");
+        assert_eq!(message.get_segments_for_output().len(), 2);
+
+        let bytes = write_message(&message, WireFormat::Unpacked).unwrap();
+        assert_eq!(&bytes[12..16], &[0; 4]);
+        let mut reference = Vec::new();
+        serialize::write_message(&mut reference, &message).unwrap();
+        assert_eq!(bytes, reference);
+
+        let reader = read_message(&bytes,
+                                  WireFormat::Unpacked,
+                                  ReaderOptions::new(),
+                                  FramingLimits::default(),
+                                  None)
+            .unwrap();
+        assert_eq!(reader.get_root::<text::Reader>().unwrap(), "two segments");
+
+        let mut buf = Buf::new();
+        buf.extend(&bytes);
+        let count = read_segment_count(&mut buf, FramingLimits::default()).unwrap();
+        let (total_words, _) =
+            read_segment_table(&mut buf,
+                               count,
+                               WireFormat::Unpacked,
+                               ReaderOptions::new(),
+                               FramingLimits::default())
+                .unwrap();
+        assert_eq!(buf.len(), total_words * 8);
+    }
+
+    #[test]
+    fn unpadded_segment_table() {
+        let allocator = HeapAllocator::new()
+            .first_segment_words(1)
+            .allocation_strategy(AllocationStrategy::FixedSize);
+        let message = text_message(allocator, "two segments");
+        let padded = write_message(&message, WireFormat::Unpacked).unwrap();
+        let bytes = write_message(&message, WireFormat::UnpaddedUnpacked).unwrap();
+        assert_eq!(&bytes[..12], &padded[..12]);
+        assert_eq!(&bytes[12..], &padded[16..]);
+
+        let reader = read_message(&bytes,
+                                  WireFormat::UnpaddedUnpacked,
+                                  ReaderOptions::new(),
+                                  FramingLimits::default(),
+                                  None)
+            .unwrap();
+        assert_eq!(reader.get_root::<text::Reader>().unwrap(), "two segments");
+        match read_message(&bytes,
+                           WireFormat::Unpacked,
+                           ReaderOptions::new(),
+                           FramingLimits::default(),
+                           None) {
+            Err(Error::InvalidDatagram { .. }) => {}
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+
+        let mut buf = Buf::new();
+        buf.extend(&bytes);
+        let count = read_segment_count(&mut buf, FramingLimits::default()).unwrap();
+        let (total_words, _) =
+            read_segment_table(&mut buf,
+                               count,
+                               WireFormat::UnpaddedUnpacked,
+                               ReaderOptions::new(),
+                               FramingLimits::default())
+                .unwrap();
+        assert_eq!(buf.len(), total_words * 8);
+    }
+
+    #[test]
+    fn segments_borrowed_if_aligned() {
+        let content: String = (0..200).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+        let allocator = HeapAllocator::new()
+            .first_segment_words(1)
+            .allocation_strategy(AllocationStrategy::FixedSize);
+        let message = text_message(allocator, &content);
+        let mut buf = Buf::new();
+        buf.extend(&write_message(&message, WireFormat::Unpacked).unwrap());
+        let count = read_segment_count(&mut buf, FramingLimits::default()).unwrap();
+        let (total_words, segment_slices) =
+            read_segment_table(&mut buf,
+                               count,
+                               WireFormat::Unpacked,
+                               ReaderOptions::new(),
+                               FramingLimits::default())
+                .unwrap();
+
+        // A word more leaves room for the segments at an unaligned offset.
+        let mut space = Word::allocate_zeroed_vec(total_words + 1);
+        for &offset in &[0, 1] {
+            Word::words_to_bytes_mut(&mut space)[offset..offset + total_words * 8]
+                .copy_from_slice(&buf[..]);
+            let bytes = &Word::words_to_bytes(&space)[offset..];
+            let reader = read_segments(bytes,
+                                       total_words,
+                                       segment_slices.clone(),
+                                       ReaderOptions::new(),
+                                       None);
+            assert_eq!(reader.get_root::<text::Reader>().unwrap(), &content[..]);
+            match (offset, reader.into_segments()) {
+                (0, Segments::Borrowed(_)) | (1, Segments::Owned(_)) => {}
+                _ => panic!("unexpected segments at offset {}", offset),
+            }
+        }
+    }
+
+    /// Socket accepting a few bytes at a time, until there's no room.
+    struct Trickle {
+        bytes: Vec<u8>,
+        room: usize,
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            let len = cmp::min(cmp::min(data.len(), 7), self.room);
+            if len == 0 {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "no room"));
+            }
+            self.bytes.extend_from_slice(&data[..len]);
+            self.room -= len;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn owned_segments_written_directly() {
+        let content: String = (0..200).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+        let allocator = HeapAllocator::new()
+            .first_segment_words(1)
+            .allocation_strategy(AllocationStrategy::FixedSize);
+        let message = text_message(allocator, &content);
+        assert!(message.get_segments_for_output().len() > 1);
+        let mut reference = Vec::new();
+        serialize::write_message(&mut reference, &message).unwrap();
+
+        let mut buf = Buf::new();
+        let mut queue = OutputQueue::default();
+        MessageWriter::new(&mut buf, &mut queue, WireFormat::Unpacked, OutputLimits::default())
+            .write_owned(message)
+            .unwrap();
+        // The segment table is buffered, and the segments are queued.
+        let mut bytes = buf[..].to_vec();
+        buf.consume(bytes.len());
+        assert_eq!(queue.len(), reference.len() - bytes.len());
+
+        let mut sock = Trickle {
+            bytes: Vec::new(),
+            room: 100,
+        };
+        queue.write_to(&mut sock);
+        assert_eq!(sock.bytes.len(), 100);
+        assert_eq!(queue.len(), reference.len() - bytes.len() - 100);
+        bytes.extend(sock.bytes);
+        queue.fill(&mut buf);
+        assert!(queue.is_empty());
+        assert_eq!(queue.len(), 0);
+        bytes.extend(&buf[..]);
+        assert_eq!(bytes, reference);
     }
 }
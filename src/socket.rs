@@ -0,0 +1,136 @@
+use std::io;
+use std::net::{IpAddr, Shutdown};
+
+use rotor::mio::tcp::TcpStream;
+#[cfg(unix)]
+use rotor::mio::unix::UnixStream;
+
+/// A socket whose write side can be shut down while it's still read from.
+pub trait HalfClose {
+    /// Shut down the write side of the socket, the peer receives EOF after
+    /// the data already written.
+    fn close_write(&mut self) -> io::Result<()>;
+}
+
+impl HalfClose for TcpStream {
+    fn close_write(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+#[cfg(unix)]
+impl HalfClose for UnixStream {
+    fn close_write(&mut self) -> io::Result<()> {
+        use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+        use std::os::unix::net;
+
+        // mio doesn't expose shutdown of Unix sockets, borrow the descriptor
+        // without taking the ownership of it.
+        let sock = unsafe { net::UnixStream::from_raw_fd(self.as_raw_fd()) };
+        let result = sock.shutdown(Shutdown::Write);
+        let _ = sock.into_raw_fd();
+        result
+    }
+}
+
+/// A socket which completes a handshake with the peer before messages are
+/// exchanged, like `TlsStream`.
+pub trait Handshake {
+    /// Continue the handshake without blocking, returns true once it's
+    /// complete.
+    fn handshake(&mut self) -> io::Result<bool>;
+}
+
+impl Handshake for TcpStream {
+    fn handshake(&mut self) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+#[cfg(unix)]
+impl Handshake for UnixStream {
+    fn handshake(&mut self) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+/// A socket whose peer may have an IP address.
+pub trait PeerAddr {
+    /// IP address of the peer, if it's known.
+    fn peer_ip(&self) -> Option<IpAddr>;
+}
+
+impl PeerAddr for TcpStream {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|addr| addr.ip())
+    }
+}
+
+#[cfg(unix)]
+impl PeerAddr for UnixStream {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+/// Credentials of the process on the other end of a Unix socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerCredentials {
+    /// Process ID of the peer, it's only known on Linux.
+    pub pid: Option<u32>,
+    /// Effective user ID of the peer.
+    pub uid: u32,
+    /// Effective group ID of the peer.
+    pub gid: u32,
+}
+
+/// A socket whose peer credentials are known, see `PeerCredentials`.
+pub trait PeerCred {
+    /// Credentials of the peer as of connecting.
+    fn peer_cred(&self) -> io::Result<PeerCredentials>;
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl PeerCred for UnixStream {
+    fn peer_cred(&self) -> io::Result<PeerCredentials> {
+        use std::mem;
+        use std::os::unix::io::AsRawFd;
+
+        let mut cred: libc::ucred = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(self.as_raw_fd(),
+                             libc::SOL_SOCKET,
+                             libc::SO_PEERCRED,
+                             &mut cred as *mut libc::ucred as *mut libc::c_void,
+                             &mut len)
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PeerCredentials {
+            pid: Some(cred.pid as u32),
+            uid: cred.uid,
+            gid: cred.gid,
+        })
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+          target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd"))]
+impl PeerCred for UnixStream {
+    fn peer_cred(&self) -> io::Result<PeerCredentials> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut uid = 0;
+        let mut gid = 0;
+        if unsafe { libc::getpeereid(self.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PeerCredentials {
+            pid: None,
+            uid: uid,
+            gid: gid,
+        })
+    }
+}
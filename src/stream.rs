@@ -1,17 +1,24 @@
+use std::cmp;
+use std::io::{ErrorKind, Read};
 use std::time::Duration;
 
-use rotor::{Scope, Time};
-use rotor_stream::{Exception, Intent, IntentBuilder, Protocol, Transport};
+use rotor::{EventSet, Machine, Response, Scope, Time};
+use rotor::void::Void;
+use rotor_stream::{Buf, Exception, Expectation, Intent, Protocol, Stream, StreamSocket,
+                   Transport};
 
 use error::Error;
-use protocol::{Action, ConnectionState, Endpoint};
-use serialization::{self, MessageWriter};
+use protocol::{Action, CloseReason, ConnectionState, Endpoint};
+use socket::HalfClose;
+use serialization::{self, MessageReader, MessageWriter, OutputQueue, PackedReader, WireFormat,
+                    OUTPUT_CHUNK_SIZE};
 
 #[derive(Debug)]
 enum Reading {
     SegmentCount,
-    SegmentTable(usize),
+    SegmentTable(usize, WireFormat),
     Segments(usize, Vec<(usize, usize)>),
+    Packed(PackedReader),
 }
 
 #[derive(Debug)]
@@ -20,49 +27,498 @@ enum CapnpState {
     Reading(Reading),
     Writing,
     Sleeping,
+    /// Receiving is suspended for other connections to be served.
+    Yielded,
+    /// The output is flushed before closing, with the linger timeout.
+    Closing(Option<Duration>),
+    /// The input is discarded until the peer closes its side.
+    Lingering,
+}
+
+/// Size of the input read while the progress of writing is watched, the
+/// rest is read by rotor-stream once it's waiting for input again.
+const INPUT_CHUNK_SIZE: usize = 4096;
+
+/// Timeout of resuming receiving after yielding, in case the notification
+/// of the state machine failed.
+const YIELD_TIMEOUT_MS: u64 = 100;
+
+impl Reading {
+    /// Whether any part of the message has been received.
+    fn is_started(&self, input: &Buf) -> bool {
+        match *self {
+            Reading::SegmentCount => !input.is_empty(),
+            Reading::Packed(ref reader) => !reader.is_empty() || !input.is_empty(),
+            _ => true,
+        }
+    }
+}
+
+impl CapnpState {
+    fn expectation(&self) -> Expectation {
+        match *self {
+            CapnpState::Idle => Expectation::Bytes(1),
+            CapnpState::Reading(Reading::SegmentCount) => Expectation::Bytes(4),
+            CapnpState::Reading(Reading::SegmentTable(segment_count, format)) => {
+                Expectation::Bytes(serialization::segment_table_len(segment_count, format))
+            }
+            CapnpState::Reading(Reading::Segments(total_words, _)) => {
+                Expectation::Bytes(total_words * 8)
+            }
+            CapnpState::Reading(Reading::Packed(_)) => Expectation::Bytes(1),
+            CapnpState::Writing | CapnpState::Closing(_) => Expectation::Flush(0),
+            CapnpState::Sleeping | CapnpState::Yielded => Expectation::Sleep,
+            CapnpState::Lingering => Expectation::Bytes(1),
+        }
+    }
+}
+
+/// Progress of flushing the output in background.
+enum Flush {
+    Pending,
+    /// The write side has been shut down after the output was flushed.
+    OutputClosed,
+    Flushed,
+    /// The timeout for sending has expired.
+    Expired,
 }
 
 /// Adaptor for receiving and sending Cap'n Proto messages over a stream connection.
 pub struct Capnp<E: Endpoint> {
     fsm: E,
     state: CapnpState,
+    deadline: Time,
+    progress: Progress,
+}
+
+/// Progress of the connection tracked independently of `CapnpState`.
+#[derive(Default)]
+struct Progress {
+    /// Deadline of flushing the output in background.
+    flush: Option<Time>,
+    /// Number of messages received in a row from the buffered input.
+    received: usize,
+    /// Size of the messages received in a row from the buffered input.
+    received_bytes: usize,
+    /// The output has reached the high watermark and not yet drained.
+    congested: bool,
+    /// Messages waiting to be copied to the output as it drains.
+    queue: OutputQueue,
+    /// The write side is to be shut down once the output is flushed.
+    closing_output: bool,
+    /// The write side has been shut down.
+    output_closed: bool,
+    /// The peer has shut down its side, nothing is read anymore.
+    input_closed: bool,
+    /// Size of the input buffered when it was last measured.
+    input_len: usize,
+    /// Size of the output buffered when it was last measured.
+    output_len: usize,
+    /// Input has been read while the progress of writing was watched, and
+    /// there may be more of it in the socket.
+    input_ready: bool,
+    /// The state machine has notified itself to resume receiving after
+    /// yielding, and the notification hasn't been delivered yet.
+    resume_pending: bool,
+}
+
+impl Progress {
+    /// Whether the progress of writing is to be checked periodically.
+    fn checking(&self) -> bool {
+        self.flush.is_some() || self.congested || !self.queue.is_empty()
+    }
+
+    fn is_flushed(&self, output: &Buf) -> bool {
+        output.len() == 0 && self.queue.is_empty()
+    }
+
+    fn measure<S: StreamSocket>(&mut self, transport: &mut Transport<S>) {
+        self.input_len = transport.input().len();
+        self.output_len = transport.output().len();
+    }
+
+    /// Whether to wait for the output to drain instead of for the `bytes`
+    /// of input.
+    ///
+    /// rotor-stream reports either the progress of reading or writing, not
+    /// both. So while the output is pending and the buffered input isn't
+    /// enough, the input is read by the state machine whenever the socket drains, and
+    /// every `Endpoint::output_check_interval` while it doesn't.
+    fn watches_output(&self, bytes: usize) -> bool {
+        self.checking() && self.output_len > 0 &&
+        (self.input_closed || !self.input_ready && self.input_len < bytes)
+    }
+
+    /// Shut down the write side after the output has been flushed for
+    /// `Action::CloseOutput`.
+    fn close_output<S: HalfClose>(&mut self, sock: &mut S) {
+        self.flush = None;
+        self.closing_output = false;
+        self.output_closed = true;
+        // A failure shows up when reading from the socket.
+        let _ = sock.close_write();
+    }
 }
 
 impl<E: Endpoint> Capnp<E> {
-    fn intent(fsm: E, state: CapnpState) -> IntentBuilder<Self> {
+    /// Start the stream of a connection whose handshake is complete, the
+    /// socket is reregistered. Data received along with the end of the
+    /// handshake is read right away, no event would arrive for it.
+    pub fn handshaken(sock: E::Socket,
+                      seed: E::Seed,
+                      scope: &mut Scope<E::Context>)
+                      -> Response<Stream<Capnp<E>>, Void> {
+        let response = Stream::connected(sock, seed, scope);
+        if response.is_stopped() {
+            return response;
+        }
+        let mut stream = None;
+        response.wrap(|machine| stream = Some(machine));
+        match stream {
+            Some(stream) => stream.ready(EventSet::readable() | EventSet::writable(), scope),
+            None => unreachable!(),
+        }
+    }
+
+    fn intent(fsm: E,
+              state: CapnpState,
+              expectation: Expectation,
+              deadline: Time,
+              mut progress: Progress,
+              scope: &mut Scope<E::Context>)
+              -> Intent<Self> {
+        let wakeup = match state {
+            // The output is checked when the sleep is over.
+            CapnpState::Sleeping => deadline,
+            _ if progress.checking() => {
+                let check = scope.now() + fsm.output_check_interval(scope);
+                cmp::min(cmp::min(deadline, progress.flush.unwrap_or(deadline)), check)
+            }
+            _ => deadline,
+        };
+        let expectation = match expectation {
+            // `bytes_flushed` is called on any progress of writing.
+            Expectation::Bytes(bytes) if progress.watches_output(bytes) => {
+                Expectation::Flush(progress.output_len - 1)
+            }
+            // The queue is refilled as soon as half of a chunk is written,
+            // it's never emptied without filling the buffer above that.
+            Expectation::Flush(0) if !progress.queue.is_empty() => {
+                Expectation::Flush(OUTPUT_CHUNK_SIZE / 2)
+            }
+            Expectation::Bytes(_) if progress.input_closed => Expectation::Sleep,
+            expectation => expectation,
+        };
+        // rotor-stream reads the socket once the buffered input isn't enough.
+        if let Expectation::Bytes(bytes) = expectation {
+            if progress.input_len < bytes {
+                progress.input_ready = false;
+            }
+        }
         Intent::of(Capnp {
-            fsm: fsm,
-            state: state,
-        })
+                fsm: fsm,
+                state: state,
+                deadline: deadline,
+                progress: progress,
+            })
+            .expect(expectation)
+            .deadline(wakeup)
+    }
+
+    fn from_action(action: Action<E>,
+                   progress: Progress,
+                   scope: &mut Scope<E::Context>)
+                   -> Intent<Self> {
+        match action {
+            Action::Idle(fsm) => Capnp::intent_idle(fsm, progress, scope),
+            Action::Recv(fsm) => Capnp::intent_read(fsm, progress, scope),
+            Action::Send(fsm) => {
+                let flush = scope.now() + fsm.send_timeout(scope);
+                Capnp::intent_idle(fsm, Progress { flush: Some(flush), ..progress }, scope)
+            }
+            Action::Flush(fsm) => Capnp::intent_flush(fsm, progress, scope),
+            Action::CloseOutput(fsm) => {
+                let mut progress = progress;
+                progress.queue.close();
+                if progress.input_closed {
+                    // Both sides are done then.
+                    Capnp::intent_close(fsm, None, progress, scope)
+                } else {
+                    let flush = scope.now() + fsm.send_timeout(scope);
+                    let progress = Progress {
+                        flush: Some(flush),
+                        closing_output: true,
+                        ..progress
+                    };
+                    Capnp::intent_idle(fsm, progress, scope)
+                }
+            }
+            Action::Sleep(fsm, timeout) => Capnp::intent_sleep(fsm, progress, scope, timeout),
+            Action::Close(fsm) => Capnp::close(fsm, CloseReason::Local, scope),
+            Action::FlushAndClose(fsm, linger) => {
+                Capnp::intent_close(fsm, linger, progress, scope)
+            }
+        }
     }
 
-    fn from_action(action: Action<E>, scope: &mut Scope<E::Context>) -> Intent<Self> {
+    /// Like `from_action`, but receiving of the message which was
+    /// interrupted in the `state` is continued instead of waiting for a new
+    /// one.
+    fn resume_action(action: Action<E>,
+                     state: CapnpState,
+                     progress: Progress,
+                     scope: &mut Scope<E::Context>)
+                     -> Intent<Self> {
+        match (action, state) {
+            (Action::Idle(fsm), CapnpState::Reading(reading)) |
+            (Action::Recv(fsm), CapnpState::Reading(reading)) => {
+                Capnp::intent_resume_read(fsm, reading, progress, scope)
+            }
+            (Action::Send(fsm), CapnpState::Reading(reading)) => {
+                let flush = scope.now() + fsm.send_timeout(scope);
+                let progress = Progress { flush: Some(flush), ..progress };
+                Capnp::intent_resume_read(fsm, reading, progress, scope)
+            }
+            (Action::CloseOutput(fsm), CapnpState::Reading(reading)) => {
+                let flush = scope.now() + fsm.send_timeout(scope);
+                let mut progress = Progress {
+                    flush: Some(flush),
+                    closing_output: true,
+                    ..progress
+                };
+                progress.queue.close();
+                Capnp::intent_resume_read(fsm, reading, progress, scope)
+            }
+            (action, _) => Capnp::from_action(action, progress, scope),
+        }
+    }
+
+    /// Like `resume_action`, but closing the connection is reported as
+    /// caused by the timeout during the `connection_state`.
+    fn timeout_action(action: Action<E>,
+                      state: CapnpState,
+                      connection_state: ConnectionState,
+                      progress: Progress,
+                      scope: &mut Scope<E::Context>)
+                      -> Intent<Self> {
         match action {
-            Action::Idle(fsm) => Capnp::intent_idle(fsm, scope),
-            Action::Recv(fsm) => Capnp::intent_read(fsm, scope),
-            Action::Flush(fsm) => Capnp::intent_flush(fsm, scope),
-            Action::Sleep(fsm, timeout) => Capnp::intent_sleep(fsm, scope, timeout),
-            Action::Close => Intent::done(),
+            Action::Close(fsm) => {
+                Capnp::close(fsm, CloseReason::Timeout(connection_state), scope)
+            }
+            action => Capnp::resume_action(action, state, progress, scope),
+        }
+    }
+
+    /// Terminate the state machine, notifying the endpoint of the `reason`.
+    fn close(fsm: E, reason: CloseReason, scope: &mut Scope<E::Context>) -> Intent<Self> {
+        fsm.closed(reason, scope);
+        Intent::done()
+    }
+
+    /// Write the queued messages as the output drains, and notify the
+    /// endpoint if the output has crossed either of the watermarks.
+    fn check_output(fsm: &mut E,
+                    transport: &mut Transport<E::Socket>,
+                    progress: &mut Progress,
+                    scope: &mut Scope<E::Context>) {
+        if transport.output().len() == 0 && !progress.output_closed {
+            progress.queue.write_to(transport.socket());
+        }
+        progress.queue.fill(transport.output());
+        let limits = fsm.output_limits(scope);
+        let pending = transport.output().len() + progress.queue.len();
+        if !progress.congested && pending >= limits.high_watermark {
+            progress.congested = true;
+            fsm.output_congested(scope);
+        } else if progress.congested && pending <= limits.low_watermark {
+            progress.congested = false;
+            let output = Capnp::writer(fsm, transport, &mut progress.queue, scope);
+            fsm.output_drained(output, scope);
+        }
+        progress.measure(transport);
+    }
+
+    /// Read the input which has arrived while the progress of writing is
+    /// watched, see `Progress::watches_output`. The end of the input and the
+    /// failures are reported like by rotor-stream.
+    fn read_input(transport: &mut Transport<E::Socket>,
+                  progress: &mut Progress)
+                  -> Option<Exception> {
+        let mut buf = [0; INPUT_CHUNK_SIZE];
+        match transport.socket().read(&mut buf) {
+            Ok(0) => Some(Exception::EndOfStream),
+            Ok(len) => {
+                transport.input().extend(&buf[..len]);
+                progress.input_ready = true;
+                progress.measure(transport);
+                None
+            }
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => None,
+            Err(ref err) if err.kind() == ErrorKind::BrokenPipe ||
+                            err.kind() == ErrorKind::ConnectionReset => {
+                Some(Exception::EndOfStream)
+            }
+            Err(err) => Some(Exception::ReadError(err)),
+        }
+    }
+
+    /// `check_output` after the endpoint has returned the `action`.
+    fn check_action(mut action: Action<E>,
+                    transport: &mut Transport<E::Socket>,
+                    progress: &mut Progress,
+                    scope: &mut Scope<E::Context>)
+                    -> Action<E> {
+        match action {
+            Action::Idle(ref mut fsm) |
+            Action::Recv(ref mut fsm) |
+            Action::Send(ref mut fsm) |
+            Action::Flush(ref mut fsm) |
+            Action::CloseOutput(ref mut fsm) |
+            Action::Sleep(ref mut fsm, _) |
+            Action::FlushAndClose(ref mut fsm, _) => {
+                Capnp::check_output(fsm, transport, progress, scope)
+            }
+            Action::Close(_) => {}
+        }
+        action
+    }
+
+    /// Call `message_flushed` if the output flushed in background has
+    /// drained and the endpoint is still waiting for messages, after it has
+    /// returned the `action`.
+    fn check_flushed(action: Action<E>,
+                     transport: &mut Transport<E::Socket>,
+                     mut progress: Progress,
+                     scope: &mut Scope<E::Context>)
+                     -> Intent<Self> {
+        if progress.flush.is_none() || !progress.is_flushed(transport.output()) {
+            return Capnp::from_action(action, progress, scope);
+        }
+        if progress.closing_output {
+            progress.close_output(transport.socket());
+            return Capnp::from_action(action, progress, scope);
+        }
+        match action {
+            Action::Idle(fsm) | Action::Recv(fsm) => {
+                progress.flush = None;
+                let output = Capnp::writer(&fsm, transport, &mut progress.queue, scope);
+                let action = fsm.message_flushed(output, scope);
+                let action = Capnp::check_action(action, transport, &mut progress, scope);
+                Capnp::from_action(action, progress, scope)
+            }
+            action => Capnp::from_action(action, progress, scope),
+        }
+    }
+
+    /// Progress of flushing the output in background, shutting down the
+    /// write side once it's flushed for `Action::CloseOutput`.
+    fn flush_progress(transport: &mut Transport<E::Socket>,
+                      progress: &mut Progress,
+                      scope: &mut Scope<E::Context>)
+                      -> Flush {
+        match progress.flush {
+            Some(_) if progress.is_flushed(transport.output()) && progress.closing_output => {
+                progress.close_output(transport.socket());
+                Flush::OutputClosed
+            }
+            Some(_) if progress.is_flushed(transport.output()) => Flush::Flushed,
+            Some(flush) if scope.now() >= flush => Flush::Expired,
+            _ => Flush::Pending,
         }
     }
 
-    fn intent_idle(fsm: E, scope: &mut Scope<E::Context>) -> Intent<Self> {
+    /// Notify the endpoint of the progress of flushing the output in
+    /// background while in the `state`.
+    fn report_flush(fsm: E,
+                    transport: &mut Transport<E::Socket>,
+                    flush: Flush,
+                    state: CapnpState,
+                    deadline: Time,
+                    mut progress: Progress,
+                    scope: &mut Scope<E::Context>)
+                    -> Intent<Self> {
+        match flush {
+            Flush::Pending | Flush::OutputClosed => {
+                let expectation = state.expectation();
+                Capnp::intent(fsm, state, expectation, deadline, progress, scope)
+            }
+            Flush::Flushed => {
+                progress.flush = None;
+                let output = Capnp::writer(&fsm, transport, &mut progress.queue, scope);
+                let action = fsm.message_flushed(output, scope);
+                let action = Capnp::check_action(action, transport, &mut progress, scope);
+                Capnp::resume_action(action, state, progress, scope)
+            }
+            Flush::Expired => {
+                progress.flush = None;
+                let output = Capnp::writer(&fsm, transport, &mut progress.queue, scope);
+                let action = fsm.timeout(ConnectionState::Sending, output, scope);
+                let action = Capnp::check_action(action, transport, &mut progress, scope);
+                Capnp::timeout_action(action, state, ConnectionState::Sending, progress, scope)
+            }
+        }
+    }
+
+    /// The peer has shut down its side between messages.
+    fn input_closed(fsm: E,
+                    transport: &mut Transport<E::Socket>,
+                    mut progress: Progress,
+                    scope: &mut Scope<E::Context>)
+                    -> Intent<Self> {
+        if progress.output_closed {
+            return Capnp::close(fsm, CloseReason::PeerClosed, scope);
+        }
+        progress.input_closed = true;
+        let output = Capnp::writer(&fsm, transport, &mut progress.queue, scope);
+        let action = fsm.input_closed(output, scope);
+        match Capnp::check_action(action, transport, &mut progress, scope) {
+            Action::Close(fsm) => Capnp::close(fsm, CloseReason::PeerClosed, scope),
+            action => Capnp::from_action(action, progress, scope),
+        }
+    }
+
+    /// Whether enough of the buffered input has been received in a row.
+    fn should_yield(fsm: &E, progress: &Progress, scope: &mut Scope<E::Context>) -> bool {
+        progress.received >= fsm.recv_budget(scope) ||
+        progress.received_bytes >= fsm.framing_limits(scope).max_bytes_per_wakeup
+    }
+
+    fn intent_idle(fsm: E, progress: Progress, scope: &mut Scope<E::Context>) -> Intent<Self> {
+        if Capnp::should_yield(&fsm, &progress, scope) {
+            return Capnp::intent_yield(fsm, progress, scope);
+        }
         let deadline = scope.now() + fsm.idle_timeout(scope);
-        Capnp::intent(fsm, CapnpState::Idle)
-            .expect_bytes(1)
-            .deadline(deadline)
+        let state = CapnpState::Idle;
+        let expectation = state.expectation();
+        Capnp::intent(fsm, state, expectation, deadline, progress, scope)
+    }
+
+    fn intent_read(fsm: E, progress: Progress, scope: &mut Scope<E::Context>) -> Intent<Self> {
+        if Capnp::should_yield(&fsm, &progress, scope) {
+            return Capnp::intent_yield(fsm, progress, scope);
+        }
+        let state = match fsm.wire_format(scope) {
+            WireFormat::Unpacked | WireFormat::UnpaddedUnpacked => Reading::SegmentCount,
+            WireFormat::Packed => Reading::Packed(PackedReader::new()),
+        };
+        Capnp::intent_resume_read(fsm, state, progress, scope)
     }
 
-    fn intent_read(fsm: E, scope: &mut Scope<E::Context>) -> Intent<Self> {
-        let deadline = scope.now() + fsm.recv_timeout(scope);
-        Capnp::intent(fsm, CapnpState::Reading(Reading::SegmentCount))
-            .expect_bytes(4)
-            .deadline(deadline)
+    fn intent_resume_read(fsm: E,
+                          state: Reading,
+                          progress: Progress,
+                          scope: &mut Scope<E::Context>)
+                          -> Intent<Self> {
+        let deadline = fsm.recv_deadline(scope);
+        let state = CapnpState::Reading(state);
+        let expectation = state.expectation();
+        Capnp::intent(fsm, state, expectation, deadline, progress, scope)
     }
 
     fn intent_continue_read(fsm: E,
                             transport: &mut Transport<E::Socket>,
                             state: Reading,
+                            mut progress: Progress,
                             scope: &mut Scope<E::Context>,
                             deadline: Time)
                             -> Intent<Self> {
@@ -70,62 +526,210 @@ impl<E: Endpoint> Capnp<E> {
         use self::Reading::*;
         match state {
             SegmentCount => {
-                match serialization::read_segment_count(transport.input()) {
+                match serialization::read_segment_count(transport.input(),
+                                                        fsm.framing_limits(scope)) {
                     Ok(segment_count) => {
-                        Capnp::intent(fsm, Reading(SegmentTable(segment_count)))
-                            .expect_bytes(segment_count * 4)
-                            .deadline(deadline)
+                        progress.measure(transport);
+                        let format = fsm.wire_format(scope);
+                        let state = Reading(SegmentTable(segment_count, format));
+                        let expectation = state.expectation();
+                        Capnp::intent(fsm, state, expectation, deadline, progress, scope)
                     }
                     Err(err) => {
-                        fsm.exception(Error::Serialization(err), scope);
-                        Intent::done()
+                        Capnp::close(fsm, CloseReason::Error(err), scope)
                     }
                 }
             }
-            SegmentTable(segment_count) => {
+            SegmentTable(segment_count, format) => {
                 match serialization::read_segment_table(transport.input(),
                                                         segment_count,
-                                                        fsm.reader_options(scope)) {
+                                                        format,
+                                                        fsm.reader_options(scope),
+                                                        fsm.framing_limits(scope)) {
                     Ok((total_words, segment_slices)) => {
-                        Capnp::intent(fsm, Reading(Segments(total_words, segment_slices)))
-                            .expect_bytes(total_words * 8)
-                            .deadline(deadline)
+                        progress.measure(transport);
+                        let state = Reading(Segments(total_words, segment_slices));
+                        let expectation = state.expectation();
+                        Capnp::intent(fsm, state, expectation, deadline, progress, scope)
                     }
                     Err(err) => {
-                        fsm.exception(Error::Serialization(err), scope);
-                        Intent::done()
+                        Capnp::close(fsm, CloseReason::Error(err), scope)
                     }
                 }
             }
             Segments(total_words, segment_slices) => {
-                match serialization::read_segments(transport.input(),
-                                                   total_words,
-                                                   segment_slices,
-                                                   fsm.reader_options(scope)) {
-                    Ok(message) => {
-                        let action = fsm.message_received(&message,
-                                                          MessageWriter(transport.output()),
-                                                          scope);
-                        Capnp::from_action(action, scope)
+                let options = fsm.reader_options(scope);
+                let format = fsm.wire_format(scope);
+                let limits = fsm.output_limits(scope);
+                let pool = fsm.segment_pool(scope);
+                let size = serialization::message_size(segment_slices.len(), total_words, format);
+                let action = {
+                    // The message may borrow the input, it's consumed after
+                    // the message is dropped.
+                    let (input, output) = transport.buffers();
+                    let message = serialization::read_segments(&input[..],
+                                                               total_words,
+                                                               segment_slices,
+                                                               options,
+                                                               pool);
+                    let output = MessageWriter::new(output, &mut progress.queue, format, limits);
+                    fsm.take_message(message, output, scope)
+                };
+                transport.input().consume(total_words * 8);
+                Capnp::message_handled(action, transport, size, progress, scope)
+            }
+            Packed(mut reader) => {
+                let options = fsm.reader_options(scope);
+                let limits = fsm.framing_limits(scope);
+                let pool = fsm.segment_pool(scope);
+                match reader.read(transport.input(), options, limits, pool) {
+                    Ok(Some((message, size))) => {
+                        Capnp::message_received(fsm, transport, message, size, progress, scope)
+                    }
+                    Ok(None) => {
+                        // The length of a packed message is unknown until
+                        // it's decoded, and what's left in the buffer is an
+                        // incomplete tag, so wait for any more bytes.
+                        progress.measure(transport);
+                        let expectation = Expectation::Bytes(transport.input().len() + 1);
+                        let state = Reading(Packed(reader));
+                        Capnp::intent(fsm, state, expectation, deadline, progress, scope)
                     }
                     Err(err) => {
-                        fsm.exception(Error::Serialization(err), scope);
-                        Intent::done()
+                        Capnp::close(fsm, CloseReason::Error(err), scope)
                     }
                 }
             }
         }
     }
 
-    fn intent_flush(fsm: E, scope: &mut Scope<E::Context>) -> Intent<Self> {
+    fn message_received(fsm: E,
+                        transport: &mut Transport<E::Socket>,
+                        message: MessageReader,
+                        size: usize,
+                        mut progress: Progress,
+                        scope: &mut Scope<E::Context>)
+                        -> Intent<Self> {
+        let output = Capnp::writer(&fsm, transport, &mut progress.queue, scope);
+        let action = fsm.take_message(message, output, scope);
+        Capnp::message_handled(action, transport, size, progress, scope)
+    }
+
+    /// Continue after the endpoint has handled a message of `size` bytes.
+    fn message_handled(action: Action<E>,
+                       transport: &mut Transport<E::Socket>,
+                       size: usize,
+                       mut progress: Progress,
+                       scope: &mut Scope<E::Context>)
+                       -> Intent<Self> {
+        let action = Capnp::check_action(action, transport, &mut progress, scope);
+        // rotor-stream delivers the buffered input right away if the next
+        // expectation is already satisfied, count the messages to yield.
+        if transport.input().len() > 0 {
+            progress.received += 1;
+            progress.received_bytes += size;
+        } else {
+            progress.received = 0;
+            progress.received_bytes = 0;
+        }
+        Capnp::check_flushed(action, transport, progress, scope)
+    }
+
+    fn writer<'a>(fsm: &E,
+                  transport: &'a mut Transport<E::Socket>,
+                  queue: &'a mut OutputQueue,
+                  scope: &mut Scope<E::Context>)
+                  -> MessageWriter<'a> {
+        MessageWriter::new(transport.output(),
+                           queue,
+                           fsm.wire_format(scope),
+                           fsm.output_limits(scope))
+    }
+
+    fn intent_flush(fsm: E, progress: Progress, scope: &mut Scope<E::Context>) -> Intent<Self> {
+        let deadline = scope.now() + fsm.send_timeout(scope);
+        let state = CapnpState::Writing;
+        let expectation = state.expectation();
+        // `message_flushed` is called once the output is flushed anyway.
+        let progress = Progress {
+            flush: None,
+            received: 0,
+            received_bytes: 0,
+            ..progress
+        };
+        Capnp::intent(fsm, state, expectation, deadline, progress, scope)
+    }
+
+    fn intent_close(fsm: E,
+                    linger: Option<Duration>,
+                    progress: Progress,
+                    scope: &mut Scope<E::Context>)
+                    -> Intent<Self> {
         let deadline = scope.now() + fsm.send_timeout(scope);
-        Capnp::intent(fsm, CapnpState::Writing)
-            .expect_flush()
-            .deadline(deadline)
+        let state = CapnpState::Closing(linger);
+        let expectation = state.expectation();
+        let progress = Progress {
+            flush: None,
+            received: 0,
+            received_bytes: 0,
+            ..progress
+        };
+        Capnp::intent(fsm, state, expectation, deadline, progress, scope)
+    }
+
+    /// Shut down the write side once the output has been flushed for
+    /// closing, and linger if asked to.
+    fn output_closed(fsm: E,
+                     transport: &mut Transport<E::Socket>,
+                     linger: Option<Duration>,
+                     progress: Progress,
+                     scope: &mut Scope<E::Context>)
+                     -> Intent<Self> {
+        let flushed = progress.output_closed || transport.socket().close_write().is_ok();
+        match linger {
+            // There's nothing to wait for if the peer has closed its side.
+            Some(timeout) if flushed && !progress.input_closed => {
+                let len = transport.input().len();
+                transport.input().consume(len);
+                let deadline = scope.now() + timeout;
+                let state = CapnpState::Lingering;
+                let expectation = state.expectation();
+                Capnp::intent(fsm, state, expectation, deadline, progress, scope)
+            }
+            _ => {
+                Capnp::close(fsm, CloseReason::Shutdown { flushed: flushed }, scope)
+            }
+        }
+    }
+
+    fn intent_sleep(fsm: E,
+                    progress: Progress,
+                    scope: &mut Scope<E::Context>,
+                    timeout: Duration)
+                    -> Intent<Self> {
+        let deadline = scope.now() + timeout;
+        let state = CapnpState::Sleeping;
+        let expectation = state.expectation();
+        Capnp::intent(fsm, state, expectation, deadline, progress, scope)
     }
 
-    fn intent_sleep(fsm: E, scope: &mut Scope<E::Context>, timeout: Duration) -> Intent<Self> {
-        Capnp::intent(fsm, CapnpState::Sleeping).sleep().deadline(scope.now() + timeout)
+    /// Suspend receiving of the buffered messages until the state machine
+    /// is woken up, so that other connections are served meanwhile.
+    fn intent_yield(fsm: E, mut progress: Progress, scope: &mut Scope<E::Context>) -> Intent<Self> {
+        // The timeout resumes receiving if the notification fails. A
+        // notification still pending resumes it as well.
+        if !progress.resume_pending {
+            progress.resume_pending = scope.notifier().wakeup().is_ok();
+        }
+        let deadline = scope.now() + Duration::from_millis(YIELD_TIMEOUT_MS);
+        let state = CapnpState::Yielded;
+        let expectation = state.expectation();
+        let progress = Progress {
+            received: 0,
+            received_bytes: 0,
+            ..progress
+        };
+        Capnp::intent(fsm, state, expectation, deadline, progress, scope)
     }
 }
 
@@ -139,39 +743,84 @@ impl<E: Endpoint> Protocol for Capnp<E> {
               scope: &mut Scope<Self::Context>)
               -> Intent<Self> {
         let action = E::create(seed, sock, scope);
-        Capnp::from_action(action, scope)
+        Capnp::from_action(action, Progress::default(), scope)
     }
 
-    fn bytes_read(self,
+    fn bytes_read(mut self,
                   transport: &mut Transport<Self::Socket>,
                   _end: usize,
                   scope: &mut Scope<Self::Context>)
                   -> Intent<Self> {
+        self.progress.measure(transport);
         let state = match self.state {
             CapnpState::Idle => {
-                if transport.input().len() < 4 {
-                    return Capnp::intent_read(self.fsm, scope);
-                } else {
-                    Reading::SegmentCount
+                match self.fsm.wire_format(scope) {
+                    WireFormat::Unpacked | WireFormat::UnpaddedUnpacked
+                        if transport.input().len() < 4 => {
+                        return Capnp::intent_read(self.fsm, self.progress, scope);
+                    }
+                    WireFormat::Unpacked | WireFormat::UnpaddedUnpacked => Reading::SegmentCount,
+                    WireFormat::Packed => Reading::Packed(PackedReader::new()),
                 }
             }
             CapnpState::Reading(state) => state,
+            CapnpState::Lingering => {
+                let len = transport.input().len();
+                transport.input().consume(len);
+                let expectation = self.state.expectation();
+                return Capnp::intent(self.fsm, self.state, expectation, self.deadline,
+                                     self.progress, scope);
+            }
             _ => unreachable!(),
         };
-        let deadline = scope.now() + self.fsm.recv_timeout(scope);
-        Capnp::intent_continue_read(self.fsm, transport, state, scope, deadline)
+        let deadline = self.fsm.recv_deadline(scope);
+        Capnp::intent_continue_read(self.fsm, transport, state, self.progress, scope, deadline)
     }
 
     fn bytes_flushed(self,
                      transport: &mut Transport<Self::Socket>,
                      scope: &mut Scope<Self::Context>)
                      -> Intent<Self> {
-        match self.state {
+        let Capnp { mut fsm, state, deadline, mut progress } = self;
+        progress.measure(transport);
+        match state {
             CapnpState::Writing => {
-                let action = self.fsm.message_flushed(MessageWriter(transport.output()), scope);
-                Capnp::from_action(action, scope)
+                Capnp::check_output(&mut fsm, transport, &mut progress, scope);
+                if !progress.is_flushed(transport.output()) {
+                    let expectation = state.expectation();
+                    return Capnp::intent(fsm, state, expectation, deadline, progress, scope);
+                }
+                let output = Capnp::writer(&fsm, transport, &mut progress.queue, scope);
+                let action = fsm.message_flushed(output, scope);
+                let action = Capnp::check_action(action, transport, &mut progress, scope);
+                Capnp::from_action(action, progress, scope)
+            }
+            CapnpState::Closing(linger) => {
+                Capnp::check_output(&mut fsm, transport, &mut progress, scope);
+                if !progress.is_flushed(transport.output()) {
+                    let expectation = state.expectation();
+                    return Capnp::intent(fsm, state, expectation, deadline, progress, scope);
+                }
+                Capnp::output_closed(fsm, transport, linger, progress, scope)
+            }
+            // The output has drained while its progress is watched instead
+            // of the input.
+            state => {
+                Capnp::check_output(&mut fsm, transport, &mut progress, scope);
+                if !progress.input_closed {
+                    if let Some(reason) = Capnp::<E>::read_input(transport, &mut progress) {
+                        let capnp = Capnp {
+                            fsm: fsm,
+                            state: state,
+                            deadline: deadline,
+                            progress: progress,
+                        };
+                        return capnp.exception(transport, reason, scope);
+                    }
+                }
+                let flush = Capnp::<E>::flush_progress(transport, &mut progress, scope);
+                Capnp::report_flush(fsm, transport, flush, state, deadline, progress, scope)
             }
-            _ => unreachable!(),
         }
     }
 
@@ -179,48 +828,151 @@ impl<E: Endpoint> Protocol for Capnp<E> {
                transport: &mut Transport<Self::Socket>,
                scope: &mut Scope<Self::Context>)
                -> Intent<Self> {
-        let state = match self.state {
+        let Capnp { mut fsm, state, deadline, mut progress } = self;
+        progress.measure(transport);
+        match state {
+            CapnpState::Closing(linger) => {
+                Capnp::check_output(&mut fsm, transport, &mut progress, scope);
+                if progress.is_flushed(transport.output()) {
+                    return Capnp::output_closed(fsm, transport, linger, progress, scope);
+                } else if scope.now() < deadline {
+                    let expectation = state.expectation();
+                    return Capnp::intent(fsm, state, expectation, deadline, progress, scope);
+                }
+                return Capnp::close(fsm, CloseReason::Shutdown { flushed: false }, scope);
+            }
+            CapnpState::Lingering => {
+                return Capnp::close(fsm, CloseReason::Shutdown { flushed: true }, scope);
+            }
+            _ => {}
+        }
+        // The output is checked when the sleep is over.
+        let checking = match state {
+            CapnpState::Sleeping => false,
+            _ => progress.checking(),
+        };
+        Capnp::check_output(&mut fsm, transport, &mut progress, scope);
+        if checking {
+            // The input isn't read by rotor-stream while the output doesn't
+            // drain, see `Progress::watches_output`.
+            let receiving = match state {
+                CapnpState::Idle | CapnpState::Reading(_) => !progress.input_closed,
+                _ => false,
+            };
+            if receiving {
+                if let Some(reason) = Capnp::<E>::read_input(transport, &mut progress) {
+                    let capnp = Capnp {
+                        fsm: fsm,
+                        state: state,
+                        deadline: deadline,
+                        progress: progress,
+                    };
+                    return capnp.exception(transport, reason, scope);
+                }
+            }
+            match Capnp::<E>::flush_progress(transport, &mut progress, scope) {
+                Flush::Pending => {}
+                flush => {
+                    return Capnp::report_flush(fsm, transport, flush, state, deadline, progress,
+                                               scope)
+                }
+            }
+        }
+        if checking && scope.now() < deadline {
+            // Just checking the progress of writing.
+            let expectation = state.expectation();
+            return Capnp::intent(fsm, state, expectation, deadline, progress, scope);
+        }
+        if let CapnpState::Yielded = state {
+            return Capnp::intent_idle(fsm, progress, scope);
+        }
+        let connection_state = match state {
             CapnpState::Idle => ConnectionState::Idle,
             CapnpState::Reading(_) => ConnectionState::Receiving,
             CapnpState::Writing => ConnectionState::Sending,
             CapnpState::Sleeping => ConnectionState::Sleeping,
+            CapnpState::Yielded | CapnpState::Closing(_) | CapnpState::Lingering => {
+                unreachable!()
+            }
         };
-        let action = self.fsm.timeout(state, MessageWriter(transport.output()), scope);
-        Capnp::from_action(action, scope)
+        let output = Capnp::writer(&fsm, transport, &mut progress.queue, scope);
+        let action = fsm.timeout(connection_state, output, scope);
+        let action = Capnp::check_action(action, transport, &mut progress, scope);
+        Capnp::timeout_action(action, state, connection_state, progress, scope)
     }
 
     fn wakeup(self,
-              _transport: &mut Transport<Self::Socket>,
+              transport: &mut Transport<Self::Socket>,
               scope: &mut Scope<Self::Context>)
               -> Intent<Self> {
-        let action = self.fsm.wakeup(scope);
-        Capnp::from_action(action, scope)
+        let Capnp { fsm, state, deadline, mut progress } = self;
+        progress.measure(transport);
+        // The notifier is shared with the endpoint, so the notification of
+        // resuming after yielding is the first one while it's pending. If the
+        // timeout has resumed receiving first, it's just consumed.
+        let resume = progress.resume_pending;
+        progress.resume_pending = false;
+        match state {
+            CapnpState::Yielded if resume => {
+                // Any message left in the buffer is delivered right away.
+                return Capnp::intent_idle(fsm, progress, scope);
+            }
+            CapnpState::Closing(_) | CapnpState::Lingering => {
+                let expectation = state.expectation();
+                return Capnp::intent(fsm, state, expectation, deadline, progress, scope);
+            }
+            _ if resume => {
+                let expectation = state.expectation();
+                return Capnp::intent(fsm, state, expectation, deadline, progress, scope);
+            }
+            _ => {}
+        }
+        let output = Capnp::writer(&fsm, transport, &mut progress.queue, scope);
+        let action = fsm.wakeup(output, scope);
+        match (Capnp::check_action(action, transport, &mut progress, scope), state) {
+            // Still yielding until receiving is resumed by the timeout.
+            (Action::Idle(fsm), CapnpState::Yielded) |
+            (Action::Recv(fsm), CapnpState::Yielded) => {
+                let state = CapnpState::Yielded;
+                let expectation = state.expectation();
+                Capnp::intent(fsm, state, expectation, deadline, progress, scope)
+            }
+            (action, state) => Capnp::resume_action(action, state, progress, scope),
+        }
     }
 
     fn exception(self,
-                 _transport: &mut Transport<Self::Socket>,
+                 transport: &mut Transport<Self::Socket>,
                  reason: Exception,
                  scope: &mut Scope<Self::Context>)
                  -> Intent<Self> {
-        match reason {
-            Exception::EndOfStream => {
-                if let CapnpState::Reading(_) = self.state {
-                    self.fsm.exception(Error::Stream(reason), scope);
-                }
-                Intent::done()
+        let Capnp { fsm, state, mut progress, .. } = self;
+        progress.measure(transport);
+        let reason = match (reason, state) {
+            // The peer has closed its side, or can't be read from anyway.
+            (_, CapnpState::Lingering) => CloseReason::Shutdown { flushed: true },
+            (Exception::EndOfStream, CapnpState::Reading(ref reading))
+                if reading.is_started(transport.input()) => {
+                CloseReason::Error(Error::PeerClosedMidMessage)
             }
-            _ => {
-                self.fsm.exception(Error::Stream(reason), scope);
-                Intent::done()
+            (Exception::EndOfStream, _) => {
+                return Capnp::input_closed(fsm, transport, progress, scope);
             }
-        }
+            (reason, _) => CloseReason::Error(Error::Stream(reason)),
+        };
+        Capnp::close(fsm, reason, scope)
     }
 
     fn fatal(self,
              reason: Exception,
              scope: &mut Scope<Self::Context>)
              -> Option<Box<::std::error::Error>> {
-        self.fsm.exception(Error::Stream(reason), scope);
+        let reason = match self.state {
+            CapnpState::Closing(_) => CloseReason::Shutdown { flushed: false },
+            CapnpState::Lingering => CloseReason::Shutdown { flushed: true },
+            _ => CloseReason::Error(Error::Stream(reason)),
+        };
+        self.fsm.closed(reason, scope);
         None
     }
 }
@@ -0,0 +1,395 @@
+use std::any::Any;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+
+use rotor::mio::{Evented, EventSet, PollOpt, Selector, Token, TryAccept};
+use rotor_stream::{ActiveStream, SocketError};
+
+use connector::Resolve;
+use socket::{HalfClose, Handshake, PeerAddr};
+
+/// TLS session of a connection, the state of the protocol apart from the
+/// socket.
+///
+/// The methods match those of `rustls::Connection`, implementing this trait
+/// for a TLS library is a matter of forwarding them.
+pub trait TlsSession: Any {
+    /// Read TLS records from the socket.
+    fn read_tls(&mut self, rd: &mut Read) -> io::Result<usize>;
+
+    /// Write pending TLS records to the socket.
+    fn write_tls(&mut self, wr: &mut Write) -> io::Result<usize>;
+
+    /// Process the TLS records read, an error is fatal to the session.
+    fn process_new_packets(&mut self) -> io::Result<()>;
+
+    /// Whether the handshake is still in progress.
+    fn is_handshaking(&self) -> bool;
+
+    /// Whether TLS records are pending to be written.
+    fn wants_write(&self) -> bool;
+
+    /// Read decrypted data. It fails with `WouldBlock` if none is available,
+    /// returns 0 after the peer has sent `close_notify` and fails with
+    /// `UnexpectedEof` if the connection has been closed without it.
+    fn read_plaintext(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Encrypt data to be written.
+    fn write_plaintext(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Queue a `close_notify` alert, nothing may be written afterwards.
+    fn send_close_notify(&mut self);
+
+    /// Certificate of the peer in DER, it's known after the handshake if the
+    /// peer has presented one.
+    fn peer_certificate(&self) -> Option<&[u8]>;
+}
+
+/// TLS session started for every connection.
+pub trait StartSession: TlsSession + Sized {
+    /// Configuration of the sessions.
+    type Config;
+
+    /// Start a session, with no handshake messages exchanged yet.
+    fn start(config: &Self::Config) -> io::Result<Self>;
+}
+
+/// A socket of type `S` secured by a TLS session of type `T`, which can be
+/// the `Endpoint::Socket`.
+///
+/// `Acceptor` and `Connector` complete the handshake before
+/// `Endpoint::create` is called, so the peer certificate is already known
+/// then.
+pub struct TlsStream<S: Read + Write, T: TlsSession> {
+    sock: S,
+    session: T,
+    /// Bytes of the data being written which have been encrypted, but aren't
+    /// reported as written until the records are flushed.
+    held: usize,
+    close_notify_sent: bool,
+}
+
+impl<S, T> TlsStream<S, T>
+    where S: Read + Write,
+          T: TlsSession
+{
+    /// Secure the `sock` with the `session`.
+    pub fn new(sock: S, session: T) -> TlsStream<S, T> {
+        TlsStream {
+            sock: sock,
+            session: session,
+            held: 0,
+            close_notify_sent: false,
+        }
+    }
+
+    /// The underlying socket.
+    pub fn get_ref(&self) -> &S {
+        &self.sock
+    }
+
+    /// The TLS session.
+    pub fn session(&self) -> &T {
+        &self.session
+    }
+
+    /// Certificate of the peer in DER, see `TlsSession::peer_certificate`.
+    pub fn peer_certificate(&self) -> Option<&[u8]> {
+        self.session.peer_certificate()
+    }
+
+    fn send_close_notify(&mut self) {
+        if !self.close_notify_sent {
+            self.session.send_close_notify();
+            self.close_notify_sent = true;
+        }
+    }
+
+    /// Write the pending TLS records, it fails with `WouldBlock` if some of
+    /// them are left.
+    fn write_records(&mut self) -> io::Result<()> {
+        while self.session.wants_write() {
+            if try!(self.session.write_tls(&mut self.sock)) == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write TLS records"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `write_records`, except that records left are written later.
+    fn try_write_records(&mut self) -> io::Result<()> {
+        match self.write_records() {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Process the TLS records read, writing the responses to them.
+    fn process_records(&mut self) -> io::Result<()> {
+        match self.session.process_new_packets() {
+            Ok(()) => self.try_write_records(),
+            Err(err) => {
+                // The alert about the error is sent if possible.
+                let _ = self.write_records();
+                Err(err)
+            }
+        }
+    }
+}
+
+impl<S, T> Read for TlsStream<S, T>
+    where S: Read + Write,
+          T: TlsSession
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        try!(self.try_write_records());
+        loop {
+            match self.session.read_plaintext(buf) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            // The socket is read until it would block, as the stream is
+            // registered as edge-triggered. The end of it is reported by
+            // `read_plaintext`.
+            if try!(self.session.read_tls(&mut self.sock)) > 0 {
+                try!(self.process_records());
+            }
+        }
+    }
+}
+
+/// The session encrypts the data it accepts at once, which can't be undone
+/// if the records then can't be flushed. Some of that data is held back:
+/// the last byte of it isn't reported as written, or none of it if the
+/// write fails with `WouldBlock`. The held bytes are skipped by the next
+/// write, so it must be passed the same data again from where the previous
+/// one stopped. Both the connection buffer of rotor-stream and the messages
+/// queued by `MessageWriter::write_owned` are retried that way. Writing
+/// other data after a short or blocked write corrupts the stream.
+impl<S, T> Write for TlsStream<S, T>
+    where S: Read + Write,
+          T: TlsSession
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.write_records());
+        let held = self.held;
+        self.held = 0;
+        let written = held + try!(self.session.write_plaintext(&buf[held..]));
+        match self.write_records() {
+            Ok(()) => Ok(written),
+            // The last byte is kept in the buffer of the stream, so that
+            // writing is retried when the socket becomes writable.
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock && written > 1 => {
+                self.held = 1;
+                Ok(written - 1)
+            }
+            Err(err) => {
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    self.held = written;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_records()
+    }
+}
+
+/// The peer is notified of closing the connection if possible, otherwise
+/// it can't tell it from the connection being cut.
+impl<S, T> Drop for TlsStream<S, T>
+    where S: Read + Write,
+          T: TlsSession
+{
+    fn drop(&mut self) {
+        self.send_close_notify();
+        let _ = self.write_records();
+    }
+}
+
+impl<S, T> Evented for TlsStream<S, T>
+    where S: Read + Write + Evented,
+          T: TlsSession
+{
+    fn register(&self,
+                selector: &mut Selector,
+                token: Token,
+                interest: EventSet,
+                opts: PollOpt)
+                -> io::Result<()> {
+        self.sock.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  selector: &mut Selector,
+                  token: Token,
+                  interest: EventSet,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        self.sock.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.sock.deregister(selector)
+    }
+}
+
+impl<S, T> SocketError for TlsStream<S, T>
+    where S: Read + Write + SocketError,
+          T: TlsSession
+{
+    fn take_socket_error(&self) -> io::Result<()> {
+        self.sock.take_socket_error()
+    }
+}
+
+impl<S, T> HalfClose for TlsStream<S, T>
+    where S: Read + Write + HalfClose,
+          T: TlsSession
+{
+    fn close_write(&mut self) -> io::Result<()> {
+        // The output has been flushed, so there's room for the alert.
+        self.send_close_notify();
+        try!(self.write_records());
+        self.sock.close_write()
+    }
+}
+
+impl<S, T> Handshake for TlsStream<S, T>
+    where S: Read + Write,
+          T: TlsSession
+{
+    fn handshake(&mut self) -> io::Result<bool> {
+        loop {
+            match self.write_records() {
+                Ok(()) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => return Err(err),
+            }
+            if !self.session.is_handshaking() {
+                return Ok(true);
+            }
+            match self.session.read_tls(&mut self.sock) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                              "connection closed in the middle of handshake"))
+                }
+                Ok(_) => try!(self.process_records()),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl<S, T> PeerAddr for TlsStream<S, T>
+    where S: Read + Write + PeerAddr,
+          T: TlsSession
+{
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.sock.peer_ip()
+    }
+}
+
+impl<S, T> ActiveStream for TlsStream<S, T>
+    where S: ActiveStream,
+          T: StartSession
+{
+    type Address = (S::Address, T::Config);
+
+    fn connect(address: &Self::Address) -> io::Result<Self> {
+        let session = try!(T::start(&address.1));
+        let sock = try!(S::connect(&address.0));
+        Ok(TlsStream::new(sock, session))
+    }
+}
+
+/// Target of a `Connector` connecting with TLS, every connection is secured
+/// by a new session with the `config`.
+#[derive(Clone, Debug)]
+pub struct TlsTarget<R, C> {
+    /// Target of the underlying socket.
+    pub target: R,
+    /// Configuration of the sessions.
+    pub config: C,
+}
+
+impl<R, C> Resolve for TlsTarget<R, C>
+    where R: Resolve,
+          C: Clone
+{
+    type Address = (R::Address, C);
+
+    fn resolve(&self) -> io::Result<Vec<Self::Address>> {
+        self.target.resolve().map(|addresses| {
+            addresses.into_iter()
+                .map(|address| (address, self.config.clone()))
+                .collect()
+        })
+    }
+}
+
+/// Listener for an `Acceptor` accepting connections with TLS, every
+/// connection is secured by a new session of type `T`.
+pub struct TlsListener<L, T: StartSession> {
+    listener: L,
+    config: T::Config,
+}
+
+impl<L, T: StartSession> TlsListener<L, T> {
+    /// Secure the connections accepted from the `listener` with sessions
+    /// started with the `config`.
+    pub fn new(listener: L, config: T::Config) -> TlsListener<L, T> {
+        TlsListener {
+            listener: listener,
+            config: config,
+        }
+    }
+}
+
+impl<L, T> TryAccept for TlsListener<L, T>
+    where L: TryAccept,
+          L::Output: Read + Write,
+          T: StartSession
+{
+    type Output = TlsStream<L::Output, T>;
+
+    fn accept(&self) -> io::Result<Option<Self::Output>> {
+        match try!(self.listener.accept()) {
+            Some(sock) => {
+                let session = try!(T::start(&self.config));
+                Ok(Some(TlsStream::new(sock, session)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl<L, T> Evented for TlsListener<L, T>
+    where L: Evented,
+          T: StartSession
+{
+    fn register(&self,
+                selector: &mut Selector,
+                token: Token,
+                interest: EventSet,
+                opts: PollOpt)
+                -> io::Result<()> {
+        self.listener.register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  selector: &mut Selector,
+                  token: Token,
+                  interest: EventSet,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        self.listener.reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.listener.deregister(selector)
+    }
+}
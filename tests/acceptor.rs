@@ -0,0 +1,213 @@
+extern crate capnp;
+extern crate rotor;
+extern crate rotor_capnp;
+
+#[macro_use]
+mod common;
+
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::net::TcpStream as StdTcpStream;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use capnp::message::ReaderOptions;
+use capnp::{serialize, text};
+use rotor::{Config as LoopConfig, Loop, Scope};
+use rotor::mio::tcp::{TcpListener, TcpStream};
+use rotor_capnp::{Acceptor, Action, Admission, CloseReason, ConnectionState, Endpoint,
+                  MessageReader, MessageWriter, Rejection};
+
+use common::text_message;
+
+#[derive(Default)]
+struct Connections {
+    closed: usize,
+    rejections: Vec<Rejection>,
+}
+
+struct Context {
+    /// Number of admitted connections, the loop is shut down once they're
+    /// all closed.
+    admitted: usize,
+    /// Length of the padding sent after the rejection reason.
+    padding: usize,
+    connections: Rc<RefCell<Connections>>,
+}
+
+/// Receives until the peer closes the connection.
+struct Server;
+
+impl Endpoint for Server {
+    type Context = Context;
+    type Socket = TcpStream;
+    type Seed = ();
+
+    fn create(_seed: (), _sock: &mut TcpStream, _scope: &mut Scope<Context>) -> Action<Self> {
+        Action::Recv(Server)
+    }
+
+    fn message_received(self,
+                        _message: &MessageReader,
+                        _output: MessageWriter,
+                        _scope: &mut Scope<Context>)
+                        -> Action<Self> {
+        unreachable!()
+    }
+
+    fn message_flushed(self, _output: MessageWriter, _scope: &mut Scope<Context>) -> Action<Self> {
+        unreachable!()
+    }
+
+    endpoint_timeouts!(Context);
+
+    fn timeout(self,
+               state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Context>)
+               -> Action<Self> {
+        panic!("timed out in {:?}", state)
+    }
+
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Context>) -> Action<Self> {
+        Action::Recv(self)
+    }
+
+    fn closed(self, _reason: CloseReason, scope: &mut Scope<Context>) {
+        let closed = {
+            let mut connections = scope.connections.borrow_mut();
+            connections.closed += 1;
+            connections.closed
+        };
+        if closed == scope.admitted {
+            scope.shutdown_loop();
+        }
+    }
+
+    fn rejected(_seed: &(),
+                reason: Rejection,
+                mut output: MessageWriter,
+                scope: &mut Scope<Context>) {
+        scope.connections.borrow_mut().rejections.push(reason);
+        let content = format!("{:?}{}", reason, " ".repeat(scope.padding));
+        output.write(&text_message(&content)).unwrap();
+    }
+}
+
+/// Connect `clients` before accepting them, the first `admitted` of them
+/// are expected to be admitted. The others send `sent` bytes, which aren't
+/// read by the server. Returns the reasons read from them, `None` for those
+/// which have been closed right away. The admitted ones are closed
+/// afterwards, even if reading fails, which stops the loop.
+fn serve(admission: Admission,
+         slab_capacity: usize,
+         padding: usize,
+         sent: usize,
+         clients: usize,
+         admitted: usize)
+         -> (Connections, Vec<Option<String>>) {
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut clients: Vec<_> =
+        (0..clients).map(|_| StdTcpStream::connect(address).unwrap()).collect();
+    for client in &mut clients[admitted..] {
+        client.write_all(&vec![0; sent]).unwrap();
+    }
+    let reader = thread::spawn(move || {
+        let mut clients = clients;
+        let mut reasons = Vec::new();
+        // The later connections are read first, so that the final messages
+        // sent to the earlier ones aren't flushed before they're accepted.
+        for mut client in clients.drain(admitted..).rev() {
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut bytes = Vec::new();
+            client.read_to_end(&mut bytes).unwrap();
+            if bytes.is_empty() {
+                reasons.push(None);
+                continue;
+            }
+            let message = serialize::read_message(&mut &bytes[..], ReaderOptions::new()).unwrap();
+            let content = message.get_root::<text::Reader>().unwrap();
+            reasons.push(Some(content.trim_end().to_string()));
+        }
+        reasons.reverse();
+        reasons
+    });
+
+    let mut config = LoopConfig::new();
+    config.slab_capacity(slab_capacity);
+    let connections = Rc::new(RefCell::new(Connections::default()));
+    let context = Context {
+        admitted: admitted,
+        padding: padding,
+        connections: connections.clone(),
+    };
+    let mut event_loop = Loop::new(&config).unwrap().instantiate(context);
+    event_loop.add_machine_with(|scope| {
+                  Acceptor::<Server, _>::new(listener, (), admission, scope)
+              })
+              .unwrap();
+    event_loop.run().unwrap();
+    let connections = Rc::try_unwrap(connections).ok().unwrap().into_inner();
+    (connections, reader.join().unwrap())
+}
+
+#[test]
+fn max_connections() {
+    let admission = Admission { max_connections: 2, ..Admission::default() };
+    let (connections, reasons) = serve(admission, 1024, 0, 0, 3, 2);
+    assert_eq!(connections.closed, 2);
+    assert_eq!(connections.rejections, [Rejection::TooManyConnections]);
+    assert_eq!(reasons, [Some("TooManyConnections".to_string())]);
+}
+
+#[test]
+fn max_connections_per_ip() {
+    let admission = Admission { max_connections_per_ip: 2, ..Admission::default() };
+    let (connections, reasons) = serve(admission, 1024, 0, 0, 3, 2);
+    let localhost = "127.0.0.1".parse().unwrap();
+    assert_eq!(connections.closed, 2);
+    assert_eq!(connections.rejections, [Rejection::TooManyConnectionsFrom(localhost)]);
+    assert_eq!(reasons, [Some("TooManyConnectionsFrom(127.0.0.1)".to_string())]);
+}
+
+#[test]
+fn rejected_after_sending() {
+    let admission = Admission { max_connections: 1, ..Admission::default() };
+    let (connections, reasons) = serve(admission, 1024, 0, 64 << 10, 2, 1);
+    assert_eq!(connections.closed, 1);
+    assert_eq!(connections.rejections, [Rejection::TooManyConnections]);
+    assert_eq!(reasons, [Some("TooManyConnections".to_string())]);
+}
+
+#[test]
+fn max_accept_rate() {
+    let admission = Admission { max_accept_rate: 2, ..Admission::default() };
+    let (connections, reasons) = serve(admission, 1024, 0, 0, 4, 2);
+    assert_eq!(connections.closed, 2);
+    assert!(connections.rejections.is_empty());
+    assert_eq!(reasons, [None, None]);
+}
+
+#[test]
+fn max_rejected() {
+    let admission = Admission {
+        max_connections: 1,
+        max_rejected: 1,
+        ..Admission::default()
+    };
+    let (connections, reasons) = serve(admission, 1024, 4 << 20, 0, 3, 1);
+    assert_eq!(connections.closed, 1);
+    assert_eq!(connections.rejections, [Rejection::TooManyConnections]);
+    assert_eq!(reasons, [Some("TooManyConnections".to_string()), None]);
+}
+
+#[test]
+fn loop_full() {
+    // The acceptor and a single connection.
+    let (connections, reasons) = serve(Admission::default(), 2, 0, 0, 3, 1);
+    assert_eq!(connections.closed, 1);
+    assert!(connections.rejections.is_empty());
+    assert_eq!(reasons, [None, None]);
+}
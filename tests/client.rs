@@ -0,0 +1,169 @@
+extern crate capnp;
+extern crate rotor;
+extern crate rotor_capnp;
+
+mod common;
+
+use std::time::Duration;
+
+use capnp::text;
+use rotor::Scope;
+use rotor_capnp::{Client, ClientAction, CloseReason, ConnectionState, Error, Harness,
+                  LoopbackSocket, MessageReader, RequestSeq, Requester, Requests};
+
+use common::{received, send, text_message, texts};
+
+/// Texts of the requests readable from the peer.
+fn requests(peer: &mut LoopbackSocket) -> Vec<String> {
+    texts(&received(peer))
+}
+
+#[derive(Default)]
+struct Context {
+    sent: Vec<RequestSeq>,
+    responses: Vec<(RequestSeq, String)>,
+    timeouts: Vec<RequestSeq>,
+    closed: Option<CloseReason>,
+}
+
+/// Sends the requests with their timeouts in milliseconds once connected.
+struct Sender(Vec<(&'static str, u64)>);
+
+impl Client for Sender {
+    type Context = Context;
+    type Socket = LoopbackSocket;
+    type Seed = Vec<(&'static str, u64)>;
+
+    fn create(seed: Self::Seed,
+              _sock: &mut LoopbackSocket,
+              _scope: &mut Scope<Context>)
+              -> ClientAction<Self> {
+        ClientAction::Continue(Sender(seed))
+    }
+
+    fn connected(mut self,
+                 requests: &mut Requests,
+                 scope: &mut Scope<Context>)
+                 -> ClientAction<Self> {
+        for (content, timeout) in self.0.drain(..) {
+            let seq = requests.send(&text_message(content), Duration::from_millis(timeout))
+                .unwrap();
+            scope.sent.push(seq);
+        }
+        ClientAction::Continue(self)
+    }
+
+    fn response_received(self,
+                         seq: RequestSeq,
+                         response: &MessageReader,
+                         requests: &mut Requests,
+                         scope: &mut Scope<Context>)
+                         -> ClientAction<Self> {
+        let content = response.get_root::<text::Reader>().unwrap().to_string();
+        scope.responses.push((seq, content));
+        if requests.pending() > 0 {
+            ClientAction::Continue(self)
+        } else {
+            ClientAction::Close(self)
+        }
+    }
+
+    fn request_timeout(&mut self, seq: RequestSeq, scope: &mut Scope<Context>) {
+        scope.timeouts.push(seq);
+    }
+
+    fn idle_timeout(&self, _scope: &mut Scope<Context>) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn send_timeout(&self, _scope: &mut Scope<Context>) -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn wakeup(self, _requests: &mut Requests, _scope: &mut Scope<Context>) -> ClientAction<Self> {
+        ClientAction::Continue(self)
+    }
+
+    fn closed(self, reason: CloseReason, scope: &mut Scope<Context>) {
+        scope.closed = Some(reason);
+    }
+}
+
+fn connect(requests: Vec<(&'static str, u64)>) -> (Harness<Requester<Sender>>, LoopbackSocket) {
+    let (sock, peer) = LoopbackSocket::pair();
+    let harness = Harness::new(sock, requests, Context::default()).unwrap();
+    (harness, peer)
+}
+
+#[test]
+fn responses_in_order() {
+    let (mut harness, mut peer) = connect(vec![("a", 1000), ("b", 1000), ("c", 1000)]);
+    assert_eq!(requests(&mut peer), ["a", "b", "c"]);
+    let sent = harness.context().sent.clone();
+    assert_eq!(sent.len(), 3);
+
+    send(&mut peer, "A");
+    send(&mut peer, "B");
+    assert!(harness.poll());
+    send(&mut peer, "C");
+    assert!(!harness.poll());
+    let responses: Vec<_> = sent.into_iter().zip(vec!["A", "B", "C"]).collect();
+    assert_eq!(harness.context()
+                   .responses
+                   .iter()
+                   .map(|&(seq, ref content)| (seq, &content[..]))
+                   .collect::<Vec<_>>(),
+               responses);
+    match harness.context().closed.take() {
+        Some(CloseReason::Local) => {}
+        reason => panic!("unexpected close: {:?}", reason),
+    }
+}
+
+#[test]
+fn request_timeout() {
+    let (mut harness, mut peer) = connect(vec![("a", 100), ("b", 200)]);
+    assert_eq!(requests(&mut peer), ["a", "b"]);
+    let sent = harness.context().sent.clone();
+
+    assert!(harness.advance(Duration::from_millis(99)));
+    assert!(harness.context().timeouts.is_empty());
+    // The later responses can't be matched, the connection is closed
+    // along with the request still pending.
+    assert!(!harness.advance(Duration::from_millis(1)));
+    assert_eq!(harness.context().timeouts, [sent[0]]);
+    assert!(harness.context().responses.is_empty());
+    match harness.context().closed.take() {
+        Some(CloseReason::Timeout(ConnectionState::Receiving)) => {}
+        reason => panic!("unexpected close: {:?}", reason),
+    }
+}
+
+#[test]
+fn expired_requests() {
+    let (mut harness, mut peer) = connect(vec![("a", 100), ("b", 100), ("c", 200)]);
+    assert_eq!(requests(&mut peer), ["a", "b", "c"]);
+    let sent = harness.context().sent.clone();
+
+    // A response arriving late is never taken for the next one.
+    send(&mut peer, "A");
+    assert!(harness.poll());
+    assert!(!harness.advance(Duration::from_millis(100)));
+    assert_eq!(harness.context().responses, [(sent[0], "A".to_string())]);
+    assert_eq!(harness.context().timeouts, [sent[1]]);
+    match harness.context().closed.take() {
+        Some(CloseReason::Timeout(ConnectionState::Receiving)) => {}
+        reason => panic!("unexpected close: {:?}", reason),
+    }
+}
+
+#[test]
+fn unexpected_message() {
+    let (mut harness, mut peer) = connect(vec![]);
+    send(&mut peer, "A");
+    assert!(!harness.poll());
+    match harness.context().closed.take() {
+        Some(CloseReason::Error(Error::UnexpectedMessage)) => {}
+        reason => panic!("unexpected close: {:?}", reason),
+    }
+}
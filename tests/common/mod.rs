@@ -0,0 +1,64 @@
+//! Helpers shared by the integration tests, not all of them use every one.
+
+#![allow(dead_code)]
+
+use std::io::{self, Read};
+
+use capnp::message::{Builder, HeapAllocator, ReaderOptions};
+use capnp::{serialize, text};
+use rotor_capnp::LoopbackSocket;
+
+/// Timeouts of an `Endpoint` with the context `$context`, which only expire
+/// if a test waits for them.
+#[allow(unused_macros)]
+macro_rules! endpoint_timeouts {
+    ($context:ty) => {
+        fn recv_timeout(&self, _scope: &mut ::rotor::Scope<$context>) -> ::std::time::Duration {
+            ::std::time::Duration::from_secs(5)
+        }
+
+        fn send_timeout(&self, _scope: &mut ::rotor::Scope<$context>) -> ::std::time::Duration {
+            ::std::time::Duration::from_secs(5)
+        }
+    }
+}
+
+pub fn text_message(content: &str) -> Builder<HeapAllocator> {
+    let mut builder = Builder::new_default();
+    builder.set_root::<text::Builder, text::Reader>(content).unwrap();
+    builder
+}
+
+/// Text of `len` letters, for messages larger than the socket buffers.
+pub fn large_content(len: usize) -> String {
+    (0..len).map(|i| (b'a' + (i % 26) as u8) as char).collect()
+}
+
+/// Send a text message from the peer.
+pub fn send(peer: &mut LoopbackSocket, content: &str) {
+    serialize::write_message(peer, &text_message(content)).unwrap();
+}
+
+/// Everything readable from the peer.
+pub fn received(peer: &mut LoopbackSocket) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        match peer.read(&mut buf) {
+            Ok(0) => return bytes,
+            Ok(len) => bytes.extend(&buf[..len]),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return bytes,
+            Err(err) => panic!("reading failed: {}", err),
+        }
+    }
+}
+
+/// Texts of the messages in the `bytes`.
+pub fn texts(mut bytes: &[u8]) -> Vec<String> {
+    let mut texts = Vec::new();
+    while !bytes.is_empty() {
+        let message = serialize::read_message(&mut bytes, ReaderOptions::new()).unwrap();
+        texts.push(message.get_root::<text::Reader>().unwrap().to_string());
+    }
+    texts
+}
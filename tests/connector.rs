@@ -0,0 +1,376 @@
+#![cfg(unix)]
+
+extern crate capnp;
+extern crate rotor;
+extern crate rotor_capnp;
+extern crate rotor_stream;
+
+#[macro_use]
+mod common;
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use rotor::{Compose2, Config as LoopConfig, EventSet, Evented, Loop, Machine, PollOpt, Response,
+            Scope, Void};
+use rotor::mio::{Selector, Token};
+use rotor::mio::unix::{pipe, PipeReader, PipeWriter};
+use rotor::void::unreachable;
+use rotor_capnp::{Action, CloseReason, ConnectionState, Connector, Endpoint, Error, HalfClose,
+                  Handshake, MessageReader, MessageWriter, Reconnect, Resolve};
+use rotor_stream::{ActiveStream, SocketError};
+
+/// How a peer responds to connecting.
+#[derive(Clone, Copy, Debug)]
+enum Peer {
+    Refusing,
+    Silent,
+    Answering,
+}
+
+struct Peers(Vec<Peer>);
+
+impl Resolve for Peers {
+    type Address = Peer;
+
+    fn resolve(&self) -> io::Result<Vec<Peer>> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Socket of a pipe, connected once it's writable. The read end is
+/// registered unless the peer is answering, so it never becomes writable.
+struct PipeSocket {
+    reader: PipeReader,
+    writer: PipeWriter,
+    peer: Peer,
+}
+
+impl PipeSocket {
+    fn evented(&self) -> &Evented {
+        match self.peer {
+            Peer::Answering => &self.writer,
+            _ => &self.reader,
+        }
+    }
+}
+
+impl ActiveStream for PipeSocket {
+    type Address = Peer;
+
+    fn connect(peer: &Peer) -> io::Result<PipeSocket> {
+        if let Peer::Refusing = *peer {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"));
+        }
+        let (reader, writer) = try!(pipe());
+        Ok(PipeSocket {
+            reader: reader,
+            writer: writer,
+            peer: *peer,
+        })
+    }
+}
+
+/// The peer closes its side as soon as the connection is established.
+impl Read for PipeSocket {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Write for PipeSocket {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::WouldBlock, "no room to write"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for PipeSocket {
+    fn register(&self,
+                selector: &mut Selector,
+                token: Token,
+                interest: EventSet,
+                opts: PollOpt)
+                -> io::Result<()> {
+        self.evented().register(selector, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  selector: &mut Selector,
+                  token: Token,
+                  interest: EventSet,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        self.evented().reregister(selector, token, interest, opts)
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        self.evented().deregister(selector)
+    }
+}
+
+impl SocketError for PipeSocket {
+    fn take_socket_error(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl HalfClose for PipeSocket {
+    fn close_write(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Handshake for PipeSocket {
+    fn handshake(&mut self) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+/// How the endpoint closes a connection once it's established.
+#[derive(Clone, Copy, Debug)]
+enum Closing {
+    Close,
+    FlushAndClose,
+    /// Shut down the write side, then wait for the peer to close its side.
+    CloseOutput,
+    /// Wait for the peer to close the connection.
+    Idle,
+}
+
+#[derive(Default)]
+struct Attempts {
+    failures: Vec<(Error, Duration)>,
+    connected: usize,
+    closed: Vec<CloseReason>,
+}
+
+struct Context {
+    /// Number of failed attempts after which the loop is shut down.
+    limit: usize,
+    closing: Closing,
+    attempts: Rc<RefCell<Attempts>>,
+}
+
+/// Closes every connection as told by the `Closing` of the context.
+struct Dialer;
+
+impl Endpoint for Dialer {
+    type Context = Context;
+    type Socket = PipeSocket;
+    type Seed = ();
+
+    fn create(_seed: (), _sock: &mut PipeSocket, scope: &mut Scope<Context>) -> Action<Self> {
+        scope.attempts.borrow_mut().connected += 1;
+        match scope.closing {
+            Closing::Close => Action::Close(Dialer),
+            Closing::FlushAndClose => Action::FlushAndClose(Dialer, None),
+            Closing::CloseOutput => Action::CloseOutput(Dialer),
+            Closing::Idle => Action::Idle(Dialer),
+        }
+    }
+
+    fn message_received(self,
+                        _message: &MessageReader,
+                        _output: MessageWriter,
+                        _scope: &mut Scope<Context>)
+                        -> Action<Self> {
+        unreachable!()
+    }
+
+    fn message_flushed(self, _output: MessageWriter, _scope: &mut Scope<Context>) -> Action<Self> {
+        unreachable!()
+    }
+
+    endpoint_timeouts!(Context);
+
+    fn timeout(self,
+               _state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Context>)
+               -> Action<Self> {
+        unreachable!()
+    }
+
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Context>) -> Action<Self> {
+        unreachable!()
+    }
+
+    fn closed(self, reason: CloseReason, scope: &mut Scope<Context>) {
+        scope.attempts.borrow_mut().closed.push(reason);
+    }
+
+    fn connect_failed(_seed: &(), err: Error, delay: Duration, scope: &mut Scope<Context>) {
+        let failed = {
+            let mut attempts = scope.attempts.borrow_mut();
+            attempts.failures.push((err, delay));
+            attempts.failures.len()
+        };
+        if failed == scope.limit {
+            scope.shutdown_loop();
+        }
+    }
+}
+
+/// Shuts down the loop once its timeout expires.
+struct Deadline;
+
+impl Machine for Deadline {
+    type Context = Context;
+    type Seed = Void;
+
+    fn create(seed: Void, _scope: &mut Scope<Context>) -> Response<Self, Void> {
+        unreachable(seed)
+    }
+
+    fn ready(self, _events: EventSet, _scope: &mut Scope<Context>) -> Response<Self, Void> {
+        unreachable!()
+    }
+
+    fn spawned(self, _scope: &mut Scope<Context>) -> Response<Self, Void> {
+        unreachable!()
+    }
+
+    fn timeout(self, scope: &mut Scope<Context>) -> Response<Self, Void> {
+        scope.shutdown_loop();
+        Response::done()
+    }
+
+    fn wakeup(self, _scope: &mut Scope<Context>) -> Response<Self, Void> {
+        unreachable!()
+    }
+}
+
+/// Connect to the `peers` until `limit` attempts have failed, or the
+/// `timeout` expires.
+fn connect(peers: Vec<Peer>,
+           reconnect: Reconnect,
+           closing: Closing,
+           limit: usize,
+           timeout: Duration)
+           -> (Attempts, Duration) {
+    let mut config = LoopConfig::new();
+    config.mio().timer_tick_ms(1);
+    let attempts = Rc::new(RefCell::new(Attempts::default()));
+    let context = Context {
+        limit: limit,
+        closing: closing,
+        attempts: attempts.clone(),
+    };
+    let mut event_loop = Loop::new(&config).unwrap().instantiate(context);
+    event_loop.add_machine_with(|scope| {
+                  Connector::<Dialer, _>::new(scope, Peers(peers), (), reconnect)
+                      .wrap(Compose2::A)
+              })
+              .unwrap();
+    event_loop.add_machine_with(|scope| {
+                  Response::ok(Compose2::B(Deadline)).deadline(scope.now() + timeout)
+              })
+              .unwrap();
+    let started = Instant::now();
+    event_loop.run().unwrap();
+    let elapsed = started.elapsed();
+    let attempts = Rc::try_unwrap(attempts).ok().unwrap().into_inner();
+    (attempts, elapsed)
+}
+
+fn reconnect() -> Reconnect {
+    Reconnect {
+        connect_timeout: Duration::from_millis(30),
+        min_delay: Duration::from_millis(20),
+        max_delay: Duration::from_millis(40),
+    }
+}
+
+#[test]
+fn connect_timeout_with_backoff() {
+    let (attempts, elapsed) =
+        connect(vec![Peer::Silent], reconnect(), Closing::Close, 4, Duration::from_secs(5));
+    assert_eq!(attempts.failures.len(), 4);
+    assert_eq!(attempts.connected, 0);
+    for failure in &attempts.failures {
+        match failure.0 {
+            Error::ConnectTimeout => {}
+            ref err => panic!("unexpected error: {:?}", err),
+        }
+    }
+    // Half of the backoff at least, doubled up to the maximum delay.
+    let delays: Vec<_> = attempts.failures.iter().map(|&(_, delay)| delay).collect();
+    let ms = Duration::from_millis;
+    assert!(ms(10) <= delays[0] && delays[0] <= ms(20), "{:?}", delays);
+    for &delay in &delays[1..] {
+        assert!(ms(20) <= delay && delay <= ms(40), "{:?}", delays);
+    }
+    // Every attempt waits for the connect timeout.
+    assert!(elapsed >= ms(4 * 30) + delays[0] + delays[1] + delays[2],
+            "{:?} {:?}",
+            elapsed,
+            delays);
+}
+
+#[test]
+fn fallback_to_next_address() {
+    let peers = vec![Peer::Refusing, Peer::Silent, Peer::Answering];
+    let (attempts, _) = connect(peers, reconnect(), Closing::Close, 0, Duration::from_millis(300));
+    assert_eq!(attempts.failures.len(), 2);
+    match attempts.failures[0] {
+        (Error::Connect(ref err), delay) if err.kind() == io::ErrorKind::ConnectionRefused => {
+            assert_eq!(delay, Duration::from_secs(0));
+        }
+        ref failure => panic!("unexpected failure: {:?}", failure),
+    }
+    match attempts.failures[1] {
+        (Error::ConnectTimeout, delay) => assert_eq!(delay, Duration::from_secs(0)),
+        ref failure => panic!("unexpected failure: {:?}", failure),
+    }
+    // Closing the connection locally stops reconnecting.
+    assert_eq!(attempts.connected, 1);
+    match attempts.closed[..] {
+        [CloseReason::Local] => {}
+        ref closed => panic!("unexpected close: {:?}", closed),
+    }
+}
+
+/// Establish connections closed as told by `closing` for 200 milliseconds.
+fn closed_by(closing: Closing) -> Attempts {
+    let (attempts, _) =
+        connect(vec![Peer::Answering], reconnect(), closing, 0, Duration::from_millis(200));
+    assert!(attempts.failures.is_empty());
+    assert_eq!(attempts.connected, attempts.closed.len());
+    attempts
+}
+
+#[test]
+fn flush_and_close_stops_reconnecting() {
+    let attempts = closed_by(Closing::FlushAndClose);
+    match attempts.closed[..] {
+        [CloseReason::Shutdown { flushed: true }] => {}
+        ref closed => panic!("unexpected close: {:?}", closed),
+    }
+}
+
+#[test]
+fn close_output_stops_reconnecting() {
+    let attempts = closed_by(Closing::CloseOutput);
+    match attempts.closed[..] {
+        [CloseReason::PeerClosed] => {}
+        ref closed => panic!("unexpected close: {:?}", closed),
+    }
+}
+
+#[test]
+fn peer_closed_reconnects() {
+    let attempts = closed_by(Closing::Idle);
+    assert!(attempts.connected >= 2, "{:?}", attempts.closed);
+    for reason in &attempts.closed {
+        match *reason {
+            CloseReason::PeerClosed => {}
+            ref reason => panic!("unexpected close: {:?}", reason),
+        }
+    }
+}
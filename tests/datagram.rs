@@ -0,0 +1,228 @@
+extern crate capnp;
+extern crate rotor;
+extern crate rotor_capnp;
+
+mod common;
+
+use std::net::{SocketAddr, UdpSocket as StdUdpSocket};
+use std::time::Duration;
+
+use capnp::text;
+use rotor::{Compose2, Config as LoopConfig, Loop, Scope};
+use rotor::mio::udp::UdpSocket;
+use rotor_capnp::{Datagram, DatagramAction, DatagramEndpoint, DatagramWriter, Error,
+                  MessageReader, WireFormat};
+
+use common::text_message;
+
+fn bind() -> (UdpSocket, SocketAddr) {
+    let sock = UdpSocket::bound(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let address = sock.local_addr().unwrap();
+    (sock, address)
+}
+
+struct Context {
+    invalid: usize,
+}
+
+/// Echoes text messages back to their source.
+struct Echo(WireFormat);
+
+impl DatagramEndpoint for Echo {
+    type Context = Context;
+    type Seed = WireFormat;
+
+    fn create(format: WireFormat,
+              _sock: &mut UdpSocket,
+              _scope: &mut Scope<Context>)
+              -> DatagramAction<Self> {
+        DatagramAction::Idle(Echo(format))
+    }
+
+    fn message_received(self,
+                        message: &MessageReader,
+                        source: SocketAddr,
+                        mut output: DatagramWriter,
+                        _scope: &mut Scope<Context>)
+                        -> DatagramAction<Self> {
+        let content = message.get_root::<text::Reader>().unwrap();
+        output.write(&text_message(content), source).unwrap();
+        DatagramAction::Idle(self)
+    }
+
+    fn invalid_datagram(&mut self, _source: SocketAddr, err: Error, scope: &mut Scope<Context>) {
+        match err {
+            Error::InvalidDatagram { size: 2 } => scope.invalid += 1,
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    fn wire_format(&self, _scope: &mut Scope<Context>) -> WireFormat {
+        self.0
+    }
+
+    fn timeout(self, _output: DatagramWriter, _scope: &mut Scope<Context>) -> DatagramAction<Self> {
+        DatagramAction::Idle(self)
+    }
+
+    fn wakeup(self, _output: DatagramWriter, _scope: &mut Scope<Context>) -> DatagramAction<Self> {
+        DatagramAction::Idle(self)
+    }
+
+    fn closed(self, _scope: &mut Scope<Context>) {}
+}
+
+/// Sends messages to the echo server and closes the loop after the replies.
+struct Hello {
+    server: SocketAddr,
+    format: WireFormat,
+    replies: usize,
+}
+
+impl DatagramEndpoint for Hello {
+    type Context = Context;
+    type Seed = (SocketAddr, WireFormat);
+
+    fn create((server, format): (SocketAddr, WireFormat),
+              _sock: &mut UdpSocket,
+              _scope: &mut Scope<Context>)
+              -> DatagramAction<Self> {
+        let hello = Hello {
+            server: server,
+            format: format,
+            replies: 0,
+        };
+        // `timeout` is called right away with the writer.
+        DatagramAction::Wait(hello, Duration::from_secs(0))
+    }
+
+    fn message_received(mut self,
+                        message: &MessageReader,
+                        source: SocketAddr,
+                        _output: DatagramWriter,
+                        _scope: &mut Scope<Context>)
+                        -> DatagramAction<Self> {
+        assert_eq!(source, self.server);
+        let content = message.get_root::<text::Reader>().unwrap();
+        assert_eq!(content, format!("hello {}", self.replies));
+        self.replies += 1;
+        if self.replies == 3 {
+            DatagramAction::Close(self)
+        } else {
+            DatagramAction::Wait(self, Duration::from_secs(5))
+        }
+    }
+
+    fn wire_format(&self, _scope: &mut Scope<Context>) -> WireFormat {
+        self.format
+    }
+
+    fn timeout(self,
+               mut output: DatagramWriter,
+               _scope: &mut Scope<Context>)
+               -> DatagramAction<Self> {
+        assert_eq!(self.replies, 0, "timed out waiting for replies");
+        for i in 0..3 {
+            output.write(&text_message(&format!("hello {}", i)), self.server).unwrap();
+        }
+        DatagramAction::Wait(self, Duration::from_secs(5))
+    }
+
+    fn wakeup(self, _output: DatagramWriter, _scope: &mut Scope<Context>) -> DatagramAction<Self> {
+        DatagramAction::Idle(self)
+    }
+
+    fn closed(self, scope: &mut Scope<Context>) {
+        assert_eq!(self.replies, 3);
+        assert_eq!(scope.invalid, 1);
+        scope.shutdown_loop();
+    }
+}
+
+/// Writes a message too large for a single segment.
+struct Segmented;
+
+impl DatagramEndpoint for Segmented {
+    type Context = Context;
+    type Seed = ();
+
+    fn create(_seed: (),
+              _sock: &mut UdpSocket,
+              _scope: &mut Scope<Context>)
+              -> DatagramAction<Self> {
+        DatagramAction::Wait(Segmented, Duration::from_secs(0))
+    }
+
+    fn message_received(self,
+                        _message: &MessageReader,
+                        _source: SocketAddr,
+                        _output: DatagramWriter,
+                        _scope: &mut Scope<Context>)
+                        -> DatagramAction<Self> {
+        unreachable!()
+    }
+
+    fn timeout(self,
+               mut output: DatagramWriter,
+               scope: &mut Scope<Context>)
+               -> DatagramAction<Self> {
+        let content: String = (0..10000).map(|_| 'a').collect();
+        match output.write(&text_message(&content), "127.0.0.1:9".parse().unwrap()) {
+            Err(Error::OutgoingMessageSegmented { count: 2 }) => scope.invalid += 1,
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(output.pending(), 0);
+        DatagramAction::Close(self)
+    }
+
+    fn wakeup(self, _output: DatagramWriter, _scope: &mut Scope<Context>) -> DatagramAction<Self> {
+        DatagramAction::Idle(self)
+    }
+
+    fn closed(self, scope: &mut Scope<Context>) {
+        assert_eq!(scope.invalid, 1);
+        scope.shutdown_loop();
+    }
+}
+
+fn echo(format: WireFormat) {
+    let (server, address) = bind();
+    let (client, _) = bind();
+    // The server drops a truncated message, either packed or not, and
+    // carries on.
+    let garbage = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    garbage.send_to(&[0x10, 1], address).unwrap();
+
+    let mut event_loop = Loop::new(&LoopConfig::new())
+        .unwrap()
+        .instantiate(Context { invalid: 0 });
+    event_loop.add_machine_with(|scope| {
+                  Datagram::<Echo>::new(server, format, scope).wrap(Compose2::A)
+              })
+              .unwrap();
+    event_loop.add_machine_with(|scope| {
+                  Datagram::<Hello>::new(client, (address, format), scope).wrap(Compose2::B)
+              })
+              .unwrap();
+    event_loop.run().unwrap();
+}
+
+#[test]
+fn echo_unpacked() {
+    echo(WireFormat::Unpacked);
+}
+
+#[test]
+fn echo_packed() {
+    echo(WireFormat::Packed);
+}
+
+#[test]
+fn segmented_message() {
+    let (sock, _) = bind();
+    let mut event_loop = Loop::new(&LoopConfig::new())
+        .unwrap()
+        .instantiate(Context { invalid: 0 });
+    event_loop.add_machine_with(|scope| Datagram::<Segmented>::new(sock, (), scope)).unwrap();
+    event_loop.run().unwrap();
+}
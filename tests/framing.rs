@@ -0,0 +1,180 @@
+extern crate capnp;
+extern crate rotor;
+extern crate rotor_capnp;
+
+#[macro_use]
+mod common;
+
+use std::io::Write;
+
+use rotor::Scope;
+use rotor_capnp::{Action, CloseReason, ConnectionState, Endpoint, Error, FramingLimits,
+                  HalfClose, Harness, LoopbackSocket, MessageReader, MessageWriter, WireFormat};
+
+struct Context {
+    format: WireFormat,
+    limits: FramingLimits,
+    received: usize,
+    closed: Option<CloseReason>,
+}
+
+/// Receives messages and records why the connection is closed.
+struct Receiver;
+
+impl Endpoint for Receiver {
+    type Context = Context;
+    type Socket = LoopbackSocket;
+    type Seed = ();
+
+    fn create(_seed: (), _sock: &mut LoopbackSocket, _scope: &mut Scope<Context>) -> Action<Self> {
+        Action::Idle(Receiver)
+    }
+
+    fn message_received(self,
+                        _message: &MessageReader,
+                        _output: MessageWriter,
+                        scope: &mut Scope<Context>)
+                        -> Action<Self> {
+        scope.received += 1;
+        Action::Idle(self)
+    }
+
+    fn message_flushed(self, _output: MessageWriter, _scope: &mut Scope<Context>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    fn framing_limits(&self, scope: &mut Scope<Context>) -> FramingLimits {
+        scope.limits
+    }
+
+    fn wire_format(&self, scope: &mut Scope<Context>) -> WireFormat {
+        scope.format
+    }
+
+    endpoint_timeouts!(Context);
+
+    fn timeout(self,
+               _state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Context>)
+               -> Action<Self> {
+        Action::Close(self)
+    }
+
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Context>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    fn closed(self, reason: CloseReason, scope: &mut Scope<Context>) {
+        scope.closed = Some(reason);
+    }
+}
+
+/// Receive the `bytes`, then close the write side of the peer if `close`,
+/// and return why the connection has been closed.
+fn receive(format: WireFormat, limits: FramingLimits, bytes: &[u8], close: bool) -> CloseReason {
+    let (sock, mut peer) = LoopbackSocket::pair();
+    let context = Context {
+        format: format,
+        limits: limits,
+        received: 0,
+        closed: None,
+    };
+    let mut harness = Harness::<Receiver>::new(sock, (), context).unwrap();
+    peer.write_all(bytes).unwrap();
+    if close {
+        peer.close_write().unwrap();
+    }
+    assert!(!harness.poll());
+    assert_eq!(harness.context().received, 0);
+    harness.context().closed.take().unwrap()
+}
+
+fn limits() -> FramingLimits {
+    FramingLimits {
+        max_segments: 2,
+        max_segment_size: 16,
+        max_message_size: 32,
+        ..FramingLimits::default()
+    }
+}
+
+#[test]
+fn too_many_segments() {
+    match receive(WireFormat::Unpacked, limits(), &[2, 0, 0, 0], false) {
+        CloseReason::Error(Error::TooManySegments { count: 3, limit: 2 }) => {}
+        reason => panic!("unexpected close: {:?}", reason),
+    }
+}
+
+#[test]
+fn zero_segments() {
+    match receive(WireFormat::Unpacked, limits(), &[0xff; 4], false) {
+        CloseReason::Error(Error::ZeroSegments) => {}
+        reason => panic!("unexpected close: {:?}", reason),
+    }
+}
+
+#[test]
+fn segment_too_large() {
+    match receive(WireFormat::Unpacked, limits(), &[0, 0, 0, 0, 3, 0, 0, 0], false) {
+        CloseReason::Error(Error::SegmentTooLarge { size: 24, limit: 16 }) => {}
+        reason => panic!("unexpected close: {:?}", reason),
+    }
+}
+
+#[test]
+fn message_too_large() {
+    // 16 bytes of the segment table and 24 bytes of the segments.
+    let table = [1, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0];
+    match receive(WireFormat::Unpacked, limits(), &table, false) {
+        CloseReason::Error(Error::MessageTooLarge { size: 40, limit: 32 }) => {}
+        reason => panic!("unexpected close: {:?}", reason),
+    }
+}
+
+#[test]
+fn unpadded_message_too_large() {
+    // 12 bytes of the segment table without the padding and 24 bytes of the
+    // segments.
+    let table = [1, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0];
+    match receive(WireFormat::UnpaddedUnpacked, limits(), &table, false) {
+        CloseReason::Error(Error::MessageTooLarge { size: 36, limit: 32 }) => {}
+        reason => panic!("unexpected close: {:?}", reason),
+    }
+}
+
+#[test]
+fn packed_message_too_large() {
+    // The segment count word of 2 segments, the first of 2 words, and the
+    // rest of the segment table with the second of 1 word.
+    match receive(WireFormat::Packed, limits(), &[0x11, 1, 2, 0x01, 1], false) {
+        CloseReason::Error(Error::MessageTooLarge { size: 40, limit: 32 }) => {}
+        reason => panic!("unexpected close: {:?}", reason),
+    }
+}
+
+#[test]
+fn invalid_packing() {
+    // A segment of 1 word followed by a run of a zero word more.
+    match receive(WireFormat::Packed, limits(), &[0x10, 1, 0x00, 1], false) {
+        CloseReason::Error(Error::InvalidPacking) => {}
+        reason => panic!("unexpected close: {:?}", reason),
+    }
+}
+
+#[test]
+fn peer_closed_mid_message() {
+    match receive(WireFormat::Unpacked, limits(), &[0, 0, 0, 0, 1, 0, 0, 0, 42], true) {
+        CloseReason::Error(Error::PeerClosedMidMessage) => {}
+        reason => panic!("unexpected close: {:?}", reason),
+    }
+}
+
+#[test]
+fn peer_closed_mid_packed_message() {
+    match receive(WireFormat::Packed, limits(), &[0x10, 1, 0x01], true) {
+        CloseReason::Error(Error::PeerClosedMidMessage) => {}
+        reason => panic!("unexpected close: {:?}", reason),
+    }
+}
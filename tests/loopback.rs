@@ -0,0 +1,838 @@
+extern crate capnp;
+extern crate rotor;
+extern crate rotor_capnp;
+
+#[macro_use]
+mod common;
+
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use capnp::message::{ReaderOptions, ReaderSegments};
+use capnp::{serialize, serialize_packed, text, Word};
+use rotor::Scope;
+use rotor_capnp::{into_owned, Action, CloseReason, ConnectionState, Endpoint, Error, HalfClose,
+                  Harness, LoopbackSocket, MessageReader, MessageWriter, OutputLimits,
+                  OwnedMessage, SegmentPool, WireFormat};
+
+use common::{received, send, text_message, texts};
+
+fn text(mut bytes: &[u8]) -> String {
+    let message = serialize::read_message(&mut bytes, ReaderOptions::new()).unwrap();
+    message.get_root::<text::Reader>().unwrap().to_string()
+}
+
+#[derive(Default)]
+struct Context {
+    received: usize,
+    flushed: usize,
+    closed: Option<String>,
+    /// Interval of checking the output, the default one if not set.
+    output_check_interval: Option<Duration>,
+}
+
+/// Echoes text messages back.
+struct Echo;
+
+impl Endpoint for Echo {
+    type Context = Context;
+    type Socket = LoopbackSocket;
+    type Seed = ();
+
+    fn create(_seed: (), _sock: &mut LoopbackSocket, _scope: &mut Scope<Context>) -> Action<Self> {
+        Action::Idle(Echo)
+    }
+
+    fn message_received(self,
+                        message: &MessageReader,
+                        mut output: MessageWriter,
+                        scope: &mut Scope<Context>)
+                        -> Action<Self> {
+        scope.received += 1;
+        let content = message.get_root::<text::Reader>().unwrap();
+        output.write(&text_message(content)).unwrap();
+        Action::Send(self)
+    }
+
+    fn message_flushed(self, _output: MessageWriter, scope: &mut Scope<Context>) -> Action<Self> {
+        scope.flushed += 1;
+        Action::Idle(self)
+    }
+
+    endpoint_timeouts!(Context);
+
+    fn output_check_interval(&self, scope: &mut Scope<Context>) -> Duration {
+        scope.output_check_interval.unwrap_or(Duration::from_millis(10))
+    }
+
+    fn timeout(self,
+               _state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Context>)
+               -> Action<Self> {
+        Action::Close(self)
+    }
+
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Context>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    fn closed(self, reason: CloseReason, scope: &mut Scope<Context>) {
+        scope.closed = Some(format!("{:?}", reason));
+    }
+}
+
+fn echo() -> (Harness<Echo>, LoopbackSocket) {
+    let (sock, peer) = LoopbackSocket::pair();
+    let harness = Harness::new(sock, (), Context::default()).unwrap();
+    (harness, peer)
+}
+
+#[test]
+fn echo_in_delayed_chunks() {
+    let (sock, mut peer) = LoopbackSocket::pair();
+    sock.set_chunk_size(5);
+    peer.set_chunk_size(3);
+    peer.set_delay(Duration::from_millis(2));
+    let mut harness = Harness::<Echo>::new(sock, (), Context::default()).unwrap();
+
+    send(&mut peer, "hello");
+    assert!(harness.poll());
+    assert_eq!(harness.context().received, 0);
+    // The segment table and the segment are written separately, so the
+    // message arrives in 3 + 6 chunks, 2 milliseconds apart.
+    assert!(harness.advance(Duration::from_millis(17)));
+    assert_eq!(harness.context().received, 0);
+    assert!(harness.advance(Duration::from_millis(1)));
+    assert_eq!(harness.context().received, 1);
+    assert_eq!(text(&received(&mut peer)), "hello");
+}
+
+#[test]
+fn recv_timeout_in_virtual_time() {
+    let (mut harness, mut peer) = echo();
+    peer.write_all(&[0, 0, 0, 0]).unwrap();
+    assert!(harness.advance(Duration::from_millis(4999)));
+    assert!(harness.run(Duration::from_millis(1)));
+    assert_eq!(harness.elapsed(), Duration::from_secs(5));
+    assert_eq!(harness.context().closed.as_ref().unwrap(),
+               &format!("{:?}", CloseReason::Timeout(ConnectionState::Receiving)));
+}
+
+#[test]
+fn injected_read_error() {
+    let (mut harness, peer) = echo();
+    peer.fail_peer_read(io::Error::new(io::ErrorKind::ConnectionAborted, "injected"));
+    assert!(!harness.poll());
+    let closed = harness.context().closed.take().unwrap();
+    assert!(closed.contains("injected"), "unexpected close: {}", closed);
+}
+
+#[test]
+fn peer_closed() {
+    let (mut harness, mut peer) = echo();
+    send(&mut peer, "bye");
+    peer.close_write().unwrap();
+    assert!(!harness.poll());
+    assert_eq!(harness.context().received, 1);
+    assert_eq!(harness.context().closed.as_ref().unwrap(),
+               &format!("{:?}", CloseReason::PeerClosed));
+    assert_eq!(text(&received(&mut peer)), "bye");
+}
+
+#[test]
+fn output_limited_by_peer() {
+    let (sock, mut peer) = LoopbackSocket::pair();
+    sock.set_capacity(64);
+    let mut harness = Harness::<Echo>::new(sock, (), Context::default()).unwrap();
+    let content: String = (0..1000).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+
+    send(&mut peer, &content);
+    assert!(harness.poll());
+    let mut bytes = Vec::new();
+    while bytes.len() < 1024 {
+        let chunk = received(&mut peer);
+        assert!(chunk.len() <= 64);
+        bytes.extend(chunk);
+        // Reading makes room for the endpoint to write.
+        assert!(harness.poll());
+    }
+    assert_eq!(text(&bytes), content);
+
+    peer.fail_peer_write(io::Error::new(io::ErrorKind::ConnectionAborted, "injected"));
+    send(&mut peer, "again");
+    assert!(!harness.poll());
+    let closed = harness.context().closed.take().unwrap();
+    assert!(closed.contains("injected"), "unexpected close: {}", closed);
+}
+
+#[test]
+fn flushed_without_delay() {
+    let (mut harness, mut peer) = echo();
+    send(&mut peer, "hello");
+    assert!(harness.poll());
+    assert_eq!(harness.context().flushed, 1);
+    assert_eq!(harness.elapsed(), Duration::from_millis(0));
+    assert_eq!(text(&received(&mut peer)), "hello");
+}
+
+#[test]
+fn receive_while_sending() {
+    let (sock, mut peer) = LoopbackSocket::pair();
+    sock.set_capacity(64);
+    let context = Context {
+        output_check_interval: Some(Duration::from_millis(3)),
+        ..Context::default()
+    };
+    let mut harness = Harness::<Echo>::new(sock, (), context).unwrap();
+    let content: String = (0..1000).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+
+    send(&mut peer, &content);
+    assert!(harness.poll());
+    assert_eq!(harness.context().received, 1);
+    // The peer doesn't read the reply, so the input is only read when the
+    // output is checked.
+    send(&mut peer, "second");
+    assert!(harness.poll());
+    assert_eq!(harness.context().received, 1);
+    assert!(harness.advance(Duration::from_millis(2)));
+    assert_eq!(harness.context().received, 1);
+    assert!(harness.advance(Duration::from_millis(1)));
+    assert_eq!(harness.context().received, 2);
+    assert_eq!(harness.context().flushed, 0);
+
+    let mut bytes = Vec::new();
+    while harness.context().flushed == 0 {
+        bytes.extend(received(&mut peer));
+        assert!(harness.poll());
+    }
+    bytes.extend(received(&mut peer));
+    let mut bytes = &bytes[..];
+    let message = serialize::read_message(&mut bytes, ReaderOptions::new()).unwrap();
+    assert_eq!(message.get_root::<text::Reader>().unwrap(), &content[..]);
+    assert_eq!(text(bytes), "second");
+}
+
+#[test]
+fn receive_while_peer_reads() {
+    let (sock, mut peer) = LoopbackSocket::pair();
+    sock.set_capacity(64);
+    let mut harness = Harness::<Echo>::new(sock, (), Context::default()).unwrap();
+    let content: String = (0..1000).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+
+    send(&mut peer, &content);
+    assert!(harness.poll());
+    assert_eq!(harness.context().received, 1);
+    // The input is read as soon as the reply makes progress, with no time
+    // passing.
+    send(&mut peer, "second");
+    let mut bytes = received(&mut peer);
+    assert!(harness.poll());
+    assert_eq!(harness.context().received, 2);
+    assert_eq!(harness.elapsed(), Duration::from_millis(0));
+
+    while harness.context().flushed == 0 {
+        let chunk = received(&mut peer);
+        assert!(!chunk.is_empty(), "stalled after {} bytes", bytes.len());
+        bytes.extend(chunk);
+        assert!(harness.poll());
+    }
+    bytes.extend(received(&mut peer));
+    assert_eq!(harness.elapsed(), Duration::from_millis(0));
+    let mut bytes = &bytes[..];
+    let message = serialize::read_message(&mut bytes, ReaderOptions::new()).unwrap();
+    assert_eq!(message.get_root::<text::Reader>().unwrap(), &content[..]);
+    assert_eq!(text(bytes), "second");
+}
+
+#[test]
+fn flushed_once_drained() {
+    let (sock, mut peer) = LoopbackSocket::pair();
+    sock.set_capacity(64);
+    let mut harness = Harness::<Echo>::new(sock, (), Context::default()).unwrap();
+    let content: String = (0..150).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+
+    send(&mut peer, &content);
+    assert!(harness.poll());
+    assert_eq!(harness.context().flushed, 0);
+    let mut bytes = received(&mut peer);
+    assert!(harness.poll());
+    assert_eq!(harness.context().flushed, 0);
+    // The rest of the reply is written once the peer reads, and reported
+    // with no time passing.
+    bytes.extend(received(&mut peer));
+    assert!(harness.poll());
+    assert_eq!(harness.context().flushed, 1);
+    assert_eq!(harness.elapsed(), Duration::from_millis(0));
+    bytes.extend(received(&mut peer));
+    assert_eq!(text(&bytes), content);
+}
+
+#[derive(Default)]
+struct Yields {
+    received: usize,
+    /// Number of messages received before each wakeup.
+    wakeups: Vec<usize>,
+}
+
+/// Receives up to 2 messages in a row, and wakes itself up on the second.
+struct Yielding;
+
+impl Endpoint for Yielding {
+    type Context = Yields;
+    type Socket = LoopbackSocket;
+    type Seed = ();
+
+    fn create(_seed: (), _sock: &mut LoopbackSocket, _scope: &mut Scope<Yields>) -> Action<Self> {
+        Action::Idle(Yielding)
+    }
+
+    fn message_received(self,
+                        _message: &MessageReader,
+                        _output: MessageWriter,
+                        scope: &mut Scope<Yields>)
+                        -> Action<Self> {
+        scope.received += 1;
+        if scope.received == 2 {
+            scope.notifier().wakeup().unwrap();
+        }
+        Action::Idle(self)
+    }
+
+    fn message_flushed(self, _output: MessageWriter, _scope: &mut Scope<Yields>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    endpoint_timeouts!(Yields);
+
+    fn recv_budget(&self, _scope: &mut Scope<Yields>) -> usize {
+        2
+    }
+
+    fn timeout(self,
+               _state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Yields>)
+               -> Action<Self> {
+        Action::Close(self)
+    }
+
+    fn wakeup(self, _output: MessageWriter, scope: &mut Scope<Yields>) -> Action<Self> {
+        let received = scope.received;
+        scope.wakeups.push(received);
+        Action::Idle(self)
+    }
+
+    fn closed(self, _reason: CloseReason, _scope: &mut Scope<Yields>) {}
+}
+
+#[test]
+fn wakeup_after_yield() {
+    let (sock, mut peer) = LoopbackSocket::pair();
+    let mut harness = Harness::<Yielding>::new(sock, (), Yields::default()).unwrap();
+    for content in &["a", "b", "c", "d", "e"] {
+        send(&mut peer, content);
+    }
+    // Receiving yields after the second message, and is resumed by the
+    // first of the notifications, while the second one reaches the endpoint.
+    assert!(harness.poll());
+    assert_eq!(harness.context().received, 5);
+    assert_eq!(harness.context().wakeups, [5]);
+    assert_eq!(harness.elapsed(), Duration::from_millis(0));
+
+    assert!(harness.wakeup());
+    assert_eq!(harness.context().wakeups, [5, 5]);
+}
+
+/// Size of the blob replied by `Blob`, spanning many output chunks.
+const BLOB_SIZE: usize = 4 << 20;
+
+#[derive(Default)]
+struct Blobs {
+    /// Whether the reply is flushed with `Action::Flush` or `Action::Send`.
+    flush: bool,
+    flushed: usize,
+}
+
+/// Replies with a blob written by `write_owned`.
+struct Blob;
+
+impl Endpoint for Blob {
+    type Context = Blobs;
+    type Socket = LoopbackSocket;
+    type Seed = ();
+
+    fn create(_seed: (), _sock: &mut LoopbackSocket, _scope: &mut Scope<Blobs>) -> Action<Self> {
+        Action::Idle(Blob)
+    }
+
+    fn message_received(self,
+                        _message: &MessageReader,
+                        mut output: MessageWriter,
+                        scope: &mut Scope<Blobs>)
+                        -> Action<Self> {
+        let content: String = (0..BLOB_SIZE).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+        output.write_owned(text_message(&content)).unwrap();
+        if scope.flush {
+            Action::Flush(self)
+        } else {
+            Action::Send(self)
+        }
+    }
+
+    fn message_flushed(self, _output: MessageWriter, scope: &mut Scope<Blobs>) -> Action<Self> {
+        scope.flushed += 1;
+        Action::Idle(self)
+    }
+
+    endpoint_timeouts!(Blobs);
+
+    fn timeout(self,
+               _state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Blobs>)
+               -> Action<Self> {
+        Action::Close(self)
+    }
+
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Blobs>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    fn closed(self, _reason: CloseReason, _scope: &mut Scope<Blobs>) {}
+}
+
+/// Request a blob, and read it from the peer as it's written.
+fn receive_blob(flush: bool) {
+    let (sock, mut peer) = LoopbackSocket::pair();
+    sock.set_capacity(16 << 10);
+    let context = Blobs { flush: flush, ..Blobs::default() };
+    let mut harness = Harness::<Blob>::new(sock, (), context).unwrap();
+
+    send(&mut peer, "blob");
+    assert!(harness.poll());
+    let mut bytes = Vec::new();
+    while harness.context().flushed == 0 {
+        let chunk = received(&mut peer);
+        assert!(!chunk.is_empty(), "stalled after {} bytes", bytes.len());
+        bytes.extend(chunk);
+        // The queue is refilled as the socket drains, with no time passing.
+        assert!(harness.poll());
+    }
+    bytes.extend(received(&mut peer));
+    assert_eq!(harness.elapsed(), Duration::from_millis(0));
+    let content = text(&bytes);
+    assert_eq!(content.len(), BLOB_SIZE);
+    assert!(content.bytes().enumerate().all(|(i, b)| b == b'a' + (i % 26) as u8));
+}
+
+#[test]
+fn owned_message_sent() {
+    receive_blob(false);
+}
+
+#[test]
+fn owned_message_flushed() {
+    receive_blob(true);
+}
+
+#[derive(Default)]
+struct Writes {
+    limits: OutputLimits,
+    /// Results of writing the replies.
+    results: Vec<Result<(), Error>>,
+    flushed: usize,
+    congested: usize,
+    drained: usize,
+}
+
+/// Replies to a number with a text of as many bytes, within the limits.
+struct Writer;
+
+impl Endpoint for Writer {
+    type Context = Writes;
+    type Socket = LoopbackSocket;
+    type Seed = ();
+
+    fn create(_seed: (), _sock: &mut LoopbackSocket, _scope: &mut Scope<Writes>) -> Action<Self> {
+        Action::Idle(Writer)
+    }
+
+    fn message_received(self,
+                        message: &MessageReader,
+                        mut output: MessageWriter,
+                        scope: &mut Scope<Writes>)
+                        -> Action<Self> {
+        let len = message.get_root::<text::Reader>().unwrap().parse().unwrap();
+        let content: String = (0..len).map(|_| 'x').collect();
+        scope.results.push(output.write(&text_message(&content)));
+        Action::Send(self)
+    }
+
+    fn message_flushed(self, _output: MessageWriter, scope: &mut Scope<Writes>) -> Action<Self> {
+        scope.flushed += 1;
+        Action::Idle(self)
+    }
+
+    fn output_congested(&mut self, scope: &mut Scope<Writes>) {
+        scope.congested += 1;
+    }
+
+    fn output_drained(&mut self, _output: MessageWriter, scope: &mut Scope<Writes>) {
+        scope.drained += 1;
+    }
+
+    fn output_limits(&self, scope: &mut Scope<Writes>) -> OutputLimits {
+        scope.limits
+    }
+
+    endpoint_timeouts!(Writes);
+
+    fn timeout(self,
+               _state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Writes>)
+               -> Action<Self> {
+        Action::Close(self)
+    }
+
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Writes>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    fn closed(self, _reason: CloseReason, _scope: &mut Scope<Writes>) {}
+}
+
+#[test]
+fn write_beyond_limits() {
+    let (sock, mut peer) = LoopbackSocket::pair();
+    sock.set_capacity(64);
+    let limits = OutputLimits {
+        max_message_size: 1024,
+        max_pending_size: 2048,
+        ..OutputLimits::default()
+    };
+    let context = Writes { limits: limits, ..Writes::default() };
+    let mut harness = Harness::<Writer>::new(sock, (), context).unwrap();
+
+    // A text of 900 bytes takes 920 in a message.
+    for len in &["2000", "900", "900", "900"] {
+        send(&mut peer, len);
+    }
+    // The peer doesn't read, so the requests are read as the output is checked.
+    assert!(harness.advance(Duration::from_millis(50)));
+    let results: Vec<_> = harness.context().results.drain(..).collect();
+    match results[0] {
+        Err(Error::OutgoingMessageTooLarge { size: 2024, limit: 1024 }) => {}
+        ref result => panic!("unexpected result: {:?}", result),
+    }
+    assert!(results[1].is_ok() && results[2].is_ok());
+    match results[3] {
+        Err(Error::OutputBufferFull { pending: 1776, limit: 2048 }) => {}
+        ref result => panic!("unexpected result: {:?}", result),
+    }
+
+    // Nothing is written when writing fails.
+    let mut bytes = Vec::new();
+    while harness.context().flushed == 0 {
+        bytes.extend(received(&mut peer));
+        assert!(harness.poll());
+    }
+    bytes.extend(received(&mut peer));
+    let content: String = (0..900).map(|_| 'x').collect();
+    assert_eq!(texts(&bytes), [content.clone(), content]);
+}
+
+#[test]
+fn watermarks_crossed_once() {
+    let (sock, mut peer) = LoopbackSocket::pair();
+    sock.set_capacity(64);
+    let limits = OutputLimits {
+        high_watermark: 2048,
+        low_watermark: 512,
+        ..OutputLimits::default()
+    };
+    let context = Writes { limits: limits, ..Writes::default() };
+    let mut harness = Harness::<Writer>::new(sock, (), context).unwrap();
+
+    for round in 1..3 {
+        // The third reply crosses the high watermark, the fourth one stays
+        // above it.
+        for _ in 0..4 {
+            send(&mut peer, "900");
+        }
+        assert!(harness.advance(Duration::from_millis(50)));
+        assert_eq!(harness.context().results.len(), round * 4);
+        assert_eq!((harness.context().congested, harness.context().drained),
+                   (round, round - 1));
+
+        let mut bytes = Vec::new();
+        while harness.context().flushed < round {
+            bytes.extend(received(&mut peer));
+            assert!(harness.poll());
+            assert_eq!(harness.context().congested, round);
+        }
+        bytes.extend(received(&mut peer));
+        assert_eq!(harness.context().drained, round);
+        assert_eq!(texts(&bytes).len(), 4);
+    }
+    assert!(harness.context().results.iter().all(Result::is_ok));
+}
+
+struct Kept {
+    pool: SegmentPool,
+    messages: Vec<OwnedMessage>,
+}
+
+/// Keeps the packed messages received, whose segments are from the pool.
+struct Keeper;
+
+impl Endpoint for Keeper {
+    type Context = Kept;
+    type Socket = LoopbackSocket;
+    type Seed = ();
+
+    fn create(_seed: (), _sock: &mut LoopbackSocket, _scope: &mut Scope<Kept>) -> Action<Self> {
+        Action::Idle(Keeper)
+    }
+
+    fn message_received(self,
+                        _message: &MessageReader,
+                        _output: MessageWriter,
+                        _scope: &mut Scope<Kept>)
+                        -> Action<Self> {
+        unreachable!()
+    }
+
+    fn take_message(self,
+                    message: MessageReader,
+                    _output: MessageWriter,
+                    scope: &mut Scope<Kept>)
+                    -> Action<Self> {
+        scope.messages.push(into_owned(message, ReaderOptions::new()));
+        Action::Idle(self)
+    }
+
+    fn message_flushed(self, _output: MessageWriter, _scope: &mut Scope<Kept>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    fn segment_pool(&self, scope: &mut Scope<Kept>) -> Option<SegmentPool> {
+        Some(scope.pool.clone())
+    }
+
+    fn wire_format(&self, _scope: &mut Scope<Kept>) -> WireFormat {
+        WireFormat::Packed
+    }
+
+    endpoint_timeouts!(Kept);
+
+    fn timeout(self,
+               _state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Kept>)
+               -> Action<Self> {
+        Action::Close(self)
+    }
+
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Kept>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    fn closed(self, _reason: CloseReason, _scope: &mut Scope<Kept>) {}
+}
+
+#[test]
+fn owned_message_returned_to_pool() {
+    let (sock, mut peer) = LoopbackSocket::pair();
+    let pool = SegmentPool::new(1 << 20);
+    let context = Kept {
+        pool: pool.clone(),
+        messages: Vec::new(),
+    };
+    let mut harness = Harness::<Keeper>::new(sock, (), context).unwrap();
+
+    serialize_packed::write_message(&mut peer, &text_message("kept")).unwrap();
+    serialize_packed::write_message(&mut peer, &text_message("next")).unwrap();
+    assert!(harness.poll());
+    assert_eq!(harness.context().messages.len(), 2);
+    // The messages outlive the callbacks and the input they were read from.
+    let message = harness.context().messages.remove(0);
+    assert_eq!(message.get_root::<text::Reader>().unwrap(), "kept");
+    assert_eq!(harness.context().messages[0].get_root::<text::Reader>().unwrap(),
+               "next");
+
+    // Dropping a message returns its buffer to the pool, where it's taken
+    // again for the size class with the message still in it.
+    let segments = message.into_segments();
+    let ptr = segments.get_segment(0).unwrap().as_ptr();
+    drop(segments);
+    let buf = pool.take(1);
+    assert_eq!(buf.as_ptr(), ptr);
+    assert!(Word::words_to_bytes(&buf).windows(4).any(|bytes| bytes == b"kept"));
+}
+
+#[derive(Default)]
+struct Closes {
+    /// Linger timeout of closing once the peer has closed its side.
+    linger: Option<Duration>,
+    received: usize,
+    write_error: Option<Error>,
+    closed: Option<CloseReason>,
+}
+
+/// Shuts down its write side on "close output", closes with "bye" on
+/// "bye" or once the peer has closed its side, and acknowledges anything
+/// else with "ok".
+struct Closer;
+
+impl Endpoint for Closer {
+    type Context = Closes;
+    type Socket = LoopbackSocket;
+    type Seed = ();
+
+    fn create(_seed: (), _sock: &mut LoopbackSocket, _scope: &mut Scope<Closes>) -> Action<Self> {
+        Action::Idle(Closer)
+    }
+
+    fn message_received(self,
+                        message: &MessageReader,
+                        mut output: MessageWriter,
+                        scope: &mut Scope<Closes>)
+                        -> Action<Self> {
+        scope.received += 1;
+        match message.get_root::<text::Reader>().unwrap() {
+            "close output" => {
+                output.write(&text_message("done")).unwrap();
+                Action::CloseOutput(self)
+            }
+            "bye" => {
+                output.write(&text_message("bye")).unwrap();
+                Action::FlushAndClose(self, Some(Duration::from_millis(100)))
+            }
+            _ => {
+                if let Err(err) = output.write(&text_message("ok")) {
+                    scope.write_error = Some(err);
+                }
+                Action::Idle(self)
+            }
+        }
+    }
+
+    fn input_closed(self, mut output: MessageWriter, scope: &mut Scope<Closes>) -> Action<Self> {
+        output.write(&text_message("bye")).unwrap();
+        let linger = scope.linger;
+        Action::FlushAndClose(self, linger)
+    }
+
+    fn message_flushed(self, _output: MessageWriter, _scope: &mut Scope<Closes>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    endpoint_timeouts!(Closes);
+
+    fn timeout(self,
+               _state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Closes>)
+               -> Action<Self> {
+        Action::Close(self)
+    }
+
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Closes>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    fn closed(self, reason: CloseReason, scope: &mut Scope<Closes>) {
+        scope.closed = Some(reason);
+    }
+}
+
+fn closer(linger: Option<Duration>) -> (Harness<Closer>, LoopbackSocket) {
+    let (sock, peer) = LoopbackSocket::pair();
+    let context = Closes { linger: linger, ..Closes::default() };
+    let harness = Harness::new(sock, (), context).unwrap();
+    (harness, peer)
+}
+
+fn is_eof(peer: &mut LoopbackSocket) -> bool {
+    match peer.read(&mut [0; 1]) {
+        Ok(0) => true,
+        Ok(_) => panic!("unexpected data"),
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => false,
+        Err(err) => panic!("reading failed: {}", err),
+    }
+}
+
+#[test]
+fn half_close() {
+    let (mut harness, mut peer) = closer(None);
+    send(&mut peer, "close output");
+    assert!(harness.poll());
+    assert_eq!(text(&received(&mut peer)), "done");
+    assert!(is_eof(&mut peer));
+
+    // Messages are still received, but can't be replied to.
+    send(&mut peer, "more");
+    assert!(harness.poll());
+    assert_eq!(harness.context().received, 2);
+    match harness.context().write_error.take() {
+        Some(Error::OutputClosed) => {}
+        err => panic!("unexpected write error: {:?}", err),
+    }
+
+    peer.close_write().unwrap();
+    assert!(!harness.poll());
+    match harness.context().closed.take() {
+        Some(CloseReason::PeerClosed) => {}
+        reason => panic!("unexpected close: {:?}", reason),
+    }
+}
+
+#[test]
+fn linger_until_peer_closed() {
+    let (mut harness, mut peer) = closer(None);
+    send(&mut peer, "bye");
+    assert!(harness.poll());
+    assert_eq!(text(&received(&mut peer)), "bye");
+    assert!(is_eof(&mut peer));
+
+    // The input is discarded meanwhile.
+    send(&mut peer, "ignored");
+    assert!(harness.advance(Duration::from_millis(50)));
+    assert_eq!(harness.context().received, 1);
+    peer.close_write().unwrap();
+    assert!(!harness.poll());
+    assert_eq!(harness.elapsed(), Duration::from_millis(50));
+    match harness.context().closed.take() {
+        Some(CloseReason::Shutdown { flushed: true }) => {}
+        reason => panic!("unexpected close: {:?}", reason),
+    }
+}
+
+#[test]
+fn linger_timeout() {
+    let (mut harness, mut peer) = closer(None);
+    send(&mut peer, "bye");
+    assert!(harness.advance(Duration::from_millis(99)));
+    assert!(harness.run(Duration::from_millis(1)));
+    match harness.context().closed.take() {
+        Some(CloseReason::Shutdown { flushed: true }) => {}
+        reason => panic!("unexpected close: {:?}", reason),
+    }
+    assert_eq!(text(&received(&mut peer)), "bye");
+}
+
+#[test]
+fn no_linger_after_peer_closed() {
+    let (mut harness, mut peer) = closer(Some(Duration::from_secs(1)));
+    peer.close_write().unwrap();
+    assert!(!harness.poll());
+    assert_eq!(harness.elapsed(), Duration::from_millis(0));
+    match harness.context().closed.take() {
+        Some(CloseReason::Shutdown { flushed: true }) => {}
+        reason => panic!("unexpected close: {:?}", reason),
+    }
+    assert_eq!(text(&received(&mut peer)), "bye");
+    assert!(is_eof(&mut peer));
+}
@@ -0,0 +1,331 @@
+extern crate capnp;
+extern crate rcgen;
+extern crate rotor;
+extern crate rotor_capnp;
+extern crate rustls;
+
+#[macro_use]
+mod common;
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use capnp::text;
+use rcgen::{BasicConstraints, CertificateParams, IsCa};
+use rotor::{Compose2, Config as LoopConfig, Loop, Scope};
+use rotor::mio::tcp::{TcpListener, TcpStream};
+use rotor_capnp::{Acceptor, Action, Admission, CloseReason, ConnectionState, Connector, Endpoint,
+                  Error, Handshake, LoopbackSocket, MessageReader, MessageWriter, Reconnect,
+                  StartSession, TlsListener, TlsSession, TlsStream, TlsTarget};
+use rustls::{Certificate, ClientConfig, ClientConnection, Connection, PrivateKey, RootCertStore,
+             ServerConfig, ServerConnection, ServerName};
+use rustls::server::AllowAnyAuthenticatedClient;
+
+use common::{large_content, text_message};
+
+/// Configuration of the rustls sessions on either side.
+#[derive(Clone)]
+enum Config {
+    Server(Arc<ServerConfig>),
+    Client(Arc<ClientConfig>, ServerName),
+}
+
+/// A rustls session.
+struct Rustls(Connection);
+
+impl StartSession for Rustls {
+    type Config = Config;
+
+    fn start(config: &Config) -> io::Result<Rustls> {
+        let connection = match *config {
+            Config::Server(ref config) => {
+                ServerConnection::new(config.clone()).map(Connection::from)
+            }
+            Config::Client(ref config, ref name) => {
+                ClientConnection::new(config.clone(), name.clone()).map(Connection::from)
+            }
+        };
+        connection.map(Rustls).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+    }
+}
+
+impl TlsSession for Rustls {
+    fn read_tls(&mut self, rd: &mut Read) -> io::Result<usize> {
+        self.0.read_tls(rd)
+    }
+
+    fn write_tls(&mut self, wr: &mut Write) -> io::Result<usize> {
+        self.0.write_tls(wr)
+    }
+
+    fn process_new_packets(&mut self) -> io::Result<()> {
+        self.0
+            .process_new_packets()
+            .map(|_| ())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.0.is_handshaking()
+    }
+
+    fn wants_write(&self) -> bool {
+        self.0.wants_write()
+    }
+
+    fn read_plaintext(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.reader().read(buf)
+    }
+
+    fn write_plaintext(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.writer().write(buf)
+    }
+
+    fn send_close_notify(&mut self) {
+        self.0.send_close_notify()
+    }
+
+    fn peer_certificate(&self) -> Option<&[u8]> {
+        self.0
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+            .map(|certificate| &certificate.0[..])
+    }
+}
+
+/// Configurations of the server and the client, authenticating each other
+/// with certificates issued by a CA generated on the fly, and the
+/// certificates of the server and the client.
+fn configs() -> (Config, Config, Vec<u8>, Vec<u8>) {
+    let mut ca = CertificateParams::new(Vec::new());
+    ca.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = rcgen::Certificate::from_params(ca).unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(&Certificate(ca.serialize_der().unwrap())).unwrap();
+
+    let issue = |name: &str| {
+        let leaf = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        (leaf.serialize_der_with_signer(&ca).unwrap(), PrivateKey(leaf.serialize_private_key_der()))
+    };
+    let (server_certificate, server_key) = issue("localhost");
+    let (client_certificate, client_key) = issue("client");
+
+    let server = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots.clone()).boxed())
+        .with_single_cert(vec![Certificate(server_certificate.clone())], server_key)
+        .unwrap();
+    let client = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_client_auth_cert(vec![Certificate(client_certificate.clone())], client_key)
+        .unwrap();
+    let name = ServerName::try_from("localhost").unwrap();
+    (Config::Server(Arc::new(server)),
+     Config::Client(Arc::new(client), name),
+     server_certificate,
+     client_certificate)
+}
+
+type Socket = TlsStream<TcpStream, Rustls>;
+
+const LARGE_CONTENT_LEN: usize = 1 << 20;
+
+struct Context {
+    server_certificate: Vec<u8>,
+    client_certificate: Vec<u8>,
+}
+
+/// Echoes text messages back.
+struct Echo;
+
+impl Endpoint for Echo {
+    type Context = Context;
+    type Socket = Socket;
+    type Seed = ();
+
+    fn create(_seed: (), sock: &mut Socket, scope: &mut Scope<Context>) -> Action<Self> {
+        assert_eq!(sock.peer_certificate(), Some(&scope.client_certificate[..]));
+        Action::Idle(Echo)
+    }
+
+    fn message_received(self,
+                        message: &MessageReader,
+                        mut output: MessageWriter,
+                        _scope: &mut Scope<Context>)
+                        -> Action<Self> {
+        let content = message.get_root::<text::Reader>().unwrap();
+        output.write(&text_message(content)).unwrap();
+        Action::Send(self)
+    }
+
+    fn message_flushed(self, _output: MessageWriter, _scope: &mut Scope<Context>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    endpoint_timeouts!(Context);
+
+    fn timeout(self,
+               _state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Context>)
+               -> Action<Self> {
+        Action::Close(self)
+    }
+
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Context>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    fn closed(self, _reason: CloseReason, _scope: &mut Scope<Context>) {}
+
+    fn handshake_failed(_seed: &(), err: Error, _scope: &mut Scope<Context>) {
+        panic!("handshake failed: {}", err)
+    }
+}
+
+/// Sends a small and a large message and closes the loop after the replies.
+struct Hello(usize);
+
+impl Endpoint for Hello {
+    type Context = Context;
+    type Socket = Socket;
+    type Seed = ();
+
+    fn create(_seed: (), sock: &mut Socket, scope: &mut Scope<Context>) -> Action<Self> {
+        assert_eq!(sock.peer_certificate(), Some(&scope.server_certificate[..]));
+        Action::Flush(Hello(0))
+    }
+
+    fn message_received(self,
+                        message: &MessageReader,
+                        _output: MessageWriter,
+                        _scope: &mut Scope<Context>)
+                        -> Action<Self> {
+        let content = message.get_root::<text::Reader>().unwrap();
+        match self.0 {
+            0 => {
+                assert_eq!(content, "hello");
+                Action::Recv(Hello(1))
+            }
+            _ => {
+                assert!(content == large_content(LARGE_CONTENT_LEN));
+                Action::Close(self)
+            }
+        }
+    }
+
+    fn message_flushed(self,
+                       mut output: MessageWriter,
+                       _scope: &mut Scope<Context>)
+                       -> Action<Self> {
+        output.write(&text_message("hello")).unwrap();
+        output.write(&text_message(&large_content(LARGE_CONTENT_LEN))).unwrap();
+        Action::Recv(self)
+    }
+
+    endpoint_timeouts!(Context);
+
+    fn timeout(self,
+               state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Context>)
+               -> Action<Self> {
+        panic!("timed out in {:?}", state)
+    }
+
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Context>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    fn closed(self, reason: CloseReason, scope: &mut Scope<Context>) {
+        match reason {
+            CloseReason::Local => {}
+            reason => panic!("unexpected close: {:?}", reason),
+        }
+        scope.shutdown_loop();
+    }
+
+    fn connect_failed(_seed: &(), err: Error, _delay: Duration, _scope: &mut Scope<Context>) {
+        panic!("connecting failed: {}", err)
+    }
+}
+
+#[test]
+fn echo_after_handshake() {
+    let (server, client, server_certificate, client_certificate) = configs();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let target = TlsTarget {
+        target: listener.local_addr().unwrap(),
+        config: client,
+    };
+    let listener = TlsListener::new(listener, server);
+    let context = Context {
+        server_certificate: server_certificate,
+        client_certificate: client_certificate,
+    };
+
+    let mut event_loop = Loop::new(&LoopConfig::new()).unwrap().instantiate(context);
+    event_loop.add_machine_with(|scope| {
+                  Acceptor::<Echo, _>::new(listener, (), Admission::default(), scope)
+                      .wrap(Compose2::A)
+              })
+              .unwrap();
+    event_loop.add_machine_with(|scope| {
+                  Connector::<Hello, _>::new(scope, target, (), Reconnect::default())
+                      .wrap(Compose2::B)
+              })
+              .unwrap();
+    event_loop.run().unwrap();
+}
+
+/// Read from the `stream` until it would block.
+fn read_available<S: Read + Write>(stream: &mut TlsStream<S, Rustls>, data: &mut Vec<u8>) {
+    let mut buf = [0; 4096];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => panic!("unexpected end of stream"),
+            Ok(len) => data.extend(&buf[..len]),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return,
+            Err(err) => panic!("reading failed: {}", err),
+        }
+    }
+}
+
+#[test]
+fn partial_write_splits_record() {
+    let (server, client, _, _) = configs();
+    let (client_sock, server_sock) = LoopbackSocket::pair();
+    let mut client = TlsStream::new(client_sock, Rustls::start(&client).unwrap());
+    let mut server = TlsStream::new(server_sock, Rustls::start(&server).unwrap());
+    let mut handshaken = (false, false);
+    while handshaken != (true, true) {
+        handshaken = (client.handshake().unwrap(), server.handshake().unwrap());
+    }
+
+    // Only a part of the first record fits, the last byte of the data is held
+    // until the rest of it is written.
+    client.get_ref().set_capacity(1000);
+    let data: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+    let mut written = client.write(&data).unwrap();
+    assert_eq!(written, data.len() - 1);
+    match client.write(&data[written..]) {
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    let mut received = Vec::new();
+    while written < data.len() {
+        read_available(&mut server, &mut received);
+        match client.write(&data[written..]) {
+            Ok(len) => written += len,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => panic!("writing failed: {}", err),
+        }
+    }
+    assert_eq!(written, data.len());
+    read_available(&mut server, &mut received);
+    assert!(received == data);
+}
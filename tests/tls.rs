@@ -0,0 +1,341 @@
+extern crate capnp;
+extern crate rotor;
+extern crate rotor_capnp;
+
+#[macro_use]
+mod common;
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream as StdTcpStream};
+use std::thread;
+use std::time::Duration;
+
+use capnp::text;
+use rotor::{Compose2, Config as LoopConfig, Loop, Scope};
+use rotor::mio::tcp::{TcpListener, TcpStream};
+use rotor_capnp::{Acceptor, Action, Admission, CloseReason, ConnectionState, Connector, Endpoint,
+                  Error, MessageReader, MessageWriter, Reconnect, StartSession, TlsListener,
+                  TlsSession, TlsStream, TlsTarget};
+
+use common::{large_content, text_message};
+
+const HELLO: u8 = 1;
+const DATA: u8 = 2;
+const CLOSE_NOTIFY: u8 = 3;
+const KEY: u8 = 0x5a;
+
+const SERVER_CERTIFICATE: &[u8] = b"server certificate";
+const CLIENT_CERTIFICATE: &[u8] = b"client certificate";
+
+#[derive(Clone)]
+struct Config {
+    server: bool,
+    certificate: Vec<u8>,
+}
+
+/// Stand-in for a TLS library: the peers exchange their certificates in
+/// the handshake, then the data is sent in records scrambled with a fixed key.
+struct Session {
+    certificate: Vec<u8>,
+    peer_certificate: Option<Vec<u8>>,
+    hello_sent: bool,
+    records: Vec<u8>,
+    output: Vec<u8>,
+    plaintext: Vec<u8>,
+    end_of_stream: bool,
+    close_notify: bool,
+}
+
+impl Session {
+    fn record(&mut self, kind: u8, payload: &[u8]) {
+        self.output.push(kind);
+        self.output.push((payload.len() >> 8) as u8);
+        self.output.push(payload.len() as u8);
+        self.output.extend(payload);
+    }
+
+    fn send_hello(&mut self) {
+        let certificate = self.certificate.clone();
+        self.record(HELLO, &certificate);
+        self.hello_sent = true;
+    }
+}
+
+impl StartSession for Session {
+    type Config = Config;
+
+    fn start(config: &Config) -> io::Result<Session> {
+        let mut session = Session {
+            certificate: config.certificate.clone(),
+            peer_certificate: None,
+            hello_sent: false,
+            records: Vec::new(),
+            output: Vec::new(),
+            plaintext: Vec::new(),
+            end_of_stream: false,
+            close_notify: false,
+        };
+        if !config.server {
+            session.send_hello();
+        }
+        Ok(session)
+    }
+}
+
+impl TlsSession for Session {
+    fn read_tls(&mut self, rd: &mut Read) -> io::Result<usize> {
+        let mut buf = [0; 4096];
+        let len = try!(rd.read(&mut buf));
+        self.end_of_stream = len == 0;
+        self.records.extend(&buf[..len]);
+        Ok(len)
+    }
+
+    fn write_tls(&mut self, wr: &mut Write) -> io::Result<usize> {
+        let len = try!(wr.write(&self.output));
+        self.output.drain(..len);
+        Ok(len)
+    }
+
+    fn process_new_packets(&mut self) -> io::Result<()> {
+        while self.records.len() >= 3 {
+            let len = (self.records[1] as usize) << 8 | self.records[2] as usize;
+            if self.records.len() < 3 + len {
+                break;
+            }
+            let record: Vec<u8> = self.records.drain(..3 + len).collect();
+            match record[0] {
+                HELLO if self.peer_certificate.is_none() => {
+                    self.peer_certificate = Some(record[3..].to_vec());
+                    if !self.hello_sent {
+                        self.send_hello();
+                    }
+                }
+                DATA if !self.is_handshaking() => {
+                    self.plaintext.extend(record[3..].iter().map(|byte| byte ^ KEY));
+                }
+                CLOSE_NOTIFY => self.close_notify = true,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected record")),
+            }
+        }
+        Ok(())
+    }
+
+    fn is_handshaking(&self) -> bool {
+        !self.hello_sent || self.peer_certificate.is_none()
+    }
+
+    fn wants_write(&self) -> bool {
+        !self.output.is_empty()
+    }
+
+    fn read_plaintext(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.plaintext.is_empty() {
+            let len = buf.len().min(self.plaintext.len());
+            buf[..len].copy_from_slice(&self.plaintext[..len]);
+            self.plaintext.drain(..len);
+            Ok(len)
+        } else if self.close_notify {
+            Ok(0)
+        } else if self.end_of_stream {
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no close_notify"))
+        } else {
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "no data"))
+        }
+    }
+
+    fn write_plaintext(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(16384);
+        let record: Vec<u8> = buf[..len].iter().map(|byte| byte ^ KEY).collect();
+        self.record(DATA, &record);
+        Ok(len)
+    }
+
+    fn send_close_notify(&mut self) {
+        self.record(CLOSE_NOTIFY, &[]);
+    }
+
+    fn peer_certificate(&self) -> Option<&[u8]> {
+        self.peer_certificate.as_ref().map(|certificate| &certificate[..])
+    }
+}
+
+type Socket = TlsStream<TcpStream, Session>;
+
+const LARGE_CONTENT_LEN: usize = 4 << 20;
+
+struct Context;
+
+/// Echoes text messages back.
+struct Echo;
+
+impl Endpoint for Echo {
+    type Context = Context;
+    type Socket = Socket;
+    type Seed = ();
+
+    fn create(_seed: (), sock: &mut Socket, _scope: &mut Scope<Context>) -> Action<Self> {
+        assert_eq!(sock.peer_certificate(), Some(CLIENT_CERTIFICATE));
+        Action::Idle(Echo)
+    }
+
+    fn message_received(self,
+                        message: &MessageReader,
+                        mut output: MessageWriter,
+                        _scope: &mut Scope<Context>)
+                        -> Action<Self> {
+        let content = message.get_root::<text::Reader>().unwrap();
+        output.write(&text_message(content)).unwrap();
+        Action::Send(self)
+    }
+
+    fn message_flushed(self, _output: MessageWriter, _scope: &mut Scope<Context>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    endpoint_timeouts!(Context);
+
+    fn timeout(self,
+               _state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Context>)
+               -> Action<Self> {
+        Action::Close(self)
+    }
+
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Context>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    fn closed(self, _reason: CloseReason, _scope: &mut Scope<Context>) {}
+
+    fn handshake_failed(_seed: &(), err: Error, scope: &mut Scope<Context>) {
+        match err {
+            Error::Handshake(ref err) if err.kind() == io::ErrorKind::InvalidData => {}
+            err => panic!("unexpected error: {:?}", err),
+        }
+        scope.shutdown_loop();
+    }
+}
+
+/// Sends a small and a large message and closes the loop after the replies.
+struct Hello(usize);
+
+impl Endpoint for Hello {
+    type Context = Context;
+    type Socket = Socket;
+    type Seed = ();
+
+    fn create(_seed: (), sock: &mut Socket, _scope: &mut Scope<Context>) -> Action<Self> {
+        assert_eq!(sock.peer_certificate(), Some(SERVER_CERTIFICATE));
+        Action::Flush(Hello(0))
+    }
+
+    fn message_received(self,
+                        message: &MessageReader,
+                        _output: MessageWriter,
+                        _scope: &mut Scope<Context>)
+                        -> Action<Self> {
+        let content = message.get_root::<text::Reader>().unwrap();
+        match self.0 {
+            0 => {
+                assert_eq!(content, "hello");
+                Action::Recv(Hello(1))
+            }
+            _ => {
+                assert!(content == large_content(LARGE_CONTENT_LEN));
+                Action::Close(self)
+            }
+        }
+    }
+
+    fn message_flushed(self,
+                       mut output: MessageWriter,
+                       _scope: &mut Scope<Context>)
+                       -> Action<Self> {
+        output.write(&text_message("hello")).unwrap();
+        output.write(&text_message(&large_content(LARGE_CONTENT_LEN))).unwrap();
+        Action::Recv(self)
+    }
+
+    endpoint_timeouts!(Context);
+
+    fn timeout(self,
+               state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Context>)
+               -> Action<Self> {
+        panic!("timed out in {:?}", state)
+    }
+
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Context>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    fn closed(self, reason: CloseReason, scope: &mut Scope<Context>) {
+        match reason {
+            CloseReason::Local => {}
+            reason => panic!("unexpected close: {:?}", reason),
+        }
+        scope.shutdown_loop();
+    }
+
+    fn connect_failed(_seed: &(), err: Error, _delay: Duration, _scope: &mut Scope<Context>) {
+        panic!("connecting failed: {}", err)
+    }
+}
+
+fn listen() -> (TlsListener<TcpListener, Session>, SocketAddr) {
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let address = listener.local_addr().unwrap();
+    let config = Config {
+        server: true,
+        certificate: SERVER_CERTIFICATE.to_vec(),
+    };
+    (TlsListener::new(listener, config), address)
+}
+
+#[test]
+fn echo_after_handshake() {
+    let (listener, address) = listen();
+    let target = TlsTarget {
+        target: address,
+        config: Config {
+            server: false,
+            certificate: CLIENT_CERTIFICATE.to_vec(),
+        },
+    };
+
+    let mut event_loop = Loop::new(&LoopConfig::new()).unwrap().instantiate(Context);
+    event_loop.add_machine_with(|scope| {
+                  Acceptor::<Echo, _>::new(listener, (), Admission::default(), scope)
+                      .wrap(Compose2::A)
+              })
+              .unwrap();
+    event_loop.add_machine_with(|scope| {
+                  Connector::<Hello, _>::new(scope, target, (), Reconnect::default())
+                      .wrap(Compose2::B)
+              })
+              .unwrap();
+    event_loop.run().unwrap();
+}
+
+#[test]
+fn failed_handshake() {
+    let (listener, address) = listen();
+    let client = thread::spawn(move || {
+        let mut sock = StdTcpStream::connect(address).unwrap();
+        sock.write_all(&[DATA, 0, 1, 0]).unwrap();
+        let mut rest = Vec::new();
+        sock.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, [CLOSE_NOTIFY, 0, 0]);
+    });
+
+    let mut event_loop = Loop::new(&LoopConfig::new()).unwrap().instantiate(Context);
+    event_loop.add_machine_with(|scope| {
+                  Acceptor::<Echo, _>::new(listener, (), Admission::default(), scope)
+              })
+              .unwrap();
+    event_loop.run().unwrap();
+    client.join().unwrap();
+}
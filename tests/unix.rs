@@ -0,0 +1,193 @@
+#![cfg(unix)]
+
+extern crate capnp;
+extern crate libc;
+extern crate rotor;
+extern crate rotor_capnp;
+
+#[macro_use]
+mod common;
+
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::os::unix::net;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+
+use capnp::message::ReaderOptions;
+use capnp::{serialize, text};
+use rotor::{Config as LoopConfig, Loop, Scope};
+use rotor::mio::unix::{UnixListener, UnixStream};
+use rotor_capnp::{Accept, Action, CapnpStream, CloseReason, ConnectionState, Connector,
+                  Endpoint, MessageReader, MessageWriter, PeerCred, PeerCredentials, Reconnect};
+
+use common::text_message;
+
+fn socket_path(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("rotor-capnp-{}-{}.sock", process::id(), name));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+fn own_credentials() -> PeerCredentials {
+    PeerCredentials {
+        pid: Some(process::id()),
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+    }
+}
+
+struct Context;
+
+/// Echoes text messages back and closes the loop with the connection.
+struct Echo;
+
+impl Endpoint for Echo {
+    type Context = Context;
+    type Socket = UnixStream;
+    type Seed = ();
+
+    fn create(_seed: (), sock: &mut UnixStream, _scope: &mut Scope<Context>) -> Action<Self> {
+        // The client is a thread of this process.
+        assert_eq!(sock.peer_cred().unwrap(), own_credentials());
+        Action::Idle(Echo)
+    }
+
+    fn message_received(self,
+                        message: &MessageReader,
+                        mut output: MessageWriter,
+                        _scope: &mut Scope<Context>)
+                        -> Action<Self> {
+        let content = message.get_root::<text::Reader>().unwrap();
+        output.write(&text_message(content)).unwrap();
+        Action::Send(self)
+    }
+
+    fn message_flushed(self, _output: MessageWriter, _scope: &mut Scope<Context>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    endpoint_timeouts!(Context);
+
+    fn timeout(self,
+               _state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Context>)
+               -> Action<Self> {
+        Action::Close(self)
+    }
+
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Context>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    fn closed(self, reason: CloseReason, scope: &mut Scope<Context>) {
+        match reason {
+            CloseReason::PeerClosed => {}
+            reason => panic!("unexpected close: {:?}", reason),
+        }
+        scope.shutdown_loop();
+    }
+}
+
+#[test]
+fn echo_with_peer_credentials() {
+    let path = socket_path("echo");
+    let listener = UnixListener::bind(&path).unwrap();
+    let client_path = path.clone();
+    let client = thread::spawn(move || {
+        let mut sock = net::UnixStream::connect(&client_path).unwrap();
+        serialize::write_message(&mut sock, &text_message("hello")).unwrap();
+        let reply = serialize::read_message(&mut sock, ReaderOptions::new()).unwrap();
+        assert_eq!(reply.get_root::<text::Reader>().unwrap(), "hello");
+    });
+
+    let mut event_loop = Loop::new(&LoopConfig::new()).unwrap().instantiate(Context);
+    event_loop.add_machine_with(|scope| {
+                  Accept::<CapnpStream<Echo>, UnixListener>::new(listener, (), scope)
+              })
+              .unwrap();
+    event_loop.run().unwrap();
+    client.join().unwrap();
+    let _ = fs::remove_file(&path);
+}
+
+/// Sends a text message and closes the loop after the reply.
+struct Hello;
+
+impl Endpoint for Hello {
+    type Context = Context;
+    type Socket = UnixStream;
+    type Seed = ();
+
+    fn create(_seed: (), _sock: &mut UnixStream, _scope: &mut Scope<Context>) -> Action<Self> {
+        // `message_flushed` is called right away on the empty buffer.
+        Action::Flush(Hello)
+    }
+
+    fn message_received(self,
+                        message: &MessageReader,
+                        _output: MessageWriter,
+                        _scope: &mut Scope<Context>)
+                        -> Action<Self> {
+        assert_eq!(message.get_root::<text::Reader>().unwrap(), "hello");
+        Action::Close(self)
+    }
+
+    fn message_flushed(self,
+                       mut output: MessageWriter,
+                       _scope: &mut Scope<Context>)
+                       -> Action<Self> {
+        output.write(&text_message("hello")).unwrap();
+        Action::Recv(self)
+    }
+
+    endpoint_timeouts!(Context);
+
+    fn timeout(self,
+               _state: ConnectionState,
+               _output: MessageWriter,
+               _scope: &mut Scope<Context>)
+               -> Action<Self> {
+        Action::Close(self)
+    }
+
+    fn wakeup(self, _output: MessageWriter, _scope: &mut Scope<Context>) -> Action<Self> {
+        Action::Idle(self)
+    }
+
+    fn closed(self, reason: CloseReason, scope: &mut Scope<Context>) {
+        match reason {
+            CloseReason::Local => {}
+            reason => panic!("unexpected close: {:?}", reason),
+        }
+        scope.shutdown_loop();
+    }
+}
+
+#[test]
+fn connector_over_unix_socket() {
+    let path = socket_path("connector");
+    let listener = net::UnixListener::bind(&path).unwrap();
+    let server = thread::spawn(move || {
+        let (mut sock, _) = listener.accept().unwrap();
+        let message = serialize::read_message(&mut sock, ReaderOptions::new()).unwrap();
+        let content = message.get_root::<text::Reader>().unwrap().to_string();
+        serialize::write_message(&mut sock, &text_message(&content)).unwrap();
+        let mut rest = Vec::new();
+        sock.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    });
+
+    let mut event_loop = Loop::new(&LoopConfig::new()).unwrap().instantiate(Context);
+    let target = path.clone();
+    event_loop.add_machine_with(|scope| {
+                  Connector::<Hello, PathBuf>::new(scope, target, (), Reconnect::default())
+              })
+              .unwrap();
+    event_loop.run().unwrap();
+    server.join().unwrap();
+    let _ = fs::remove_file(&path);
+}